@@ -14,10 +14,118 @@ impl Guest for Component {
     fn eprint(s: String) {
         eprintln!("{}", s);
     }
-    fn get_random() -> Vec<u8> {
-        let mut data = vec![0u8; 1024];
-        getrandom::fill(&mut data).unwrap();
+    fn get_random(seed: Option<u64>, len: u32) -> Vec<u8> {
+        // The coverage-guided driver in `proxy-component run` provides the input as hex.
+        if let Ok(input) = std::env::var("PROXY_FUZZ_INPUT") {
+            let mut data: Vec<u8> = (0..input.len() / 2)
+                .map(|i| u8::from_str_radix(&input[2 * i..2 * i + 2], 16).unwrap())
+                .collect();
+            data.truncate(len as usize);
+            return data;
+        }
+        // Without an explicit seed, take it from the environment, so that a failing run can be reproduced.
+        let seed = env_seed(seed, "PROXY_FUZZ_SEED");
+        eprintln!("fuzz seed: {seed}");
+        let mut state = seed;
+        let mut data = Vec::with_capacity(len as usize + 8);
+        while data.len() < len as usize {
+            data.extend_from_slice(&splitmix64(&mut state).to_le_bytes());
+        }
+        data.truncate(len as usize);
         data
     }
+    fn get_fault_seed(seed: Option<u64>) -> u64 {
+        let seed = env_seed(seed, "PROXY_FAULT_SEED");
+        eprintln!("fault seed: {seed}");
+        seed
+    }
+    fn now() -> u64 {
+        START.get_or_init(Instant::now).elapsed().as_nanos() as u64
+    }
+    fn add_profile(method: String, is_export: bool, arg_size: u64, ret_size: u64, nanos: u64) {
+        PROFILE.with_borrow_mut(|profile| {
+            let stat = profile.entry((is_export, method)).or_default();
+            stat.calls += 1;
+            stat.arg_size += arg_size;
+            stat.ret_size += ret_size;
+            stat.nanos += nanos;
+            stat.max_nanos = stat.max_nanos.max(nanos);
+        });
+    }
+    fn print_profile(json: bool) {
+        PROFILE.with_borrow(|profile| {
+            // the slowest functions first
+            let mut stats: Vec<_> = profile.iter().collect();
+            stats.sort_by_key(|(_, stat)| std::cmp::Reverse(stat.nanos));
+            if json {
+                let stats: Vec<_> = stats
+                    .iter()
+                    .map(|((is_export, method), stat)| {
+                        serde_json::json!({
+                            "method": method,
+                            "kind": if *is_export { "export" } else { "import" },
+                            "calls": stat.calls,
+                            "arg_size": stat.arg_size,
+                            "ret_size": stat.ret_size,
+                            "nanos": stat.nanos,
+                            "max_nanos": stat.max_nanos,
+                        })
+                    })
+                    .collect();
+                eprintln!("{}", serde_json::Value::Array(stats));
+                return;
+            }
+            eprintln!(
+                "{:>8} {:>12} {:>12} {:>12} {:>10} {:>10}  method",
+                "calls", "total(ms)", "avg(ms)", "max(ms)", "args(B)", "ret(B)"
+            );
+            for ((is_export, method), stat) in stats {
+                let ms = |nanos: u64| nanos as f64 / 1_000_000.0;
+                let kind = if *is_export { "export" } else { "import" };
+                eprintln!(
+                    "{:>8} {:>12.3} {:>12.3} {:>12.3} {:>10} {:>10}  {kind} {method}",
+                    stat.calls,
+                    ms(stat.nanos),
+                    ms(stat.nanos / stat.calls),
+                    ms(stat.max_nanos),
+                    stat.arg_size,
+                    stat.ret_size,
+                );
+            }
+        });
+    }
+}
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::sync::OnceLock;
+use std::time::Instant;
+static START: OnceLock<Instant> = OnceLock::new();
+#[derive(Default)]
+struct Stat {
+    calls: u64,
+    arg_size: u64,
+    ret_size: u64,
+    nanos: u64,
+    max_nanos: u64,
+}
+thread_local! {
+    // by (is_export, method)
+    static PROFILE: RefCell<BTreeMap<(bool, String), Stat>> = const { RefCell::new(BTreeMap::new()) };
+}
+/// The explicit seed, or the seed in the environment variable `var`, or a random one.
+fn env_seed(seed: Option<u64>, var: &str) -> u64 {
+    seed.or_else(|| std::env::var(var).ok()?.parse().ok())
+        .unwrap_or_else(|| {
+            let mut bytes = [0u8; 8];
+            getrandom::fill(&mut bytes).unwrap();
+            u64::from_le_bytes(bytes)
+        })
+}
+fn splitmix64(state: &mut u64) -> u64 {
+    *state = state.wrapping_add(0x9e3779b97f4a7c15);
+    let mut z = *state;
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+    z ^ (z >> 31)
 }
 bindings::export!(Component with_types_in bindings);
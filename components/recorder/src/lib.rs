@@ -5,65 +5,156 @@ mod bindings {
     });
 }
 
-use trace::Logger;
+use trace::{Format, FuncCall, Logger, binary::Encoder};
 struct Component;
 impl bindings::exports::proxy::recorder::record::Guest for Component {
     fn record_args(method: Option<String>, args: Vec<String>, is_export: bool) {
         let mut logger = Logger::new();
         let call = logger.record_args(method, args, is_export);
-        let json = serde_json::to_string(&call).unwrap();
-        println!("{json}");
+        print_call(call, true);
     }
     fn record_ret(method: Option<String>, ret: Option<String>, is_export: bool) {
         let mut logger = Logger::new();
         let call = logger.record_ret(method, ret, is_export);
-        let json = serde_json::to_string(&call).unwrap();
-        println!("{json}");
+        print_call(call, is_export);
+    }
+    fn new_label(kind: String) -> String {
+        let id = LABEL_ID.get();
+        LABEL_ID.set(id + 1);
+        format!("{kind}-{id}")
+    }
+    fn record_header(header: String) {
+        if HEADER_RECORDED.replace(true) {
+            return;
+        }
+        let header = match serde_json::from_str(&header) {
+            Ok(header) => header,
+            Err(err) => {
+                eprintln!("invalid trace header: {err}");
+                return;
+            }
+        };
+        let mut logger = Logger::new();
+        let call = logger.record_header(header).unwrap();
+        print_call(call, false);
+    }
+    fn record_stream(label: String, values: Option<Vec<String>>) {
+        let mut logger = Logger::new();
+        let call = logger.record_stream(label, values);
+        print_call(call, false);
     }
 }
 
 use std::cell::RefCell;
 thread_local! {
     static TRACE: RefCell<Option<Logger>> = RefCell::new(None);
+    static LABEL_ID: std::cell::Cell<u32> = const { std::cell::Cell::new(0) };
+    static HEADER_RECORDED: std::cell::Cell<bool> = const { std::cell::Cell::new(false) };
+    // the encoder and the buffered calls of a binary trace, see `print_call`
+    static ENCODER: RefCell<Option<(Format, Encoder, Vec<FuncCall>)>> = const { RefCell::new(None) };
+}
+
+/// Print the recorded call to stdout in the format of `PROXY_TRACE_FORMAT`. A binary trace is printed
+/// one frame per call. A compressed trace buffers the returns until the next call starts, so that a
+/// trap in the middle of a call still leaves the call in the trace.
+fn print_call(call: FuncCall, flush: bool) {
+    ENCODER.with_borrow_mut(|v| {
+        let (format, encoder, calls) = v.get_or_insert_with(|| {
+            let format = match std::env::var("PROXY_TRACE_FORMAT").map(|f| f.parse()) {
+                Ok(Ok(format)) => format,
+                Ok(Err(err)) => {
+                    eprintln!("PROXY_TRACE_FORMAT: {err}, fall back to json");
+                    Format::Json
+                }
+                Err(_) => Format::default(),
+            };
+            (format, Encoder::new(format == Format::Zstd), Vec::new())
+        });
+        if *format == Format::Json {
+            let json = serde_json::to_string(&call).unwrap();
+            println!("{json}");
+            return;
+        }
+        calls.push(call);
+        if *format == Format::Binary || flush || calls.len() >= 1024 {
+            use std::io::Write;
+            let frame = encoder.frame(calls.iter());
+            calls.clear();
+            let mut stdout = std::io::stdout();
+            stdout.write_all(&frame).unwrap();
+            stdout.flush().unwrap();
+        }
+    });
 }
 
-fn load_trace() {
+fn load_trace() -> Result<(), String> {
     let load = TRACE.with_borrow(|v| v.is_none());
     if load {
         TRACE.with_borrow_mut(|v| {
             use std::io::Read;
-            let mut input = String::new();
-            std::io::stdin().read_to_string(&mut input).unwrap();
+            let mut input = Vec::new();
+            std::io::stdin().read_to_end(&mut input).unwrap();
             let mut logger = Logger::new();
-            logger.load_trace(&input);
+            logger.load(&input)?;
+            if let Ok(matching) = std::env::var("PROXY_REPLAY_MATCH") {
+                let matching = matching
+                    .parse()
+                    .map_err(|err| format!("PROXY_REPLAY_MATCH: {err}"))?;
+                logger.set_matching(matching);
+            }
             *v = Some(logger);
-        });
+            Ok::<_, String>(())
+        })?;
     }
+    Ok(())
 }
 
 impl bindings::exports::proxy::recorder::replay::Guest for Component {
-    fn replay_export() -> Option<(String, Vec<String>)> {
-        load_trace();
-        TRACE.with_borrow_mut(|v| v.as_mut().unwrap().replay_export())
+    fn replay_export() -> Result<Option<(String, Vec<String>)>, String> {
+        load_trace()?;
+        TRACE
+            .with_borrow_mut(|v| v.as_mut().unwrap().replay_export())
+            .map_err(|err| err.to_string())
     }
-    fn assert_export_ret(assert_method: Option<String>, assert_ret: Option<String>) {
-        TRACE.with_borrow_mut(|v| {
-            v.as_mut()
-                .unwrap()
-                .assert_export_ret(assert_method, assert_ret)
-        });
+    fn replay_export_ret() -> Option<String> {
+        TRACE.with_borrow_mut(|v| v.as_mut().unwrap().replay_export_ret())
+    }
+    fn assert_export_ret(
+        assert_method: Option<String>,
+        assert_ret: Option<String>,
+    ) -> Result<(), String> {
+        TRACE
+            .with_borrow_mut(|v| {
+                v.as_mut()
+                    .unwrap()
+                    .assert_export_ret(assert_method, assert_ret)
+            })
+            .map_err(|err| err.to_string())
     }
     fn replay_import(
         assert_method: Option<String>,
         assert_args: Option<Vec<String>>,
-    ) -> Option<String> {
+    ) -> Result<Option<String>, String> {
         TRACE.with_borrow_mut(|v| {
             let (_, ret) = v
                 .as_mut()
                 .unwrap()
-                .replay_import(assert_method, assert_args, true);
-            ret
+                .replay_import(assert_method, assert_args, true)
+                .map_err(|err| err.to_string())?;
+            Ok(ret)
         })
     }
+    fn replay_stream(label: String) -> Option<Vec<String>> {
+        TRACE.with_borrow_mut(|v| v.as_mut().unwrap().replay_stream(&label))
+    }
+    fn check_header(header: String) -> Option<String> {
+        if let Err(err) = load_trace() {
+            return Some(err);
+        }
+        match serde_json::from_str(&header) {
+            Ok(header) => TRACE.with_borrow(|v| v.as_ref().unwrap().check_header(&header)),
+            Err(err) => Some(format!("invalid trace header: {err}")),
+        }
+    }
 }
 bindings::export!(Component with_types_in bindings);
@@ -0,0 +1,386 @@
+//! A compact binary encoding of the trace.
+//!
+//! A binary trace is a sequence of frames. Each frame starts with `MAGIC`, a flag byte and the
+//! payload length, so the frames can be found among other output, e.g. the stdout of the guest.
+//! The payload is optionally compressed with zstd. Method names and stream labels are interned:
+//! the first use of a name writes the string, and the later uses refer to it by index, across the
+//! frames of the same trace. The first frame of a trace starts a new name table, so that traces can
+//! be concatenated.
+
+use crate::FuncCall;
+use std::collections::HashMap;
+use std::str::FromStr;
+
+pub const MAGIC: &[u8; 4] = b"\0PXT";
+const COMPRESSED: u8 = 1;
+const FIRST: u8 = 2;
+
+/// The encoding of a trace file, or of the trace printed by the guest recorder.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Format {
+    /// One JSON `FuncCall` per line
+    #[default]
+    Json,
+    Binary,
+    /// Binary with zstd compression
+    Zstd,
+}
+impl FromStr for Format {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "json" => Ok(Format::Json),
+            "binary" => Ok(Format::Binary),
+            "zstd" => Ok(Format::Zstd),
+            _ => Err(format!(
+                "unknown trace format {s}, expect json, binary or zstd"
+            )),
+        }
+    }
+}
+
+impl std::fmt::Display for Format {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            Format::Json => "json",
+            Format::Binary => "binary",
+            Format::Zstd => "zstd",
+        };
+        f.write_str(name)
+    }
+}
+
+/// Whether the bytes contain a binary trace frame.
+pub fn is_binary(bytes: &[u8]) -> bool {
+    bytes.windows(MAGIC.len()).any(|w| w == MAGIC)
+}
+
+/// Encodes calls into frames. The same encoder has to be used for all the frames of a trace.
+pub struct Encoder {
+    compress: bool,
+    names: HashMap<String, u64>,
+    started: bool,
+}
+impl Encoder {
+    pub fn new(compress: bool) -> Self {
+        Self {
+            compress,
+            names: HashMap::new(),
+            started: false,
+        }
+    }
+    pub fn frame<'a>(&mut self, calls: impl IntoIterator<Item = &'a FuncCall>) -> Vec<u8> {
+        let mut payload = Vec::new();
+        for call in calls {
+            self.call(&mut payload, call);
+        }
+        let mut flags = 0;
+        if !std::mem::replace(&mut self.started, true) {
+            flags |= FIRST;
+        }
+        if self.compress {
+            payload = ruzstd::encoding::compress_to_vec(
+                &payload[..],
+                ruzstd::encoding::CompressionLevel::Fastest,
+            );
+            flags |= COMPRESSED;
+        }
+        let mut out = MAGIC.to_vec();
+        out.push(flags);
+        write_len(&mut out, payload.len() as u64);
+        out.extend(payload);
+        out
+    }
+    fn call(&mut self, out: &mut Vec<u8>, call: &FuncCall) {
+        match call {
+            FuncCall::ExportArgs { method, args } => {
+                out.push(0);
+                self.name(out, Some(method));
+                write_strs(out, args);
+            }
+            FuncCall::ExportRet { method, ret } => {
+                out.push(1);
+                self.name(out, method.as_deref());
+                write_opt(out, ret.as_deref());
+            }
+            FuncCall::ImportArgs { method, args } => {
+                out.push(2);
+                self.name(out, method.as_deref());
+                write_strs(out, args);
+            }
+            FuncCall::ImportRet { method, ret } => {
+                out.push(3);
+                self.name(out, method.as_deref());
+                write_opt(out, ret.as_deref());
+            }
+            FuncCall::Stream { label, values } => {
+                out.push(4);
+                self.name(out, Some(label));
+                match values {
+                    Some(values) => {
+                        out.push(1);
+                        write_strs(out, values);
+                    }
+                    None => out.push(0),
+                }
+            }
+            FuncCall::Header(header) => {
+                out.push(5);
+                write_str(out, &serde_json::to_string(header).unwrap());
+            }
+        }
+    }
+    // 0 is None, and n refers to the (n-1)th name. A new name is followed by the string.
+    fn name(&mut self, out: &mut Vec<u8>, name: Option<&str>) {
+        let Some(name) = name else {
+            return out.push(0);
+        };
+        match self.names.get(name) {
+            Some(idx) => write_len(out, idx + 1),
+            None => {
+                let idx = self.names.len() as u64;
+                self.names.insert(name.to_string(), idx);
+                write_len(out, idx + 1);
+                write_str(out, name);
+            }
+        }
+    }
+}
+
+/// Decode the frames in `bytes`, skipping the bytes outside of the frames. A truncated frame at the
+/// end, e.g. from a crashed run, is ignored.
+pub fn decode(bytes: &[u8]) -> Result<Vec<FuncCall>, String> {
+    let mut calls = Vec::new();
+    let mut names = Vec::new();
+    let mut rest = bytes;
+    while let Some(pos) = rest.windows(MAGIC.len()).position(|w| w == MAGIC) {
+        let offset = bytes.len() - rest.len() + pos;
+        let corrupted = || format!("corrupted trace frame at byte {offset}");
+        let mut reader = Reader(&rest[pos + MAGIC.len()..]);
+        let Some((flags, payload)) = reader.frame() else {
+            break;
+        };
+        rest = reader.0;
+        if flags & FIRST != 0 {
+            names.clear();
+        }
+        let payload = if flags & COMPRESSED != 0 {
+            use std::io::Read;
+            let mut out = Vec::new();
+            ruzstd::decoding::StreamingDecoder::new(payload)
+                .map_err(|_| corrupted())?
+                .read_to_end(&mut out)
+                .map_err(|_| corrupted())?;
+            out
+        } else {
+            payload.to_vec()
+        };
+        let mut reader = Reader(&payload);
+        while !reader.0.is_empty() {
+            calls.push(reader.call(&mut names).ok_or_else(corrupted)?);
+        }
+    }
+    Ok(calls)
+}
+
+struct Reader<'a>(&'a [u8]);
+impl<'a> Reader<'a> {
+    fn frame(&mut self) -> Option<(u8, &'a [u8])> {
+        let flags = self.byte()?;
+        let len = self.len()? as usize;
+        Some((flags, self.bytes(len)?))
+    }
+    fn call(&mut self, names: &mut Vec<String>) -> Option<FuncCall> {
+        Some(match self.byte()? {
+            0 => FuncCall::ExportArgs {
+                method: self.name(names)??,
+                args: self.strs()?,
+            },
+            1 => FuncCall::ExportRet {
+                method: self.name(names)?,
+                ret: self.opt()?,
+            },
+            2 => FuncCall::ImportArgs {
+                method: self.name(names)?,
+                args: self.strs()?,
+            },
+            3 => FuncCall::ImportRet {
+                method: self.name(names)?,
+                ret: self.opt()?,
+            },
+            4 => FuncCall::Stream {
+                label: self.name(names)??,
+                values: match self.byte()? {
+                    0 => None,
+                    _ => Some(self.strs()?),
+                },
+            },
+            5 => FuncCall::Header(serde_json::from_str(&self.str()?).ok()?),
+            _ => return None,
+        })
+    }
+    fn name(&mut self, names: &mut Vec<String>) -> Option<Option<String>> {
+        let idx = self.len()? as usize;
+        if idx == 0 {
+            return Some(None);
+        }
+        if idx == names.len() + 1 {
+            names.push(self.str()?);
+        }
+        Some(Some(names.get(idx - 1)?.clone()))
+    }
+    fn byte(&mut self) -> Option<u8> {
+        let (byte, rest) = self.0.split_first()?;
+        self.0 = rest;
+        Some(*byte)
+    }
+    fn bytes(&mut self, len: usize) -> Option<&'a [u8]> {
+        if self.0.len() < len {
+            return None;
+        }
+        let (bytes, rest) = self.0.split_at(len);
+        self.0 = rest;
+        Some(bytes)
+    }
+    fn len(&mut self) -> Option<u64> {
+        let mut res = 0;
+        for shift in (0..64).step_by(7) {
+            let byte = self.byte()?;
+            res |= ((byte & 0x7f) as u64) << shift;
+            if byte & 0x80 == 0 {
+                return Some(res);
+            }
+        }
+        None
+    }
+    fn str(&mut self) -> Option<String> {
+        let len = self.len()? as usize;
+        String::from_utf8(self.bytes(len)?.to_vec()).ok()
+    }
+    fn strs(&mut self) -> Option<Vec<String>> {
+        let len = self.len()?;
+        (0..len).map(|_| self.str()).collect()
+    }
+    fn opt(&mut self) -> Option<Option<String>> {
+        match self.byte()? {
+            0 => Some(None),
+            _ => Some(Some(self.str()?)),
+        }
+    }
+}
+
+fn write_len(out: &mut Vec<u8>, mut n: u64) {
+    loop {
+        let byte = (n & 0x7f) as u8;
+        n >>= 7;
+        if n == 0 {
+            return out.push(byte);
+        }
+        out.push(byte | 0x80);
+    }
+}
+fn write_str(out: &mut Vec<u8>, s: &str) {
+    write_len(out, s.len() as u64);
+    out.extend_from_slice(s.as_bytes());
+}
+fn write_strs(out: &mut Vec<u8>, strs: &[String]) {
+    write_len(out, strs.len() as u64);
+    for s in strs {
+        write_str(out, s);
+    }
+}
+fn write_opt(out: &mut Vec<u8>, s: Option<&str>) {
+    match s {
+        Some(s) => {
+            out.push(1);
+            write_str(out, s);
+        }
+        None => out.push(0),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Logger;
+
+    const TRACE: &str = r#"{"ExportArgs":{"method":"run","args":["\"a, [b]\"","1"]}}
+{"ImportArgs":{"method":"wasi:clocks/monotonic-clock.now","args":[]}}
+{"ImportRet":{"method":"wasi:clocks/monotonic-clock.now","ret":"42"}}
+{"Stream":{"label":"stream-0","values":["1","2"]}}
+{"Stream":{"label":"stream-0","values":null}}
+{"ImportArgs":{"method":"wasi:clocks/monotonic-clock.now","args":[]}}
+{"ImportRet":{"method":null,"ret":null}}
+{"ExportRet":{"method":"run","ret":"ok(\"\")"}}"#;
+
+    fn calls() -> Vec<FuncCall> {
+        let mut logger = Logger::new();
+        logger.load_trace(TRACE);
+        logger.0.into()
+    }
+    fn to_json(calls: &[FuncCall]) -> String {
+        calls
+            .iter()
+            .map(|call| serde_json::to_string(call).unwrap())
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+    fn encode(compress: bool) -> Vec<u8> {
+        let calls = calls();
+        let mut encoder = Encoder::new(compress);
+        let mut bytes = encoder.frame(&calls[..3]);
+        bytes.extend(encoder.frame(&calls[3..]));
+        bytes
+    }
+
+    #[test]
+    fn round_trip() {
+        for compress in [false, true] {
+            let bytes = encode(compress);
+            assert!(is_binary(&bytes));
+            assert_eq!(to_json(&decode(&bytes).unwrap()), TRACE);
+        }
+    }
+
+    #[test]
+    fn mixed_with_output() {
+        let calls = calls();
+        let mut encoder = Encoder::new(false);
+        let mut bytes = b"hello\n".to_vec();
+        bytes.extend(encoder.frame(&calls[..3]));
+        bytes.extend(b"world\n");
+        bytes.extend(encoder.frame(&calls[3..]));
+        bytes.extend(b"bye");
+        assert_eq!(to_json(&decode(&bytes).unwrap()), TRACE);
+    }
+
+    #[test]
+    fn concatenated() {
+        let mut bytes = encode(false);
+        bytes.extend(encode(true));
+        let expected = format!("{TRACE}\n{TRACE}");
+        assert_eq!(to_json(&decode(&bytes).unwrap()), expected);
+    }
+
+    #[test]
+    fn truncated() {
+        let calls = calls();
+        let mut encoder = Encoder::new(false);
+        let mut bytes = encoder.frame(&calls[..3]);
+        let last = encoder.frame(&calls[3..]);
+        bytes.extend(&last[..last.len() - 1]);
+        assert_eq!(to_json(&decode(&bytes).unwrap()), to_json(&calls[..3]));
+    }
+
+    #[test]
+    fn corrupted() {
+        for compress in [false, true] {
+            let mut bytes = encode(compress);
+            // the first byte of the payload, after the magic, flags and length
+            let idx = MAGIC.len() + 2;
+            bytes[idx] = 0xff;
+            let err = decode(&bytes).unwrap_err();
+            assert_eq!(err, "corrupted trace frame at byte 0");
+        }
+    }
+}
@@ -0,0 +1,307 @@
+//! A structural diff of WAVE values, without their types.
+
+/// A WAVE value split by its brackets, e.g. `{a: [1, 2], b: some("x")}`.
+#[derive(Debug, PartialEq)]
+enum Node<'a> {
+    /// Numbers, chars, strings, and names of enum cases, flags, bools, etc.
+    Atom(&'a str),
+    /// Lists, tuples, records and flags. The items of a record have labels.
+    Seq(char, Vec<(Option<&'a str>, Node<'a>)>),
+    /// Variant cases, options and results with a payload
+    Case(&'a str, Box<Node<'a>>),
+}
+
+/// The differences between two WAVE values at `path`, with the paths into the values, e.g.
+/// `ret.items[2]: expected 1, got 3`. The values are compared as text if they cannot be parsed.
+/// The labels of streams and futures are not compared, because the replaying components number
+/// them on their own.
+pub fn wave_diff(path: &str, expected: &str, actual: &str) -> Vec<String> {
+    let mut diffs = Vec::new();
+    match (parse(expected), parse(actual)) {
+        (Some(expected), Some(actual)) => diff_node(path, &expected, &actual, &mut diffs),
+        _ if expected != actual => diffs.push(format!("{path}: expected {expected}, got {actual}")),
+        _ => (),
+    }
+    diffs
+}
+
+fn diff_node(path: &str, expected: &Node, actual: &Node, diffs: &mut Vec<String>) {
+    match (expected, actual) {
+        (Node::Case(name1, payload1), Node::Case(name2, payload2)) if name1 == name2 => {
+            diff_node(&format!("{path}.{name1}"), payload1, payload2, diffs)
+        }
+        (Node::Seq(open1, items1), Node::Seq(open2, items2)) if open1 == open2 => {
+            let labeled = items1
+                .iter()
+                .chain(items2)
+                .all(|(label, _)| label.is_some());
+            if labeled && !items1.is_empty() && !items2.is_empty() {
+                // records are compared by field
+                for (label, item1) in items1 {
+                    let label = label.unwrap();
+                    let field = format!("{path}.{label}");
+                    match items2.iter().find(|(l, _)| *l == Some(label)) {
+                        Some((_, item2)) => diff_node(&field, item1, item2, diffs),
+                        None => diffs.push(format!("{field}: missing")),
+                    }
+                }
+                for (label, _) in items2 {
+                    if !items1.iter().any(|(l, _)| l == label) {
+                        diffs.push(format!("{path}.{}: unexpected", label.unwrap()));
+                    }
+                }
+                return;
+            }
+            for (idx, ((_, item1), (_, item2))) in items1.iter().zip(items2).enumerate() {
+                diff_node(&format!("{path}[{idx}]"), item1, item2, diffs);
+            }
+            if items1.len() != items2.len() {
+                diffs.push(format!(
+                    "{path}: expected {} items, got {}",
+                    items1.len(),
+                    items2.len()
+                ));
+            }
+        }
+        (Node::Atom(label1), Node::Atom(label2))
+            if async_label_kind(label1).is_some()
+                && async_label_kind(label1) == async_label_kind(label2) => {}
+        _ if expected != actual => diffs.push(format!(
+            "{path}: expected {}, got {}",
+            print(expected),
+            print(actual)
+        )),
+        _ => (),
+    }
+}
+
+/// The kind of a stream or future handle, e.g. `stream` for `stream-3`.
+fn async_label_kind(atom: &str) -> Option<&str> {
+    let atom = atom.trim_matches(|c: char| !c.is_ascii_alphanumeric());
+    let atom = atom.strip_prefix("borrow-").unwrap_or(atom);
+    let (kind, id) = atom.split_once('-')?;
+    (matches!(kind, "stream" | "future")
+        && !id.is_empty()
+        && id.bytes().all(|b| b.is_ascii_digit()))
+    .then_some(kind)
+}
+
+fn print(node: &Node) -> String {
+    match node {
+        Node::Atom(atom) => atom.to_string(),
+        Node::Case(name, payload) => format!("{name}({})", print(payload)),
+        Node::Seq(open, items) => {
+            let close = match open {
+                '[' => ']',
+                '(' => ')',
+                _ => '}',
+            };
+            let items: Vec<_> = items
+                .iter()
+                .map(|(label, item)| match label {
+                    Some(label) => format!("{label}: {}", print(item)),
+                    None => print(item),
+                })
+                .collect();
+            format!("{open}{}{close}", items.join(", "))
+        }
+    }
+}
+
+fn parse(text: &str) -> Option<Node<'_>> {
+    let mut parser = Parser(text);
+    let node = parser.value()?;
+    parser.0.trim().is_empty().then_some(node)
+}
+
+struct Parser<'a>(&'a str);
+impl<'a> Parser<'a> {
+    fn value(&mut self) -> Option<Node<'a>> {
+        self.0 = self.0.trim_start();
+        let first = self.0.chars().next()?;
+        match first {
+            '[' | '(' | '{' => {
+                self.0 = &self.0[1..];
+                let close = match first {
+                    '[' => ']',
+                    '(' => ')',
+                    _ => '}',
+                };
+                let mut items = Vec::new();
+                loop {
+                    self.0 = self.0.trim_start();
+                    if let Some(rest) = self.0.strip_prefix(close) {
+                        self.0 = rest;
+                        return Some(Node::Seq(first, items));
+                    }
+                    let label = if first == '{' { self.label() } else { None };
+                    items.push((label, self.value()?));
+                    self.0 = self.0.trim_start();
+                    if let Some(rest) = self.0.strip_prefix(',') {
+                        self.0 = rest;
+                    } else if !self.0.starts_with(close) {
+                        return None;
+                    }
+                }
+            }
+            '"' | '\'' => {
+                let mut escaped = false;
+                let end = self.0[1..].find(|c| {
+                    let end = c == first && !escaped;
+                    escaped = c == '\\' && !escaped;
+                    end
+                })? + 2;
+                let (atom, rest) = self.0.split_at(end);
+                self.0 = rest;
+                Some(Node::Atom(atom))
+            }
+            _ => {
+                let end = self
+                    .0
+                    .find([',', '(', ')', '[', ']', '{', '}', ':'])
+                    .unwrap_or(self.0.len());
+                let (atom, rest) = self.0.split_at(end);
+                let atom = atom.trim_end();
+                if atom.is_empty() {
+                    return None;
+                }
+                self.0 = rest;
+                if let Some(rest) = self.0.strip_prefix('(') {
+                    self.0 = rest;
+                    let payload = self.value()?;
+                    self.0 = self.0.trim_start().strip_prefix(')')?;
+                    return Some(Node::Case(atom, Box::new(payload)));
+                }
+                Some(Node::Atom(atom))
+            }
+        }
+    }
+    // The label of a record field, or None for flags.
+    fn label(&mut self) -> Option<&'a str> {
+        let end = self.0.find([':', ',', '}'])?;
+        let (label, rest) = self.0.split_at(end);
+        let rest = rest.strip_prefix(':')?;
+        self.0 = rest;
+        Some(label.trim())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn async_labels() {
+        assert!(wave_diff("args[0]", "stream-3", "stream-7").is_empty());
+        assert!(wave_diff("ret", "some(future-0)", "some(future-2)").is_empty());
+        assert_eq!(
+            wave_diff("ret", "stream-3", "future-3"),
+            vec!["ret: expected stream-3, got future-3"]
+        );
+        assert_eq!(
+            wave_diff("ret", "output-stream-3", "output-stream-4"),
+            vec!["ret: expected output-stream-3, got output-stream-4"]
+        );
+    }
+
+    #[test]
+    fn nested_records() {
+        assert!(
+            wave_diff(
+                "ret",
+                "{a: 1, b: {c: [1, 2]}}",
+                "{ a: 1, b: { c: [1, 2] } }"
+            )
+            .is_empty()
+        );
+        assert_eq!(
+            wave_diff(
+                "ret",
+                r#"{a: 1, b: {c: [1, 2], d: "x"}}"#,
+                r#"{a: 1, b: {c: [1, 3], d: "y"}}"#
+            ),
+            vec![
+                r#"ret.b.c[1]: expected 2, got 3"#,
+                r#"ret.b.d: expected "x", got "y""#
+            ]
+        );
+        assert_eq!(
+            wave_diff("ret", "{a: 1, b: 2}", "{a: 1, c: 2}"),
+            vec!["ret.b: missing", "ret.c: unexpected"]
+        );
+    }
+
+    #[test]
+    fn lists() {
+        assert_eq!(
+            wave_diff("args[0]", "[[1], [2, 3]]", "[[1], [2, 4], [5]]"),
+            vec![
+                "args[0][1][1]: expected 3, got 4",
+                "args[0]: expected 2 items, got 3"
+            ]
+        );
+        assert_eq!(
+            wave_diff("ret", "(1, [])", "(1, [2])"),
+            vec!["ret[1]: expected 0 items, got 1"]
+        );
+    }
+
+    #[test]
+    fn options_and_results() {
+        assert!(wave_diff("ret", "some(ok(1))", "some(ok(1))").is_empty());
+        assert_eq!(
+            wave_diff("ret", "ok(some(1))", "ok(some(2))"),
+            vec!["ret.ok.some: expected 1, got 2"]
+        );
+        assert_eq!(
+            wave_diff("ret", r#"some(ok(1))"#, r#"some(err("e"))"#),
+            vec![r#"ret.some: expected ok(1), got err("e")"#]
+        );
+        assert_eq!(
+            wave_diff("ret", "none", "some({a: 1})"),
+            vec!["ret: expected none, got some({a: 1})"]
+        );
+    }
+
+    #[test]
+    fn strings_with_delimiters() {
+        assert!(wave_diff("ret", r#"["a, b", "[c]"]"#, r#"["a, b", "[c]"]"#).is_empty());
+        assert_eq!(
+            wave_diff(
+                "ret",
+                r#"{s: "say \"hi\", {ok}", n: 1}"#,
+                r#"{s: "say \"hi\", {ok}", n: 2}"#
+            ),
+            vec!["ret.n: expected 1, got 2"]
+        );
+        assert_eq!(
+            wave_diff("ret", "[',', ']', 'a']", "[',', ']', 'b']"),
+            vec!["ret[2]: expected 'a', got 'b'"]
+        );
+        assert_eq!(
+            wave_diff("ret", r#"["a, b", "[c]"]"#, r#"["a, b", "(c)"]"#),
+            vec![r#"ret[1]: expected "[c]", got "(c)""#]
+        );
+        assert_eq!(
+            wave_diff("ret", r#"{s: "x}"}"#, r#"{s: "x,"}"#),
+            vec![r#"ret.s: expected "x}", got "x,""#]
+        );
+    }
+
+    #[test]
+    fn unparsed() {
+        assert!(wave_diff("ret", "[1, 2", "[1, 2").is_empty());
+        assert_eq!(
+            wave_diff("ret", "[1, 2", "[1, 3"),
+            vec!["ret: expected [1, 2, got [1, 3"]
+        );
+        assert_eq!(
+            wave_diff("ret", "{a: 1}", "{a: 1} }"),
+            vec!["ret: expected {a: 1}, got {a: 1} }"]
+        );
+        assert_eq!(
+            wave_diff("ret", r#""open"#, r#""open""#),
+            vec![r#"ret: expected "open, got "open""#]
+        );
+    }
+}
@@ -0,0 +1,87 @@
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+/// The version of the trace format. Traces with a different version cannot be replayed.
+pub const SCHEMA_VERSION: u32 = 1;
+
+/// The first event of a recorded trace, which identifies the component that produced it.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct TraceHeader {
+    pub version: u32,
+    /// The hash of the original component
+    pub component: String,
+    pub world: String,
+    /// The WIT text of the virtualized interfaces, e.g. `import wasi:io/streams@0.2.0`
+    pub wit: BTreeMap<String, String>,
+    /// The recording time in seconds since the Unix epoch, filled in by the recorder
+    pub time: u64,
+    /// The version of proxy-component
+    pub tool: String,
+}
+
+impl TraceHeader {
+    /// Check that a trace recorded with the `recorded` header can be replayed by the component of
+    /// `self`. Returns the warnings, or an error that explains the mismatches.
+    pub fn check(&self, recorded: &TraceHeader) -> Result<Vec<String>, String> {
+        let mut errors = Vec::new();
+        let mut warnings = Vec::new();
+        if self.version != recorded.version {
+            errors.push(format!(
+                "the trace has schema version {}, but the replayer expects version {}",
+                recorded.version, self.version
+            ));
+        }
+        if self.world != recorded.world {
+            errors.push(format!(
+                "the trace is recorded from world {}, but the component has world {}",
+                recorded.world, self.world
+            ));
+        }
+        for (name, wit) in &self.wit {
+            match recorded.wit.get(name) {
+                None => errors.push(format!("{name} is not recorded in the trace")),
+                Some(recorded_wit) if recorded_wit != wit => {
+                    let (old, new) = recorded_wit
+                        .lines()
+                        .zip(wit.lines())
+                        .find(|(old, new)| old != new)
+                        .unwrap_or_else(|| {
+                            let old = recorded_wit.lines().count();
+                            let new = wit.lines().count();
+                            if old > new {
+                                (recorded_wit.lines().nth(new).unwrap_or(""), "")
+                            } else {
+                                ("", wit.lines().nth(old).unwrap_or(""))
+                            }
+                        });
+                    errors.push(format!(
+                        "{name} has changed since the recording:\n      recorded: {}\n      now:      {}",
+                        old.trim(),
+                        new.trim()
+                    ));
+                }
+                Some(_) => (),
+            }
+        }
+        if self.component != recorded.component {
+            warnings.push(format!(
+                "the trace is recorded from a different binary (hash {}), replaying with {}",
+                recorded.component, self.component
+            ));
+        }
+        if self.tool != recorded.tool {
+            warnings.push(format!(
+                "the trace is recorded by proxy-component {}, replaying with {}",
+                recorded.tool, self.tool
+            ));
+        }
+        if errors.is_empty() {
+            Ok(warnings)
+        } else {
+            Err(format!(
+                "the trace does not match the component:\n  - {}",
+                errors.join("\n  - ")
+            ))
+        }
+    }
+}
@@ -1,4 +1,12 @@
-use std::collections::VecDeque;
+use std::collections::{HashMap, VecDeque};
+
+pub mod binary;
+mod diff;
+mod header;
+mod matching;
+pub use binary::Format;
+pub use header::{SCHEMA_VERSION, TraceHeader};
+pub use matching::{MatchPolicy, Matching};
 
 use serde::{Deserialize, Serialize};
 
@@ -20,13 +28,64 @@ pub enum FuncCall {
         method: Option<String>,
         ret: Option<String>,
     },
+    /// A chunk of a stream, or the value of a future. `None` marks the end of a stream.
+    Stream {
+        label: String,
+        values: Option<Vec<String>>,
+    },
+    /// The first event of a trace, see `TraceHeader`.
+    Header(TraceHeader),
+}
+
+pub struct Logger(pub VecDeque<FuncCall>, ReplayState);
+
+/// The number of replayed events in a divergence report
+const CONTEXT_LEN: usize = 5;
+
+/// The replayed events for the context of a divergence, and the match policies.
+#[derive(Default)]
+struct ReplayState {
+    count: usize,
+    recent: VecDeque<FuncCall>,
+    matching: Matching,
+    // the last recorded return of each import method, see `MatchPolicy::Skip`
+    last_rets: HashMap<String, Option<String>>,
+    // the import calls of a provider are not in a contract trace, see `Logger::contract_trace`
+    unchecked_imports: bool,
+}
+
+/// Where the replay diverges from the trace.
+#[derive(Serialize, Debug, Clone)]
+pub struct Divergence {
+    /// The index of the event, counting the replayed events
+    pub index: usize,
+    /// The recorded event, or `None` at the end of the trace
+    pub expected: Option<FuncCall>,
+    /// The call of the replay, or `None` when the replay waits for the next export call
+    pub actual: Option<FuncCall>,
+    /// The differences between the recorded and the replayed values
+    pub diff: Vec<String>,
+    /// The last replayed events
+    pub context: Vec<FuncCall>,
 }
 
-pub struct Logger(pub VecDeque<FuncCall>);
+/// A recorded call replayed against a provider in contract verification, see `Logger::contract_trace`.
+#[derive(Serialize, Debug)]
+pub struct ContractCall {
+    pub method: String,
+    pub args: Vec<String>,
+    pub expected: Option<String>,
+    pub actual: Option<String>,
+}
+impl ContractCall {
+    pub fn passed(&self) -> bool {
+        self.expected == self.actual
+    }
+}
 
 impl Logger {
     pub fn new() -> Self {
-        Self(VecDeque::new())
+        Self(VecDeque::new(), ReplayState::default())
     }
     pub fn load_trace(&mut self, text: &str) {
         self.0.clear();
@@ -38,6 +97,22 @@ impl Logger {
             }
         }
     }
+    /// Load a trace in any format. Binary traces are detected by the frame header.
+    pub fn load(&mut self, bytes: &[u8]) -> Result<(), String> {
+        if binary::is_binary(bytes) {
+            self.0 = binary::decode(bytes)?.into();
+        } else {
+            self.load_trace(&String::from_utf8_lossy(bytes));
+        }
+        Ok(())
+    }
+    pub fn dump(&self, format: Format) -> Vec<u8> {
+        match format {
+            Format::Json => self.dump_trace().into_bytes(),
+            Format::Binary => binary::Encoder::new(false).frame(&self.0),
+            Format::Zstd => binary::Encoder::new(true).frame(&self.0),
+        }
+    }
     pub fn dump_trace(&self) -> String {
         self.0
             .iter()
@@ -76,43 +151,314 @@ impl Logger {
         self.0.push_back(call.clone());
         call
     }
-    pub fn replay_export(&mut self) -> Option<(String, Vec<String>)> {
-        let call = self.0.pop_front()?;
+    /// Record the header with the current time, unless the trace already has one.
+    pub fn record_header(&mut self, mut header: TraceHeader) -> Option<FuncCall> {
+        if self.header().is_some() {
+            return None;
+        }
+        header.time = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map_or(0, |d| d.as_secs());
+        let call = FuncCall::Header(header);
+        self.0.push_front(call.clone());
+        Some(call)
+    }
+    pub fn header(&self) -> Option<&TraceHeader> {
+        self.0.iter().find_map(|call| match call {
+            FuncCall::Header(header) => Some(header),
+            _ => None,
+        })
+    }
+    /// Check the recorded header against the header of the replaying component, and print the
+    /// warnings. Traces without a header are not checked.
+    pub fn check_header(&self, header: &TraceHeader) -> Option<String> {
+        let recorded = self.header()?;
+        match header.check(recorded) {
+            Ok(warnings) => {
+                for warning in warnings {
+                    eprintln!("warning: {warning}");
+                }
+                None
+            }
+            Err(err) => Some(err),
+        }
+    }
+    pub fn record_stream(&mut self, label: String, values: Option<Vec<String>>) -> FuncCall {
+        let call = FuncCall::Stream { label, values };
+        self.0.push_back(call.clone());
+        call
+    }
+    // Stream events are consumed by label, out of order with the function calls.
+    fn front_call(&self) -> Option<&FuncCall> {
+        self.0.iter().find(|call| call.is_call())
+    }
+    fn pop_call(&mut self) -> Option<FuncCall> {
+        let idx = self.0.iter().position(|call| call.is_call())?;
+        self.0.remove(idx)
+    }
+    /// Turn the recorded import calls of the interfaces, e.g. `my:svc/api`, into export calls, so
+    /// that the trace of a consumer can be replayed against a provider. The other calls are dropped,
+    /// and the live import calls of the provider are not checked.
+    pub fn contract_trace(&mut self, names: &[String]) {
+        let matched = |method: &Option<String>| {
+            method.as_deref().is_some_and(|method| {
+                names
+                    .iter()
+                    .any(|name| name.split('@').next().unwrap() == method_interface(method))
+            })
+        };
+        let calls = std::mem::take(&mut self.0);
+        self.0 = calls
+            .into_iter()
+            .filter_map(|call| match call {
+                FuncCall::ImportArgs { method, args } if matched(&method) => {
+                    Some(FuncCall::ExportArgs {
+                        method: method.unwrap(),
+                        args,
+                    })
+                }
+                FuncCall::ImportRet { method, ret } if matched(&method) => {
+                    Some(FuncCall::ExportRet { method, ret })
+                }
+                _ => None,
+            })
+            .collect();
+        self.1.unchecked_imports = true;
+    }
+    pub fn replay_stream(&mut self, assert_label: &str) -> Option<Vec<String>> {
+        let idx = self.0.iter().position(
+            |call| matches!(call, FuncCall::Stream { label, .. } if label == assert_label),
+        )?;
+        let call = self.0.remove(idx).unwrap();
+        println!("stream: {}", call.to_string());
+        self.replayed(&call);
+        let FuncCall::Stream { values, .. } = call else {
+            unreachable!()
+        };
+        values
+    }
+    fn replayed(&mut self, call: &FuncCall) {
+        self.1.count += 1;
+        if self.1.recent.len() == CONTEXT_LEN {
+            self.1.recent.pop_front();
+        }
+        self.1.recent.push_back(call.clone());
+    }
+    fn divergence(
+        &self,
+        expected: Option<FuncCall>,
+        actual: Option<FuncCall>,
+        diff: Vec<String>,
+    ) -> Box<Divergence> {
+        Box::new(Divergence {
+            index: self.1.count,
+            expected,
+            actual,
+            diff,
+            context: self.1.recent.iter().cloned().collect(),
+        })
+    }
+    pub fn set_matching(&mut self, matching: Matching) {
+        self.1.matching = matching;
+    }
+    fn policy(&self, call: &FuncCall) -> MatchPolicy {
+        match call {
+            FuncCall::ImportArgs { method, .. } | FuncCall::ImportRet { method, .. } => {
+                self.1.matching.policy(method.as_deref())
+            }
+            _ => MatchPolicy::Strict,
+        }
+    }
+    /// The indices of the recorded import calls before the next export event.
+    fn pending_imports(&self) -> Vec<usize> {
+        self.0
+            .iter()
+            .enumerate()
+            .take_while(|(_, call)| {
+                !matches!(
+                    call,
+                    FuncCall::ExportArgs { .. } | FuncCall::ExportRet { .. }
+                )
+            })
+            .filter(|(_, call)| matches!(call, FuncCall::ImportArgs { .. }))
+            .map(|(idx, _)| idx)
+            .collect()
+    }
+    /// Remove the recorded import call at `idx`, with its return.
+    fn take_import(&mut self, idx: usize) -> Vec<FuncCall> {
+        let ret = (idx + 1..self.0.len())
+            .find(|idx| self.0[*idx].is_call())
+            .filter(|idx| matches!(self.0[*idx], FuncCall::ImportRet { .. }));
+        let mut calls = Vec::new();
+        if let Some(ret) = ret {
+            calls.push(self.0.remove(ret).unwrap());
+        }
+        calls.push(self.0.remove(idx).unwrap());
+        calls.reverse();
+        calls
+    }
+    /// Drop the recorded import calls of the lenient policies that are not replayed before the
+    /// next export event.
+    fn skip_unmatched(&mut self) {
+        if self.1.matching.is_strict() {
+            return;
+        }
+        for idx in self.pending_imports().into_iter().rev() {
+            if self.policy(&self.0[idx]) != MatchPolicy::Strict {
+                let calls = self.take_import(idx);
+                eprintln!(
+                    "warning: skipped the recorded call {}",
+                    calls[0].to_string()
+                );
+            }
+        }
+    }
+    /// Move the recorded import call that matches the replayed call by the match policy to the
+    /// front, so that it is replayed next. Returns the result of an unmatched call in skip policy.
+    fn match_import(
+        &mut self,
+        assert_method: &Option<String>,
+        assert_args: &Option<Vec<String>>,
+    ) -> Option<Option<String>> {
+        let policy = self.1.matching.policy(assert_method.as_deref());
+        let same_method = |call: &FuncCall| matches!(call, FuncCall::ImportArgs { method, .. } if method == assert_method);
+        let same_args = |call: &FuncCall| {
+            matches!(call, FuncCall::ImportArgs { method, args }
+                if method == assert_method && assert_args.as_ref().is_none_or(|a| a == args))
+        };
+        let pending = self.pending_imports();
+        let found = pending.iter().copied().find(|idx| {
+            let call = &self.0[*idx];
+            match policy {
+                MatchPolicy::Strict => self.policy(call) == MatchPolicy::Strict,
+                MatchPolicy::Fifo => same_method(call),
+                MatchPolicy::Args | MatchPolicy::Skip => same_args(call),
+            }
+        });
+        let Some(idx) = found else {
+            if policy == MatchPolicy::Skip
+                && let Some(ret) = assert_method
+                    .as_ref()
+                    .and_then(|method| self.1.last_rets.get(method))
+            {
+                eprintln!(
+                    "warning: {} is not recorded, returning the last recorded result",
+                    assert_method.as_deref().unwrap()
+                );
+                return Some(ret.clone());
+            }
+            return None;
+        };
+        let calls = self.take_import(idx);
+        if policy == MatchPolicy::Skip {
+            // the replay does not make the recorded calls before the match
+            for skipped in pending.into_iter().filter(|i| *i < idx).rev() {
+                if self.policy(&self.0[skipped]) == MatchPolicy::Skip {
+                    let calls = self.take_import(skipped);
+                    eprintln!(
+                        "warning: skipped the recorded call {}",
+                        calls[0].to_string()
+                    );
+                }
+            }
+        }
+        for call in calls.into_iter().rev() {
+            self.0.push_front(call);
+        }
+        None
+    }
+    pub fn replay_export(&mut self) -> Result<Option<(String, Vec<String>)>, Box<Divergence>> {
+        self.skip_unmatched();
+        let Some(call) = self.pop_call() else {
+            return Ok(None);
+        };
+        if !matches!(call, FuncCall::ExportArgs { .. }) {
+            return Err(self.divergence(Some(call), None, Vec::new()));
+        }
         println!("export call: {}", call.to_string());
+        self.replayed(&call);
         let FuncCall::ExportArgs { method, args } = call else {
-            panic!()
+            unreachable!()
         };
-        Some((method, args))
+        Ok(Some((method, args)))
     }
-    pub fn assert_export_ret(&mut self, assert_method: Option<String>, assert_ret: Option<String>) {
-        if let Some(FuncCall::ExportRet { .. }) = self.0.front() {
-            let call = self.0.pop_front().unwrap();
+    // The recorded return value of the current export call, without consuming it. The unmatched
+    // import calls are skipped first, as in `assert_export_ret`.
+    pub fn replay_export_ret(&mut self) -> Option<String> {
+        self.skip_unmatched();
+        match self.front_call() {
+            Some(FuncCall::ExportRet { ret, .. }) => ret.clone(),
+            _ => None,
+        }
+    }
+    /// Like `assert_export_ret`, but returns the recorded return value instead of asserting it.
+    pub fn pop_export_ret(&mut self) -> Option<Option<String>> {
+        if let Some(FuncCall::ExportRet { .. }) = self.front_call() {
+            let Some(FuncCall::ExportRet { ret, .. }) = self.pop_call() else {
+                unreachable!()
+            };
+            Some(ret)
+        } else {
+            None
+        }
+    }
+    pub fn assert_export_ret(
+        &mut self,
+        assert_method: Option<String>,
+        assert_ret: Option<String>,
+    ) -> Result<(), Box<Divergence>> {
+        self.skip_unmatched();
+        if let Some(FuncCall::ExportRet { .. }) = self.front_call() {
+            let call = self.pop_call().unwrap();
             println!("export ret: {}", call.to_string());
-            let FuncCall::ExportRet { method, ret } = call else {
-                panic!()
+            let FuncCall::ExportRet { method, ret } = &call else {
+                unreachable!()
             };
-            if let (Some(method), Some(assert_method)) = (method, assert_method) {
-                assert_eq!(method, assert_method);
+            let mut diff = method_diff(method, &assert_method);
+            diff.extend(ret_diff(ret, &assert_ret));
+            if !diff.is_empty() {
+                let actual = FuncCall::ExportRet {
+                    method: assert_method,
+                    ret: assert_ret,
+                };
+                return Err(self.divergence(Some(call), Some(actual), diff));
             }
-            assert_eq!(ret, assert_ret);
+            self.replayed(&call);
         }
+        Ok(())
     }
     pub fn replay_import(
         &mut self,
         assert_method: Option<String>,
         assert_args: Option<Vec<String>>,
         from_guest: bool,
-    ) -> (bool, Option<String>) {
+    ) -> Result<(bool, Option<String>), Box<Divergence>> {
+        if self.1.unchecked_imports {
+            return Ok((false, None));
+        }
         let mut exit_called = false;
-        let mut call = self.0.pop_front().unwrap();
+        let actual = FuncCall::ImportArgs {
+            method: assert_method.clone(),
+            args: assert_args.clone().unwrap_or_default(),
+        };
+        if !self.1.matching.is_strict()
+            && let Some(ret) = self.match_import(&assert_method, &assert_args)
+        {
+            return Ok((false, ret));
+        }
+        let Some(mut call) = self.pop_call() else {
+            return Err(self.divergence(None, Some(actual), Vec::new()));
+        };
         if let FuncCall::ImportArgs { method, args } = &call {
-            if let (Some(method), Some(assert_method)) = (method, assert_method) {
-                assert_eq!(method, &assert_method);
-            }
+            let mut diff = method_diff(method, &assert_method);
             if let Some(assert_args) = &assert_args {
-                assert_eq!(args, assert_args);
+                diff.extend(args_diff(args, assert_args));
+            }
+            if !diff.is_empty() {
+                return Err(self.divergence(Some(call), Some(actual), diff));
             }
             println!("import call: {}", call.to_string());
+            self.replayed(&call);
             if method
                 .as_ref()
                 .is_some_and(|m| m.starts_with("wasi:cli/exit"))
@@ -128,20 +474,128 @@ impl Logger {
                     std::process::exit(code);
                 } else {
                     exit_called = true;
-                    return (exit_called, Some("Something that can crash".to_string()));
+                    return Ok((exit_called, Some("Something that can crash".to_string())));
                 }
             }
-            call = self.0.pop_front().unwrap();
+            let Some(next) = self.pop_call() else {
+                return Err(self.divergence(None, Some(actual), Vec::new()));
+            };
+            call = next;
+        }
+        if !matches!(call, FuncCall::ImportRet { .. }) {
+            return Err(self.divergence(Some(call), Some(actual), Vec::new()));
         }
         println!("import ret: {}", call.to_string());
+        self.replayed(&call);
         let FuncCall::ImportRet { ret, .. } = call else {
-            panic!()
+            unreachable!()
         };
-        (exit_called, ret)
+        if let Some(method) = assert_method {
+            self.1.last_rets.insert(method, ret.clone());
+        }
+        Ok((exit_called, ret))
+    }
+}
+
+fn method_diff(expected: &Option<String>, actual: &Option<String>) -> Vec<String> {
+    match (expected, actual) {
+        (Some(expected), Some(actual)) if expected != actual => {
+            vec![format!("method: expected {expected}, got {actual}")]
+        }
+        _ => Vec::new(),
+    }
+}
+fn args_diff(expected: &[String], actual: &[String]) -> Vec<String> {
+    let mut diff: Vec<_> = expected
+        .iter()
+        .zip(actual)
+        .enumerate()
+        .flat_map(|(idx, (expected, actual))| {
+            diff::wave_diff(&format!("args[{idx}]"), expected, actual)
+        })
+        .collect();
+    if expected.len() != actual.len() {
+        diff.push(format!(
+            "args: expected {} args, got {}",
+            expected.len(),
+            actual.len()
+        ));
+    }
+    diff
+}
+fn ret_diff(expected: &Option<String>, actual: &Option<String>) -> Vec<String> {
+    match (expected, actual) {
+        (Some(expected), Some(actual)) => diff::wave_diff("ret", expected, actual),
+        (None, None) => Vec::new(),
+        _ => vec![format!(
+            "ret: expected {}, got {}",
+            expected.as_deref().unwrap_or("()"),
+            actual.as_deref().unwrap_or("()")
+        )],
+    }
+}
+
+impl std::fmt::Display for Divergence {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "replay diverges from the trace at event #{}", self.index)?;
+        let expected = self
+            .expected
+            .as_ref()
+            .map_or("end of trace".to_string(), describe);
+        let actual = self
+            .actual
+            .as_ref()
+            .map_or("waiting for the next export call".to_string(), describe);
+        writeln!(f, "  expected: {expected}")?;
+        write!(f, "  actual:   {actual}")?;
+        for diff in &self.diff {
+            write!(f, "\n    {diff}")?;
+        }
+        if !self.context.is_empty() {
+            write!(f, "\n  last events:")?;
+            let start = self.index - self.context.len();
+            for (idx, call) in self.context.iter().enumerate() {
+                write!(f, "\n    #{} {}", start + idx, describe(call))?;
+            }
+        }
+        Ok(())
     }
 }
+fn describe(call: &FuncCall) -> String {
+    let kind = match call {
+        FuncCall::ExportArgs { .. } => "export call",
+        FuncCall::ExportRet { .. } => "export ret",
+        FuncCall::ImportArgs { .. } => "import call",
+        FuncCall::ImportRet { .. } => "import ret",
+        FuncCall::Stream { .. } => "stream",
+        FuncCall::Header(_) => "header",
+    };
+    format!("{kind} {}", call.to_string())
+}
+
+/// The interface of a recorded method, e.g., `wasi:io/streams` for
+/// `[method]wasi:io/streams/output-stream.write`, or the function name of a world function.
+fn method_interface(method: &str) -> &str {
+    let method = match method.strip_prefix('[') {
+        Some(rest) => rest.split_once(']').map_or(method, |(_, name)| name),
+        None => method,
+    };
+    if !method.contains(':') {
+        return method;
+    }
+    let end = method
+        .match_indices('/')
+        .nth(1)
+        .map(|(idx, _)| idx)
+        .or_else(|| method.rfind('.'))
+        .unwrap_or(method.len());
+    &method[..end]
+}
 
 impl FuncCall {
+    fn is_call(&self) -> bool {
+        !matches!(self, FuncCall::Stream { .. } | FuncCall::Header(_))
+    }
     pub fn to_string(&self) -> String {
         match self {
             FuncCall::ExportArgs { method, args } => format!("{method}({})", args.join(", ")),
@@ -153,6 +607,159 @@ impl FuncCall {
             FuncCall::ExportRet { ret, .. } | FuncCall::ImportRet { ret, .. } => {
                 ret.as_deref().unwrap_or("()").to_owned()
             }
+            FuncCall::Stream { label, values } => match values {
+                Some(values) => format!("{label} <- [{}]", values.join(", ")),
+                None => format!("{label} <- end"),
+            },
+            FuncCall::Header(header) => format!("trace of {}", header.world),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn call(method: &str, args: &[&str]) -> FuncCall {
+        FuncCall::ImportArgs {
+            method: Some(method.to_string()),
+            args: args.iter().map(|arg| arg.to_string()).collect(),
+        }
+    }
+    fn ret(method: &str, ret: &str) -> FuncCall {
+        FuncCall::ImportRet {
+            method: Some(method.to_string()),
+            ret: Some(ret.to_string()),
+        }
+    }
+    /// A trace of one export call `run` with the import calls.
+    fn run_trace(imports: &[(&str, &str, &str)], matching: &str) -> Logger {
+        let mut logger = Logger::new();
+        logger.0.push_back(FuncCall::ExportArgs {
+            method: "run".to_string(),
+            args: Vec::new(),
+        });
+        for (method, arg, res) in imports {
+            let args: &[&str] = if arg.is_empty() { &[] } else { &[arg] };
+            logger.0.push_back(call(method, args));
+            logger.0.push_back(ret(method, res));
         }
+        logger.0.push_back(FuncCall::ExportRet {
+            method: Some("run".to_string()),
+            ret: None,
+        });
+        logger.set_matching(matching.parse().unwrap());
+        logger.replay_export().unwrap();
+        logger
+    }
+    fn replay(logger: &mut Logger, method: &str, arg: &str) -> Option<String> {
+        let args = if arg.is_empty() {
+            Vec::new()
+        } else {
+            vec![arg.to_string()]
+        };
+        logger
+            .replay_import(Some(method.to_string()), Some(args), false)
+            .unwrap()
+            .1
+    }
+    fn finish(logger: &mut Logger) {
+        logger
+            .assert_export_ret(Some("run".to_string()), None)
+            .unwrap();
+        assert!(logger.0.is_empty());
+    }
+
+    const NOW: &str = "wasi:clocks/monotonic-clock.now";
+    const GET: &str = "my:svc/api.get";
+    const PUT: &str = "my:svc/api.put";
+
+    #[test]
+    fn strict_order() {
+        let mut logger = run_trace(&[(GET, "1", "one"), (PUT, "2", "")], "strict");
+        assert!(
+            logger
+                .replay_import(Some(PUT.to_string()), Some(vec!["2".to_string()]), false)
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn fifo_reorders_methods() {
+        let mut logger = run_trace(
+            &[(GET, "1", "one"), (PUT, "2", ""), (GET, "3", "three")],
+            "fifo",
+        );
+        assert_eq!(replay(&mut logger, PUT, "2").as_deref(), Some(""));
+        assert_eq!(replay(&mut logger, GET, "1").as_deref(), Some("one"));
+        assert_eq!(replay(&mut logger, GET, "3").as_deref(), Some("three"));
+        finish(&mut logger);
+    }
+
+    #[test]
+    fn args_reorders_calls() {
+        let mut logger = run_trace(&[(GET, "1", "one"), (GET, "2", "two")], "args");
+        assert_eq!(replay(&mut logger, GET, "2").as_deref(), Some("two"));
+        assert_eq!(replay(&mut logger, GET, "1").as_deref(), Some("one"));
+        finish(&mut logger);
+    }
+
+    #[test]
+    fn skip_unmatched_calls() {
+        let mut logger = run_trace(
+            &[(NOW, "", "1"), (GET, "1", "one"), (NOW, "", "2")],
+            "strict,wasi:clocks/*=skip",
+        );
+        // the strict call is replayed in order, the clock calls around it are left for later
+        assert_eq!(replay(&mut logger, GET, "1").as_deref(), Some("one"));
+        assert_eq!(replay(&mut logger, NOW, "").as_deref(), Some("1"));
+        // the second clock call is not replayed, and is skipped at the end of the export call
+        finish(&mut logger);
+    }
+
+    #[test]
+    fn skip_earlier_calls() {
+        let mut logger = run_trace(&[(GET, "1", "one"), (GET, "2", "two")], "skip");
+        assert_eq!(replay(&mut logger, GET, "2").as_deref(), Some("two"));
+        // the call before the match is skipped, and an extra call returns the last result
+        assert_eq!(replay(&mut logger, GET, "1").as_deref(), Some("two"));
+        finish(&mut logger);
+    }
+
+    #[test]
+    fn contract_with_provider_imports() {
+        let mut logger = Logger::new();
+        logger.0 = VecDeque::from([
+            FuncCall::ExportArgs {
+                method: "run".to_string(),
+                args: Vec::new(),
+            },
+            call("my:svc/api.get", &["1"]),
+            call("wasi:clocks/monotonic-clock.now", &[]),
+            ret("wasi:clocks/monotonic-clock.now", "42"),
+            ret("my:svc/api.get", "\"one\""),
+            FuncCall::ExportRet {
+                method: Some("run".to_string()),
+                ret: None,
+            },
+        ]);
+        logger.contract_trace(&["my:svc/api@0.1.0".to_string()]);
+        assert_eq!(logger.0.len(), 2);
+        let export = logger.replay_export().unwrap();
+        assert_eq!(
+            export,
+            Some(("my:svc/api.get".to_string(), vec!["1".to_string()]))
+        );
+        // the provider calls its own imports, which are not in the contract trace
+        let now = logger
+            .replay_import(
+                Some("wasi:clocks/monotonic-clock.now".to_string()),
+                Some(Vec::new()),
+                false,
+            )
+            .unwrap();
+        assert_eq!(now, (false, None));
+        assert_eq!(logger.pop_export_ret(), Some(Some("\"one\"".to_string())));
+        assert_eq!(logger.replay_export().unwrap(), None);
     }
 }
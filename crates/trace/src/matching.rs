@@ -0,0 +1,135 @@
+use crate::method_interface;
+use std::str::FromStr;
+
+/// How a replayed import call is matched with the recorded calls of the current export call.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum MatchPolicy {
+    /// The next recorded call, in order
+    #[default]
+    Strict,
+    /// The next recorded call of the same method, so that calls to different methods can be reordered
+    Fifo,
+    /// The first recorded call with the same method and arguments
+    Args,
+    /// The next recorded call with the same method and arguments. The recorded calls before it are
+    /// skipped, and an extra call returns the last recorded result of the method, with warnings.
+    Skip,
+}
+impl FromStr for MatchPolicy {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "strict" => Ok(MatchPolicy::Strict),
+            "fifo" => Ok(MatchPolicy::Fifo),
+            "args" => Ok(MatchPolicy::Args),
+            "skip" => Ok(MatchPolicy::Skip),
+            _ => Err(format!(
+                "unknown match policy {s}, expect strict, fifo, args or skip"
+            )),
+        }
+    }
+}
+
+/// The match policies of the replay, e.g. `fifo,wasi:clocks/*=skip`: the default policy, and the
+/// policies of interfaces. `wasi:clocks/*` matches all the interfaces in the package.
+#[derive(Debug, Clone, Default)]
+pub struct Matching {
+    default: MatchPolicy,
+    overrides: Vec<(String, MatchPolicy)>,
+}
+impl FromStr for Matching {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut matching = Matching::default();
+        for item in s.split(',').map(str::trim).filter(|item| !item.is_empty()) {
+            match item.split_once('=') {
+                Some((name, policy)) => {
+                    let name = name.trim().split('@').next().unwrap().to_string();
+                    matching.overrides.push((name, policy.trim().parse()?));
+                }
+                None => matching.default = item.parse()?,
+            }
+        }
+        Ok(matching)
+    }
+}
+impl Matching {
+    pub fn is_strict(&self) -> bool {
+        self.default == MatchPolicy::Strict
+            && self
+                .overrides
+                .iter()
+                .all(|(_, policy)| *policy == MatchPolicy::Strict)
+    }
+    pub fn policy(&self, method: Option<&str>) -> MatchPolicy {
+        let Some(method) = method else {
+            return self.default;
+        };
+        let interface = method_interface(method);
+        self.overrides
+            .iter()
+            .rev()
+            .find(|(name, _)| match name.strip_suffix("/*") {
+                Some(package) => interface.split('/').next() == Some(package),
+                None => name == interface,
+            })
+            .map_or(self.default, |(_, policy)| *policy)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse() {
+        let matching: Matching = " fifo , wasi:clocks/*=skip, my:svc/api@0.1.0 = args,"
+            .parse()
+            .unwrap();
+        assert_eq!(matching.default, MatchPolicy::Fifo);
+        assert_eq!(
+            matching.overrides,
+            [
+                ("wasi:clocks/*".to_string(), MatchPolicy::Skip),
+                ("my:svc/api".to_string(), MatchPolicy::Args),
+            ]
+        );
+        assert!(!matching.is_strict());
+        let matching: Matching = "".parse().unwrap();
+        assert_eq!(matching.default, MatchPolicy::Strict);
+        assert!(matching.is_strict());
+        assert!(
+            "strict,my:svc/api=strict"
+                .parse::<Matching>()
+                .unwrap()
+                .is_strict()
+        );
+        assert!("lenient".parse::<Matching>().is_err());
+        assert!("my:svc/api=lenient".parse::<Matching>().is_err());
+    }
+
+    #[test]
+    fn overrides() {
+        let matching: Matching =
+            "args,wasi:clocks/*=skip,wasi:clocks/wall-clock=strict,my:svc/api=fifo"
+                .parse()
+                .unwrap();
+        assert_eq!(matching.policy(None), MatchPolicy::Args);
+        assert_eq!(matching.policy(Some("run")), MatchPolicy::Args);
+        assert_eq!(
+            matching.policy(Some("wasi:clocks/monotonic-clock.now")),
+            MatchPolicy::Skip
+        );
+        // the later override wins
+        assert_eq!(
+            matching.policy(Some("wasi:clocks/wall-clock.now")),
+            MatchPolicy::Strict
+        );
+        assert_eq!(
+            matching.policy(Some("[method]my:svc/api/store.get")),
+            MatchPolicy::Fifo
+        );
+        assert_eq!(matching.policy(Some("my:svc/api2.get")), MatchPolicy::Args);
+        assert_eq!(matching.policy(Some("my:other/api.get")), MatchPolicy::Args);
+    }
+}
@@ -0,0 +1,386 @@
+use anyhow::{Context, Result};
+use std::collections::{BTreeMap, BTreeSet, btree_map::Entry};
+use std::path::{Path, PathBuf};
+use wit_bindgen_core::wit_parser;
+use wit_component::WitPrinter;
+use wit_parser::*;
+
+/// The host interfaces of an adapter in adapt mode. The component imports the old versions of the
+/// packages, and the adapter forwards them to the versions loaded from `--to`, e.g. `wasi:http@0.2.0`
+/// to `wasi:http@0.2.6`. The types are converted by `ProxyTrait`, so they have to be structurally
+/// equal in both versions.
+pub struct Adapter {
+    host: Resolve,
+    /// The old and new versions of the adapted packages, by `namespace:name`
+    versions: BTreeMap<String, (String, String)>,
+    /// The files of the old versions in the wit directory
+    old_files: Vec<PathBuf>,
+    /// The host packages to write to the wit directory, including their dependencies
+    host_packages: BTreeSet<PackageId>,
+}
+
+impl Adapter {
+    pub fn new(to: &Path, wit_dir: &Path) -> Result<Self> {
+        let mut resolve = Resolve::default();
+        let (main_id, sources) = resolve.push_dir(wit_dir)?;
+        let world = resolve.select_world(&[main_id], None)?;
+        let mut host = Resolve::default();
+        host.push_path(to)
+            .with_context(|| format!("Failed to parse wit files in {}", to.display()))?;
+        let mut host_names: BTreeMap<String, Vec<PackageId>> = BTreeMap::new();
+        for (id, pkg) in host.packages.iter() {
+            host_names
+                .entry(format!("{}:{}", pkg.name.namespace, pkg.name.name))
+                .or_default()
+                .push(id);
+        }
+        let mut gaps = Vec::new();
+        let mut versions = BTreeMap::new();
+        let mut adapted = BTreeMap::new();
+        let mut old_files = Vec::new();
+        for (id, pkg) in resolve.packages.iter() {
+            let key = format!("{}:{}", pkg.name.namespace, pkg.name.name);
+            let Some(ids) = host_names.get(&key) else {
+                continue;
+            };
+            if ids
+                .iter()
+                .any(|id| host.packages[*id].name.version == pkg.name.version)
+            {
+                continue;
+            }
+            if ids.len() > 1 {
+                gaps.push(format!("{} has several versions of {key}", to.display()));
+                continue;
+            }
+            let host_id = ids[0];
+            let (Some(old), Some(new)) = (&pkg.name.version, &host.packages[host_id].name.version)
+            else {
+                gaps.push(format!("{key} is not versioned"));
+                continue;
+            };
+            if versions
+                .insert(key.clone(), (old.to_string(), new.to_string()))
+                .is_some()
+            {
+                gaps.push(format!("the component uses several versions of {key}"));
+            }
+            eprintln!("Adapting {key}@{old} to {key}@{new}");
+            adapted.insert(id, host_id);
+            old_files.extend(
+                sources
+                    .package_paths(id)
+                    .into_iter()
+                    .flatten()
+                    .map(Path::to_path_buf),
+            );
+        }
+        if adapted.is_empty() {
+            anyhow::bail!(
+                "{} has no other version of the packages used by the component",
+                to.display()
+            );
+        }
+        let check = Check {
+            old: &resolve,
+            new: &host,
+        };
+        // The packages that stay in the old version cannot use the adapted ones
+        for (id, pkg) in resolve.packages.iter() {
+            if id == main_id || adapted.contains_key(&id) {
+                continue;
+            }
+            for dep in resolve.package_direct_deps(id) {
+                if adapted.contains_key(&dep) {
+                    gaps.push(format!(
+                        "{} uses {}, but is not in {}",
+                        pkg.name,
+                        resolve.packages[dep].name,
+                        to.display()
+                    ));
+                }
+            }
+        }
+        let world = &resolve.worlds[world];
+        for (name, item) in &world.imports {
+            if let WorldItem::Type(ty) = item
+                && let TypeDefKind::Type(Type::Id(ty)) = resolve.types[*ty].kind
+                && let TypeOwner::Interface(iface) = resolve.types[ty].owner
+                && resolve.interfaces[iface]
+                    .package
+                    .is_some_and(|pkg| adapted.contains_key(&pkg))
+            {
+                gaps.push(format!(
+                    "world-level type `{}` uses {}",
+                    resolve.name_world_key(name),
+                    resolve.id_of(iface).unwrap()
+                ));
+            }
+        }
+        // The adapted interfaces used by the component, and whether they are exported
+        let mut interfaces = BTreeMap::new();
+        let mut stack = Vec::new();
+        for (items, is_export) in [(&world.imports, false), (&world.exports, true)] {
+            for item in items.values() {
+                if let WorldItem::Interface { id, .. } = item {
+                    interfaces.insert(*id, is_export);
+                    stack.push(*id);
+                }
+            }
+        }
+        while let Some(id) = stack.pop() {
+            for dep in resolve.interface_direct_deps(id) {
+                if let Entry::Vacant(entry) = interfaces.entry(dep) {
+                    entry.insert(false);
+                    stack.push(dep);
+                }
+            }
+        }
+        for (id, is_export) in interfaces {
+            let iface = &resolve.interfaces[id];
+            let Some(host_pkg) = iface.package.and_then(|pkg| adapted.get(&pkg)) else {
+                continue;
+            };
+            let old_name = resolve.id_of(id).unwrap();
+            let new_pkg = &host.packages[*host_pkg];
+            let Some(new_iface) = iface
+                .name
+                .as_ref()
+                .and_then(|name| new_pkg.interfaces.get(name))
+            else {
+                gaps.push(format!("{old_name} is missing in {}", new_pkg.name));
+                continue;
+            };
+            let new_iface = &host.interfaces[*new_iface];
+            for (name, ty) in &iface.types {
+                // the used types are checked in their own interfaces
+                if let TypeDefKind::Type(Type::Id(used)) = resolve.types[*ty].kind
+                    && resolve.types[used].owner != TypeOwner::Interface(id)
+                {
+                    continue;
+                }
+                match new_iface.types.get(name) {
+                    None => gaps.push(format!(
+                        "type `{name}` of {old_name} is missing in {}",
+                        new_pkg.name
+                    )),
+                    Some(new_ty) if !check.same_type(&Type::Id(*ty), &Type::Id(*new_ty)) => gaps
+                        .push(format!(
+                            "type `{name}` of {old_name} is changed in {}",
+                            new_pkg.name
+                        )),
+                    Some(_) => (),
+                }
+            }
+            for (name, func) in &iface.functions {
+                match new_iface.functions.get(name) {
+                    None => gaps.push(format!(
+                        "function `{name}` of {old_name} is missing in {}",
+                        new_pkg.name
+                    )),
+                    Some(new_func) if !check.same_func(func, new_func) => gaps.push(format!(
+                        "function `{name}` of {old_name} is changed in {}",
+                        new_pkg.name
+                    )),
+                    Some(_) => (),
+                }
+            }
+            // The host calls the new functions of the exports, which the component does not have
+            if is_export {
+                for name in new_iface.functions.keys() {
+                    if !iface.functions.contains_key(name) {
+                        gaps.push(format!(
+                            "function `{name}` is added to the export {old_name} in {}",
+                            new_pkg.name
+                        ));
+                    }
+                }
+            }
+        }
+        if !gaps.is_empty() {
+            anyhow::bail!(
+                "cannot adapt the component to {}:\n{}",
+                to.display(),
+                gaps.iter()
+                    .map(|gap| format!("  - {gap}"))
+                    .collect::<Vec<_>>()
+                    .join("\n")
+            );
+        }
+        // The dependencies of the host packages, except the ones the component already has
+        let old_names: BTreeSet<_> = resolve
+            .packages
+            .iter()
+            .map(|(_, pkg)| pkg.name.to_string())
+            .collect();
+        let mut host_packages = BTreeSet::new();
+        let mut stack: Vec<_> = adapted.into_values().collect();
+        while let Some(id) = stack.pop() {
+            if old_names.contains(&host.packages[id].name.to_string()) || !host_packages.insert(id)
+            {
+                continue;
+            }
+            stack.extend(host.package_direct_deps(id));
+        }
+        Ok(Self {
+            host,
+            versions,
+            old_files,
+            host_packages,
+        })
+    }
+    /// The host name of an interface, e.g. `wasi:http/types@0.2.0` -> `wasi:http/types@0.2.6`.
+    pub fn host_name(&self, name: &str) -> String {
+        if let Some((pkg, iface)) = name.split_once('/')
+            && let Some((iface, version)) = iface.rsplit_once('@')
+            && let Some((old, new)) = self.versions.get(pkg)
+            && old == version
+        {
+            format!("{pkg}/{iface}@{new}")
+        } else {
+            name.to_string()
+        }
+    }
+    /// Replace the old versions in the wit directory with the host packages. This runs after the
+    /// old versions are wrapped by `Opt::generate_wrapped_wits`.
+    pub fn write_packages(&self, wit_dir: &Path) -> Result<()> {
+        for path in &self.old_files {
+            std::fs::remove_file(path)?;
+        }
+        for id in &self.host_packages {
+            let name = &self.host.packages[*id].name;
+            let mut printer = WitPrinter::default();
+            printer.print_package(&self.host, *id, true)?;
+            let filename = if let Some(ver) = &name.version {
+                format!("{}@{}.wit", name.name, ver)
+            } else {
+                format!("{}.wit", name.name)
+            };
+            std::fs::write(
+                wit_dir.join("deps").join(&filename),
+                printer.output.to_string(),
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// Structural equality of the types in the old and new versions. The named types also need the
+/// same name and interface, because `ProxyTrait` converts them by their paths.
+struct Check<'a> {
+    old: &'a Resolve,
+    new: &'a Resolve,
+}
+impl Check<'_> {
+    fn same_func(&self, old: &Function, new: &Function) -> bool {
+        std::mem::discriminant(&old.kind) == std::mem::discriminant(&new.kind)
+            && old.params.len() == new.params.len()
+            && old
+                .params
+                .iter()
+                .zip(&new.params)
+                .all(|((_, old), (_, new))| self.same_type(old, new))
+            && self.same_opt(&old.result, &new.result)
+    }
+    fn same_opt(&self, old: &Option<Type>, new: &Option<Type>) -> bool {
+        match (old, new) {
+            (Some(old), Some(new)) => self.same_type(old, new),
+            (None, None) => true,
+            _ => false,
+        }
+    }
+    fn same_type(&self, old: &Type, new: &Type) -> bool {
+        match (dealias(self.old, *old), dealias(self.new, *new)) {
+            (Type::Id(old), Type::Id(new)) => self.same_type_def(old, new),
+            (old, new) => old == new,
+        }
+    }
+    fn same_type_def(&self, old: TypeId, new: TypeId) -> bool {
+        let old = &self.old.types[old];
+        let new = &self.new.types[new];
+        if old.name != new.name || owner(self.old, old) != owner(self.new, new) {
+            return false;
+        }
+        match (&old.kind, &new.kind) {
+            (TypeDefKind::Record(old), TypeDefKind::Record(new)) => {
+                old.fields.len() == new.fields.len()
+                    && old
+                        .fields
+                        .iter()
+                        .zip(&new.fields)
+                        .all(|(old, new)| old.name == new.name && self.same_type(&old.ty, &new.ty))
+            }
+            (TypeDefKind::Resource, TypeDefKind::Resource) => true,
+            (TypeDefKind::Handle(Handle::Own(old)), TypeDefKind::Handle(Handle::Own(new)))
+            | (
+                TypeDefKind::Handle(Handle::Borrow(old)),
+                TypeDefKind::Handle(Handle::Borrow(new)),
+            ) => self.same_type(&Type::Id(*old), &Type::Id(*new)),
+            (TypeDefKind::Flags(old), TypeDefKind::Flags(new)) => old
+                .flags
+                .iter()
+                .map(|flag| &flag.name)
+                .eq(new.flags.iter().map(|flag| &flag.name)),
+            (TypeDefKind::Tuple(old), TypeDefKind::Tuple(new)) => {
+                old.types.len() == new.types.len()
+                    && old
+                        .types
+                        .iter()
+                        .zip(&new.types)
+                        .all(|(old, new)| self.same_type(old, new))
+            }
+            (TypeDefKind::Variant(old), TypeDefKind::Variant(new)) => {
+                old.cases.len() == new.cases.len()
+                    && old
+                        .cases
+                        .iter()
+                        .zip(&new.cases)
+                        .all(|(old, new)| old.name == new.name && self.same_opt(&old.ty, &new.ty))
+            }
+            (TypeDefKind::Enum(old), TypeDefKind::Enum(new)) => old
+                .cases
+                .iter()
+                .map(|case| &case.name)
+                .eq(new.cases.iter().map(|case| &case.name)),
+            (TypeDefKind::Option(old), TypeDefKind::Option(new))
+            | (TypeDefKind::List(old), TypeDefKind::List(new)) => self.same_type(old, new),
+            (TypeDefKind::Result(old), TypeDefKind::Result(new)) => {
+                self.same_opt(&old.ok, &new.ok) && self.same_opt(&old.err, &new.err)
+            }
+            (TypeDefKind::Map(old_key, old), TypeDefKind::Map(new_key, new)) => {
+                self.same_type(old_key, new_key) && self.same_type(old, new)
+            }
+            (
+                TypeDefKind::FixedSizeList(old, old_len),
+                TypeDefKind::FixedSizeList(new, new_len),
+            ) => old_len == new_len && self.same_type(old, new),
+            (TypeDefKind::Future(old), TypeDefKind::Future(new))
+            | (TypeDefKind::Stream(old), TypeDefKind::Stream(new)) => self.same_opt(old, new),
+            _ => false,
+        }
+    }
+}
+
+/// Follow the type aliases, e.g. the types used from other interfaces.
+fn dealias(resolve: &Resolve, mut ty: Type) -> Type {
+    while let Type::Id(id) = ty
+        && let TypeDefKind::Type(inner) = resolve.types[id].kind
+    {
+        ty = inner;
+    }
+    ty
+}
+/// The interface of a named type, without the package version.
+fn owner(resolve: &Resolve, ty: &TypeDef) -> Option<String> {
+    let TypeOwner::Interface(id) = ty.owner else {
+        return None;
+    };
+    let iface = &resolve.interfaces[id];
+    let pkg = &resolve.packages[iface.package?].name;
+    Some(format!(
+        "{}:{}/{}",
+        pkg.namespace,
+        pkg.name,
+        iface.name.as_deref().unwrap_or_default()
+    ))
+}
@@ -1,4 +1,5 @@
 use crate::Mode;
+use crate::adapt::Adapter;
 use crate::instrument::InstrumentArgs;
 use crate::util::*;
 use anyhow::Result;
@@ -14,6 +15,11 @@ pub struct Opt<'a> {
     imports: LinkInfo,
     exports: LinkInfo,
     main: LinkInfo,
+    /// Imports of the main component that are not virtualized. In replay mode, they are live
+    /// imports, which still go through the imports component to be checked against the trace.
+    host_imports: BTreeSet<String>,
+    /// The host versions of the interfaces in adapt mode
+    adapter: Option<&'a Adapter>,
 }
 #[derive(Default)]
 struct LinkInfo {
@@ -29,7 +35,7 @@ enum LinkType {
 }
 
 impl<'a> Opt<'a> {
-    pub fn new(args: &'a InstrumentArgs) -> Self {
+    pub fn new(args: &'a InstrumentArgs, adapter: Option<&'a Adapter>) -> Self {
         let mode = args.mode.clone();
         Self {
             args,
@@ -37,9 +43,87 @@ impl<'a> Opt<'a> {
             imports: LinkInfo::default(),
             exports: LinkInfo::default(),
             main: LinkInfo::default(),
+            host_imports: BTreeSet::new(),
+            adapter,
         }
     }
-    fn generate_main_wit(&mut self, resolve: &Resolve, id: WorldId, files: &mut Files) {
+    pub fn host_imports(&self) -> &BTreeSet<String> {
+        &self.host_imports
+    }
+    /// The interface provided by the host, which is a different version in adapt mode.
+    fn host_name(&self, name: &str) -> String {
+        match self.adapter {
+            Some(adapter) => adapter.host_name(name),
+            None => name.to_string(),
+        }
+    }
+    fn is_virtualized(&self, name: &str) -> bool {
+        (self.args.include_imports.is_empty()
+            || self
+                .args
+                .include_imports
+                .iter()
+                .any(|p| glob_match(p, name)))
+            && !self
+                .args
+                .exclude_imports
+                .iter()
+                .any(|p| glob_match(p, name))
+    }
+    /// The imported interfaces that go straight to the host. The interfaces used by the
+    /// virtualized interfaces and the exports are always virtualized, so that their types are
+    /// available to the generated code. The interfaces that use a virtualized interface are
+    /// virtualized too, otherwise the host and the proxy would provide different resource types.
+    fn host_interfaces(&self, resolve: &Resolve, id: WorldId) -> BTreeSet<InterfaceId> {
+        let world = &resolve.worlds[id];
+        let mut host = BTreeSet::new();
+        let mut stack = Vec::new();
+        for (name, import) in &world.imports {
+            if let WorldItem::Interface { id, .. } = import {
+                let name = resolve.name_world_key(name);
+                if name.starts_with("proxy:util/") || self.is_virtualized(&name) {
+                    stack.push(*id);
+                } else {
+                    host.insert(*id);
+                }
+            }
+        }
+        for export in world.exports.values() {
+            if let WorldItem::Interface { id, .. } = export {
+                stack.push(*id);
+            }
+        }
+        loop {
+            while let Some(id) = stack.pop() {
+                for dep in resolve.interface_direct_deps(id) {
+                    if host.remove(&dep) {
+                        stack.push(dep);
+                    }
+                }
+            }
+            stack = host
+                .iter()
+                .copied()
+                .filter(|id| {
+                    resolve
+                        .interface_direct_deps(*id)
+                        .any(|dep| !host.contains(&dep))
+                })
+                .collect();
+            if stack.is_empty() {
+                return host;
+            }
+            for id in &stack {
+                host.remove(id);
+            }
+        }
+    }
+    fn generate_main_wit(
+        &mut self,
+        resolve: &Resolve,
+        id: WorldId,
+        files: &mut Files,
+    ) -> Result<()> {
         let mut out = Source::default();
         let world = &resolve.worlds[id];
         let recorder = "proxy:recorder/";
@@ -51,19 +135,49 @@ impl<'a> Opt<'a> {
                     "import {recorder}{}@0.1.0;\n",
                     ident(self.mode.to_str())
                 ));
+                // the injected faults are generated from random numbers
+                if self.args.faults.is_enabled() {
+                    out.push_str("import proxy:util/debug;\n");
+                }
             }
             Mode::Fuzz => {
                 out.push_str(&format!("import proxy:util/debug;\n"));
+                // fuzz runs are recorded as replayable traces
+                out.push_str(&format!("import {recorder}{}@0.1.0;\n", ident("record")));
             }
             Mode::Dialog => {
                 out.push_str(&format!("import proxy:util/dialog;\n"));
             }
+            // the mock rules are compiled into the imports component
+            Mode::Mock | Mode::Provider => (),
+            Mode::Log | Mode::Profile => out.push_str("import proxy:util/debug;\n"),
+            Mode::Policy | Mode::Adapt => (),
         };
         out.push_str("export proxy:conversion/conversion;\n");
+        let world_types = print_world_types(resolve, id)?;
+        out.push_str(&world_types);
+        let host_interfaces = self.host_interfaces(resolve, id);
         for (name, import) in &world.imports {
             match import {
-                WorldItem::Interface { .. } => {
+                WorldItem::Interface { id, .. } => {
                     let name = resolve.name_world_key(name);
+                    if host_interfaces.contains(id) {
+                        if matches!(self.mode, Mode::Replay) {
+                            // the live calls are checked against the trace
+                            self.main.imports.insert(name.clone(), LinkType::Imports);
+                            out.push_str(&format!("import {name};\n"));
+                            out.push_str(&format!("export wrapped-{name};\n"));
+                        } else {
+                            self.main.imports.insert(name.clone(), LinkType::Host);
+                        }
+                        self.host_imports.insert(name);
+                        continue;
+                    }
+                    if !name.starts_with("proxy:util/") && !self.is_virtualized(&name) {
+                        eprintln!(
+                            "Virtualizing {name}, because it is used by a virtualized interface"
+                        );
+                    }
                     // Don't virtualize util imports
                     if name.starts_with("proxy:util/") {
                         self.main.imports.insert(name.clone(), LinkType::Debug);
@@ -71,37 +185,66 @@ impl<'a> Opt<'a> {
                         continue;
                     }
                     self.main.imports.insert(name.clone(), LinkType::Imports);
-                    match self.mode {
-                        Mode::Record => {
-                            out.push_str(&format!("import {name};\n"));
-                            out.push_str(&format!("export wrapped-{name};\n"));
-                        }
-                        Mode::Replay | Mode::Fuzz | Mode::Dialog => {
-                            out.push_str(&format!("export {name};\n"))
+                    if self.mode.is_instrument() {
+                        out.push_str(&format!("import {};\n", self.host_name(&name)));
+                        out.push_str(&format!("export wrapped-{name};\n"));
+                    } else {
+                        out.push_str(&format!("export {name};\n"));
+                    }
+                }
+                WorldItem::Function(func) => {
+                    let name = resolve.name_world_key(name);
+                    let sig = print_world_func(resolve, func)?;
+                    if !self.is_virtualized(&name) {
+                        if matches!(self.mode, Mode::Replay) {
+                            self.main.imports.insert(name.clone(), LinkType::Imports);
+                            out.push_str(&format!("import {}: {sig};\n", ident(&name)));
+                            out.push_str(&format!("export wrapped-{name}: {sig};\n"));
+                        } else {
+                            self.main.imports.insert(name.clone(), LinkType::Host);
                         }
+                        self.host_imports.insert(name);
+                        continue;
+                    }
+                    self.main.imports.insert(name.clone(), LinkType::Imports);
+                    if self.mode.is_instrument() {
+                        out.push_str(&format!("import {}: {sig};\n", ident(&name)));
+                        out.push_str(&format!("export wrapped-{name}: {sig};\n"));
+                    } else {
+                        out.push_str(&format!("export {}: {sig};\n", ident(&name)));
                     }
                 }
-                _ => todo!(),
+                // World-level types are emitted by print_world_types
+                WorldItem::Type(_) => (),
             }
         }
         out.push_str("}\n");
         out.push_str("world tmp-exports {\n");
+        out.push_str(&world_types);
         for (name, export) in &world.exports {
             match export {
                 WorldItem::Interface { .. } => {
                     let name = resolve.name_world_key(name);
                     self.main.exports.insert(name.clone());
-                    match self.mode {
-                        Mode::Record => {
-                            out.push_str(&format!("import wrapped-{name};\n"));
-                            out.push_str(&format!("export {name};\n"));
-                        }
-                        Mode::Replay | Mode::Fuzz | Mode::Dialog => {
-                            out.push_str(&format!("import {name};\n"));
-                        }
+                    if self.mode.is_instrument() {
+                        out.push_str(&format!("import wrapped-{name};\n"));
+                        out.push_str(&format!("export {};\n", self.host_name(&name)));
+                    } else {
+                        out.push_str(&format!("import {name};\n"));
                     }
                 }
-                _ => todo!(),
+                WorldItem::Function(func) => {
+                    let name = resolve.name_world_key(name);
+                    let sig = print_world_func(resolve, func)?;
+                    self.main.exports.insert(name.clone());
+                    if self.mode.is_instrument() {
+                        out.push_str(&format!("import wrapped-{name}: {sig};\n"));
+                        out.push_str(&format!("export {}: {sig};\n", ident(&name)));
+                    } else {
+                        out.push_str(&format!("import {}: {sig};\n", ident(&name)));
+                    }
+                }
+                WorldItem::Type(_) => unreachable!(),
             }
         }
         if matches!(self.mode, Mode::Replay | Mode::Fuzz | Mode::Dialog) {
@@ -109,8 +252,14 @@ impl<'a> Opt<'a> {
         }
         out.push_str("}\n");
         files.push("component.wit", out.as_bytes());
+        Ok(())
     }
-    pub fn generate_exports_world(&self, resolve: &Resolve, id: WorldId, files: &mut Files) {
+    pub fn generate_exports_world(
+        &self,
+        resolve: &Resolve,
+        id: WorldId,
+        files: &mut Files,
+    ) -> Result<()> {
         let mut out = Source::default();
         let world = &resolve.worlds[id];
         let recorder = "proxy:recorder/";
@@ -124,19 +273,32 @@ impl<'a> Opt<'a> {
             }
             Mode::Fuzz => {
                 out.push_str(&format!("import proxy:util/debug;\n"));
+                // fuzz runs are recorded as replayable traces
+                out.push_str(&format!("import {recorder}{}@0.1.0;\n", ident("record")));
             }
             Mode::Dialog => {
                 out.push_str(&format!("import proxy:util/dialog;\n"));
             }
+            Mode::Mock | Mode::Provider => {
+                unreachable!("the mock and provider modes have no exports component")
+            }
+            Mode::Log | Mode::Profile => out.push_str("import proxy:util/debug;\n"),
+            Mode::Policy | Mode::Adapt => (),
         };
         out.push_str("import proxy:conversion/conversion;\n");
+        out.push_str(&print_world_types(resolve, id)?);
         for (name, import) in &world.imports {
             match import {
                 WorldItem::Interface { .. } => {
                     let name = resolve.name_world_key(name);
                     out.push_str(&format!("import {name};\n"));
                 }
-                _ => todo!(),
+                WorldItem::Function(func) => {
+                    let name = resolve.name_world_key(name);
+                    let sig = print_world_func(resolve, func)?;
+                    out.push_str(&format!("import {}: {sig};\n", ident(&name)));
+                }
+                WorldItem::Type(_) => (),
             }
         }
         for (name, export) in &world.exports {
@@ -145,21 +307,29 @@ impl<'a> Opt<'a> {
                     let name = resolve.name_world_key(name);
                     out.push_str(&format!("export {name};\n"));
                 }
-                _ => todo!(),
+                WorldItem::Function(func) => {
+                    let name = resolve.name_world_key(name);
+                    let sig = print_world_func(resolve, func)?;
+                    out.push_str(&format!("export {}: {sig};\n", ident(&name)));
+                }
+                WorldItem::Type(_) => unreachable!(),
             }
         }
         out.push_str("}\n");
         files.push("component.wit", out.as_bytes());
+        Ok(())
     }
     pub fn generate_wac(
         &mut self,
         imports_wasm: &Path,
-        exports_wasm: &Path,
+        exports_wasm: Option<&Path>,
         out_dir: &Path,
     ) -> Result<()> {
         // Get WIT from wasm to account for unused imports being optimized away
         self.load_imports(imports_wasm)?;
-        self.load_exports(exports_wasm)?;
+        if let Some(exports_wasm) = exports_wasm {
+            self.load_exports(exports_wasm)?;
+        }
         let mut out = Source::default();
         out.push_str("package component:composed;\n");
         out.push_str("let debug = new import:debug { ... };\n");
@@ -191,23 +361,37 @@ impl<'a> Opt<'a> {
         }
         out.push_str("};\n");
         out.push_str("let main = new root:component {\n");
+        // the coverage import is provided by the host
+        has_host = self.args.coverage;
         for (name, link_type) in &self.main.imports {
             match link_type {
                 LinkType::Debug => {
                     out.push_str(&format!("\"{name}\": debug[\"{name}\"] ,\n"));
                 }
                 LinkType::Imports => {
-                    let prefix = if matches!(self.mode, Mode::Record) {
+                    let prefix = if self.mode.is_instrument() || self.host_imports.contains(name) {
                         "wrapped-"
                     } else {
                         ""
                     };
                     out.push_str(&format!("\"{name}\": imports[\"{prefix}{name}\"] ,\n"));
                 }
-                LinkType::Host | LinkType::Main | LinkType::Recorder => unreachable!(),
+                LinkType::Host => {
+                    has_host = true;
+                }
+                LinkType::Main | LinkType::Recorder => unreachable!(),
             }
         }
+        if has_host {
+            out.push_str("...,\n");
+        }
         out.push_str("};\n");
+        if exports_wasm.is_none() {
+            // the exports of the main component are not proxied
+            out.push_str("export main...;\n");
+            std::fs::write(out_dir.join("compose.wac"), out.as_bytes())?;
+            return Ok(());
+        }
         out.push_str("let final = new export:proxy {\n");
         has_host = false;
         for (name, link_type) in &self.exports.imports {
@@ -251,7 +435,7 @@ impl<'a> Opt<'a> {
         id: WorldId,
         files: &mut Files,
     ) -> Result<()> {
-        self.generate_main_wit(resolve, id, files);
+        self.generate_main_wit(resolve, id, files)?;
         files.push(
             "deps/recorder.wit",
             include_str!("../assets/recorder.wit").as_bytes(),
@@ -267,10 +451,25 @@ impl<'a> Opt<'a> {
         let mut resolve = Resolve::default();
         let (main_id, _files) = resolve.push_dir(dir)?;
         let has_version = package_with_version(&resolve);
+        // Resources of the exported interfaces are passed to the main component directly
+        let world = resolve.select_world(&[main_id], None)?;
+        let exported: BTreeSet<_> = resolve.worlds[world]
+            .exports
+            .values()
+            .filter_map(|item| match item {
+                WorldItem::Interface { id, .. } => Some(*id),
+                _ => None,
+            })
+            .collect();
+        // Resources of the host imports are not converted
+        let host_interfaces = self.host_interfaces(&resolve, world);
         // Generate conversion interface. Not updating resolve to avoid deep cloning the packages.
         let mut resources = BTreeMap::new();
-        for (_, iface) in resolve.interfaces.iter().filter(|(_, iface)| {
-            iface.package.is_some_and(|id| id != main_id) && iface.name.is_some()
+        for (_, iface) in resolve.interfaces.iter().filter(|(id, iface)| {
+            iface.package.is_some_and(|id| id != main_id)
+                && iface.name.is_some()
+                && !exported.contains(id)
+                && !host_interfaces.contains(id)
         }) {
             let pkg_id = iface.package.unwrap();
             let pkg_name = &resolve.packages[pkg_id].name;
@@ -301,34 +500,39 @@ impl<'a> Opt<'a> {
         for (resource, iface, bindgen_name) in resources.into_values() {
             use heck::ToKebabCase;
             let func_name = format!("{bindgen_name}-{resource}").to_kebab_case();
-            match self.mode {
-                Mode::Record => {
-                    out.push_str(&format!(
-                        "\nuse {iface}.{{{resource} as host-{func_name}}};\n",
-                    ));
-                    out.push_str(&format!(
-                        "use wrapped-{iface}.{{{resource} as wrapped-{func_name}}};\n",
-                    ));
-                    out.push_str(&format!(
-                        "get-wrapped-{func_name}: func(x: host-{func_name}) -> wrapped-{func_name};\n",
-                    ));
-                    out.push_str(&format!(
-                        "get-host-{func_name}: func(x: wrapped-{func_name}) -> host-{func_name};\n",
-                    ));
-                }
-                Mode::Replay | Mode::Fuzz | Mode::Dialog => {
-                    // Add a magic separator so that codegen::generate_conversion_func can recover the resource name
-                    let magic_name = format!("{bindgen_name}-magic42-{resource}").to_kebab_case();
-                    out.push_str(&format!("\nuse {iface}.{{{resource} as {func_name}}};\n"));
-                    out.push_str(&format!(
-                        "get-mock-{magic_name}: func(handle: u32) -> {func_name};\n"
-                    ));
-                }
+            if self.mode.is_instrument() {
+                out.push_str(&format!(
+                    "\nuse {}.{{{resource} as host-{func_name}}};\n",
+                    self.host_name(&iface)
+                ));
+                out.push_str(&format!(
+                    "use wrapped-{iface}.{{{resource} as wrapped-{func_name}}};\n",
+                ));
+                out.push_str(&format!(
+                    "get-wrapped-{func_name}: func(x: host-{func_name}) -> wrapped-{func_name};\n",
+                ));
+                out.push_str(&format!(
+                    "get-host-{func_name}: func(x: wrapped-{func_name}) -> host-{func_name};\n",
+                ));
+            } else {
+                // Add a magic separator so that codegen::generate_conversion_func can recover the resource name
+                let magic_name = format!("{bindgen_name}-magic42-{resource}").to_kebab_case();
+                out.push_str(&format!("\nuse {iface}.{{{resource} as {func_name}}};\n"));
+                out.push_str(&format!(
+                    "get-mock-{magic_name}: func(handle: u32) -> {func_name};\n"
+                ));
             }
         }
+        if matches!(self.mode, Mode::Policy) {
+            // the exports component resets the quotas at the start of each export call
+            out.push_str("\npolicy-reset: func();\n");
+        }
         out.push_str("}\n");
         std::fs::write(dir.join("deps").join("conversion.wit"), out.as_bytes())?;
-        if matches!(self.mode, Mode::Record) {
+        // in replay mode, only the live imports are wrapped
+        if self.mode.is_instrument()
+            || (matches!(self.mode, Mode::Replay) && !host_interfaces.is_empty())
+        {
             // rename package name and generate wrapped wit
             resolve.package_names = resolve
                 .package_names
@@ -369,7 +573,11 @@ impl<'a> Opt<'a> {
                     };
                     self.imports.imports.insert(name.clone(), link_type);
                 }
-                _ => todo!(),
+                WorldItem::Function(_) => {
+                    let name = resolve.name_world_key(name);
+                    self.imports.imports.insert(name, LinkType::Host);
+                }
+                WorldItem::Type(_) => (),
             }
         }
         Ok(())
@@ -379,14 +587,14 @@ impl<'a> Opt<'a> {
         let world = &resolve.worlds[id];
         for (name, import) in &world.imports {
             match import {
-                WorldItem::Interface { .. } => {
+                WorldItem::Interface { .. } | WorldItem::Function(_) => {
                     let name = resolve.name_world_key(name);
                     let link_type = match name.as_str() {
                         "proxy:util/debug" => LinkType::Debug,
                         "proxy:conversion/conversion" => LinkType::Imports,
                         "proxy:util/dialog" => LinkType::Host,
                         name if name.starts_with("proxy:recorder/") => LinkType::Recorder,
-                        name if matches!(self.mode, Mode::Record) => {
+                        name if self.mode.is_instrument() => {
                             if let Some(stripped) = name.strip_prefix("wrapped-") {
                                 if self.main.exports.contains(stripped) {
                                     LinkType::Main
@@ -398,17 +606,65 @@ impl<'a> Opt<'a> {
                             }
                         }
                         name if self.main.exports.contains(name) => LinkType::Main,
+                        name if self.host_imports.contains(name) => LinkType::Host,
                         _ => LinkType::Imports,
                     };
                     self.exports.imports.insert(name.to_string(), link_type);
                 }
-                _ => todo!(),
+                WorldItem::Type(_) => (),
             }
         }
         Ok(())
     }
 }
 
+/// Print the signature of a world-level function, e.g. `func(x: u32) -> string`.
+fn print_world_func(resolve: &Resolve, func: &Function) -> Result<String> {
+    if func.kind.resource().is_some() {
+        anyhow::bail!("world-level resource `{}` is not supported", func.name);
+    }
+    let mut params = Vec::new();
+    for (name, ty) in &func.params {
+        params.push(format!(
+            "{}: {}",
+            ident(name),
+            print_type_name(resolve, ty)?
+        ));
+    }
+    let mut sig = format!("func({})", params.join(", "));
+    if let Some(ty) = &func.result {
+        sig.push_str(&format!(" -> {}", print_type_name(resolve, ty)?));
+    }
+    Ok(sig)
+}
+fn print_type_name(resolve: &Resolve, ty: &Type) -> Result<String> {
+    let mut printer = WitPrinter::default();
+    printer.print_type_name(resolve, ty)?;
+    Ok(printer.output.to_string())
+}
+/// Print the types defined or used at the world level, so that world-level functions
+/// can refer to them from the generated worlds.
+fn print_world_types(resolve: &Resolve, id: WorldId) -> Result<String> {
+    let world = &resolve.worlds[id];
+    let mut types = Vec::new();
+    for (name, item) in &world.imports {
+        if let (WorldKey::Name(name), WorldItem::Type(ty)) = (name, item) {
+            if matches!(resolve.types[*ty].kind, TypeDefKind::Resource) {
+                anyhow::bail!("world-level resource `{name}` is not supported");
+            }
+            types.push((name.as_str(), *ty));
+        }
+    }
+    let mut printer = WitPrinter::default();
+    printer.print_types(
+        resolve,
+        TypeOwner::World(id),
+        types.into_iter(),
+        &Default::default(),
+    )?;
+    Ok(printer.output.to_string())
+}
+
 fn load_wasm(file: &Path) -> Result<(Resolve, WorldId)> {
     use wit_parser::decoding::{DecodedWasm, decode};
     let bytes = std::fs::read(file)?;
@@ -0,0 +1,106 @@
+use crate::util::glob_match;
+use anyhow::Result;
+use proc_macro2::TokenStream;
+use quote::quote;
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::path::Path;
+
+/// The assertion rules of the export results in replay mode, loaded from a TOML or JSON file, e.g.,
+/// ```toml
+/// [[rule]]
+/// method = "*handler.handle"
+/// ignore = ["ret.ok.timestamp", "ret.ok.items[*].id"]
+/// float-tolerance = 1e-6
+/// regex = { "ret.ok.request-id" = "[0-9a-f]{32}" }
+///
+/// [[rule]]
+/// method = "wasi:cli/run.run"
+/// variant-only = true
+/// ```
+/// The paths are the ones reported by the replay divergences: `ret`, followed by `.field`, `[index]`
+/// and the case names of variants, e.g. `.some` and `.ok`. `*` matches any characters.
+#[derive(Deserialize, Default)]
+#[serde(deny_unknown_fields)]
+pub struct Rules {
+    #[serde(default)]
+    rule: Vec<Rule>,
+}
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+pub struct Rule {
+    /// The display name of the export function. `*` matches any characters.
+    /// The `[method]` and `[constructor]` prefixes are optional.
+    method: String,
+    /// The paths that are not compared.
+    #[serde(default)]
+    ignore: Vec<String>,
+    /// The absolute difference allowed between floats.
+    #[serde(default)]
+    float_tolerance: f64,
+    /// The regex that the whole string at the path has to match, instead of the recorded string.
+    #[serde(default)]
+    regex: BTreeMap<String, String>,
+    /// Only compare the cases of variants, enums, options and results, not their payloads.
+    #[serde(default)]
+    variant_only: bool,
+}
+impl Rules {
+    pub fn load(path: Option<&Path>) -> Result<Self> {
+        let Some(path) = path else {
+            return Ok(Rules::default());
+        };
+        let text = std::fs::read_to_string(path)?;
+        let rules: Rules = match path.extension().and_then(|ext| ext.to_str()) {
+            Some("json") => serde_json::from_str(&text)?,
+            _ => toml::from_str(&text)?,
+        };
+        for rule in &rules.rule {
+            if rule.float_tolerance < 0.0 || rule.float_tolerance.is_nan() {
+                anyhow::bail!(
+                    "assertion rule for {} has an invalid float-tolerance",
+                    rule.method
+                );
+            }
+            for (path, regex) in &rule.regex {
+                if let Err(err) = regex_lite::Regex::new(regex) {
+                    anyhow::bail!(
+                        "invalid regex for {path} in the assertion rule for {}: {err}",
+                        rule.method
+                    );
+                }
+            }
+        }
+        Ok(rules)
+    }
+    pub fn is_empty(&self) -> bool {
+        self.rule.is_empty()
+    }
+    /// The first rule matching the method, with or without the `[method]` or `[constructor]` prefix.
+    fn find(&self, method: &str) -> Option<&Rule> {
+        let name = method.split_once(']').map_or(method, |(_, name)| name);
+        self.rule
+            .iter()
+            .find(|rule| glob_match(&rule.method, method) || glob_match(&rule.method, name))
+    }
+    /// The `AssertRule` of the export function, see `traits::assert::AssertTrait`.
+    pub fn generate(&self, method: &str) -> Option<TokenStream> {
+        let rule = self.find(method)?;
+        let ignore = &rule.ignore;
+        let tolerance = rule.float_tolerance;
+        let (paths, regex): (Vec<_>, Vec<_>) = rule
+            .regex
+            .iter()
+            .map(|(path, regex)| (path, format!("^(?:{regex})$")))
+            .unzip();
+        let variant_only = rule.variant_only;
+        Some(quote! {
+            AssertRule {
+                ignore: &[#(#ignore),*],
+                float_tolerance: #tolerance,
+                regex: &[#((#paths, #regex)),*],
+                variant_only: #variant_only,
+            }
+        })
+    }
+}
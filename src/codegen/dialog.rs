@@ -1,4 +1,4 @@
-use super::State;
+use super::{State, stream};
 use crate::util::{
     FullTypePath, ResourceFuncKind, extract_arg_info, get_owned_type, get_return_type, make_path,
     wit_func_name,
@@ -17,10 +17,14 @@ impl State {
         let is_export = module_path.join("::") == "exports::proxy::recorder::start_replay";
         if !is_export {
             let (kind, args) = extract_arg_info(sig);
-            let arg_names = args.iter().map(|arg| &arg.ident);
+            let arg_values = args.iter().map(|arg| {
+                let ident = &arg.ident;
+                stream::to_value(&arg.ty, quote! { &#ident })
+            });
             let display_name = wit_func_name(module_path, resource, func_name, &kind);
             let ret_ty = get_return_type(&sig.output);
             if let Some(ty) = ret_ty {
+                let ret_value = stream::to_value(&ty, quote! { &ret });
                 let init_vec = if matches!(kind, Some(ResourceFuncKind::Method)) {
                     quote! { vec![wasm_wave::to_string(&ToValue::to_value(&self)).unwrap()] }
                 } else {
@@ -30,12 +34,12 @@ impl State {
                     #sig {
                         let mut __params: Vec<String> = #init_vec;
                         #(
-                            __params.push(wasm_wave::to_string(&ToValue::to_value(&#arg_names)).unwrap());
+                            __params.push(wasm_wave::to_string(&#arg_values).unwrap());
                         )*
                         proxy::util::dialog::print(0, &format!("import: {}({})", #display_name, __params.join(", ")));
                         proxy::util::dialog::print(0, &format!("return type: {}", <#ty as WitName>::name()));
-                        let ret = Dialog::read_value(0);
-                        proxy::util::dialog::print(0, &format!("ret: {}", wasm_wave::to_string(&ToValue::to_value(&ret)).unwrap()));
+                        let ret: #ty = Dialog::read_value(0);
+                        proxy::util::dialog::print(0, &format!("ret: {}", wasm_wave::to_string(&#ret_value).unwrap()));
                         ret
                     }
                 }
@@ -50,7 +54,7 @@ impl State {
             let arms: Vec<_> = self
                 .funcs
                 .iter()
-                .filter(|(path, _)| path[0] != "exports" && path[0] != "proxy")
+                .filter(|(path, _)| path.first().is_none_or(|p| p != "exports" && p != "proxy"))
                 .flat_map(|(path, resources)| {
                     resources.iter().flat_map(move |(resource, sigs)| {
                         sigs.iter().filter_map(move |sig| {
@@ -59,8 +63,7 @@ impl State {
                                 return None;
                             }
                             let arg_name: Vec<_> = args.iter().map(|arg| &arg.ident).collect();
-                            let call_param = args.iter().map(|arg| arg.call_param());
-                            let ty = args.iter().map(|arg| {
+                            let ty: Vec<_> = args.iter().map(|arg| {
                                 let mut ty = arg.ty.clone();
                                 FullTypePath { module_path: path }.visit_type_mut(&mut ty);
                                 if let Some(owned) = get_owned_type(&ty) {
@@ -68,13 +71,15 @@ impl State {
                                 } else {
                                     ty
                                 }
-                            });
+                            }).collect();
+                            let arg_value = ty.iter().zip(&arg_name).map(|(ty, arg_name)| stream::to_value(ty, quote! { &#arg_name }));
                             let func_name = if let Some(resource) = resource {
                                 format!("{}::{}", resource, sig.ident)
                             } else {
                                 sig.ident.to_string()
                             };
                             let func = make_path(path, &func_name);
+                            let call = self.call_export(sig, &func, args.iter().map(|arg| arg.call_param()));
                             let display_name = wit_func_name(path, resource, &sig.ident, &kind);
                             Some((quote! {
                                 {
@@ -83,10 +88,10 @@ impl State {
                                     #(
                                         proxy::util::dialog::print(0, &format!("provide argument for {}: {}", stringify!(#arg_name), <#ty as WitName>::name()));
                                         let #arg_name: #ty = Dialog::read_value(0);
-                                        __params.push(wasm_wave::to_string(&ToValue::to_value(&#arg_name)).unwrap());
+                                        __params.push(wasm_wave::to_string(&#arg_value).unwrap());
                                     )*
                                     proxy::util::dialog::print(0, &format!("export: {}({})", #display_name, __params.join(", ")));
-                                    let _ = #func(#(#call_param),*);
+                                    let _ = #call;
                                 }
                             }, display_name))
                         })
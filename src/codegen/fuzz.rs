@@ -1,10 +1,67 @@
-use super::State;
+use super::{FuzzArgs, State, stream};
 use crate::util::{
-    FullTypePath, ResourceFuncKind, extract_arg_info, get_owned_type, get_return_type, make_path,
-    wit_func_name,
+    FullTypePath, ResourceFuncKind, extract_arg_info, get_owned_type, get_return_type, glob_match,
+    make_path, wit_func_name, wit_resource_drop_name,
 };
+use anyhow::Result;
+use proc_macro2::Literal;
 use quote::quote;
+use std::collections::{BTreeMap, BTreeSet};
 use syn::{Signature, parse_quote, visit_mut::VisitMut};
+use trace::{FuncCall, Logger};
+
+/// Values recorded in the seed traces, by the display name of the function. The values are
+/// embedded in the generated fuzzer, so they are de-duplicated and capped by `FuzzArgs`.
+#[derive(Default)]
+pub struct Seeds {
+    pub export_args: BTreeMap<String, BTreeSet<Vec<String>>>,
+    pub import_rets: BTreeMap<String, BTreeSet<String>>,
+}
+impl Seeds {
+    pub fn load(fuzz: &FuzzArgs) -> Result<Self> {
+        let mut seeds = Seeds::default();
+        let args_len = |args: &Vec<String>| args.iter().map(String::len).sum::<usize>();
+        for path in &fuzz.seed_traces {
+            let mut logger = Logger::new();
+            logger
+                .load(&std::fs::read(path)?)
+                .map_err(|err| anyhow::anyhow!("{}: {err}", path.display()))?;
+            for call in logger.0 {
+                match call {
+                    FuncCall::ExportArgs { method, args }
+                        if args_len(&args) <= fuzz.max_seed_len =>
+                    {
+                        seeds.export_args.entry(method).or_default().insert(args);
+                    }
+                    FuncCall::ImportRet {
+                        method: Some(method),
+                        ret: Some(ret),
+                    } if ret.len() <= fuzz.max_seed_len => {
+                        seeds.import_rets.entry(method).or_default().insert(ret);
+                    }
+                    _ => (),
+                }
+            }
+        }
+        for values in seeds.export_args.values_mut() {
+            *values = shortest(std::mem::take(values), fuzz.max_seeds, args_len);
+        }
+        for values in seeds.import_rets.values_mut() {
+            *values = shortest(std::mem::take(values), fuzz.max_seeds, String::len);
+        }
+        Ok(seeds)
+    }
+    pub fn is_empty(&self) -> bool {
+        self.export_args.is_empty() && self.import_rets.is_empty()
+    }
+}
+
+/// The `max` shortest values.
+fn shortest<T: Ord>(values: BTreeSet<T>, max: usize, len: impl Fn(&T) -> usize) -> BTreeSet<T> {
+    let mut values: Vec<_> = values.into_iter().collect();
+    values.sort_by_key(|value| len(value));
+    values.into_iter().take(max).collect()
+}
 
 impl State {
     pub fn generate_fuzz_func(
@@ -17,35 +74,68 @@ impl State {
         let is_export = module_path.join("::") == "exports::proxy::recorder::start_replay";
         if !is_export {
             let (kind, args) = extract_arg_info(sig);
-            let arg_names = args.iter().map(|arg| &arg.ident);
+            let arg_values = args.iter().map(|arg| {
+                let ident = &arg.ident;
+                stream::to_value(&arg.ty, quote! { &#ident })
+            });
             let display_name = wit_func_name(module_path, resource, func_name, &kind);
+            let init_vec = if matches!(kind, Some(ResourceFuncKind::Method)) {
+                quote! { vec![wasm_wave::to_string(&ToValue::to_value(&self)).unwrap()] }
+            } else {
+                quote! { Vec::new() }
+            };
+            let is_mut = if args.is_empty() {
+                quote! {}
+            } else {
+                quote! { mut }
+            };
+            let record_args = quote! {
+                let #is_mut __params: Vec<String> = #init_vec;
+                #(
+                    __params.push(wasm_wave::to_string(&#arg_values).unwrap());
+                )*
+                proxy::recorder::record::record_args(Some(#display_name), &__params, false);
+            };
             let ret_ty = get_return_type(&sig.output);
-            if ret_ty.is_some() {
-                let init_vec = if matches!(kind, Some(ResourceFuncKind::Method)) {
-                    quote! { vec![wasm_wave::to_string(&ToValue::to_value(&self)).unwrap()] }
+            if let Some(ret_ty) = ret_ty {
+                let seeds = self
+                    .seeds
+                    .import_rets
+                    .get(&display_name)
+                    .filter(|_| !self.may_have_handle(&ret_ty));
+                let arbitrary_ret = if stream::has_async_handle(&ret_ty) {
+                    stream::arbitrary(&ret_ty)
+                } else if let Some(seeds) = seeds {
+                    // mutate one of the recorded returns
+                    let seeds = seeds.iter();
+                    quote! {{
+                        const SEEDS: &[&str] = &[#(#seeds),*];
+                        choose_seed(SEEDS, &mut u)
+                            .and_then(|wave| mutate_seed::<#ret_ty>(wave, &mut u))
+                            .unwrap_or_else(|| u.arbitrary().unwrap())
+                    }}
                 } else {
-                    quote! { Vec::new() }
+                    quote! { u.arbitrary().unwrap() }
                 };
+                let ret_value = stream::to_value(&ret_ty, quote! { &res });
                 parse_quote! {
                     #sig {
-                        let mut __params: Vec<String> = #init_vec;
-                        #(
-                            __params.push(wasm_wave::to_string(&ToValue::to_value(&#arg_names)).unwrap());
-                        )*
+                        #record_args
                         let mut __buf = __params.join(",");
-                        proxy::util::debug::print(&format!("import: {}({})", #display_name, __buf));
                         __buf += #display_name;
                         let mut u = Unstructured::new(&__buf.as_bytes());
-                        let res = u.arbitrary().unwrap();
-                        let res_str = wasm_wave::to_string(&ToValue::to_value(&res)).unwrap();
-                        proxy::util::debug::print(&format!("ret: {}", res_str));
+                        let res = #arbitrary_ret;
+                        let res_str = wasm_wave::to_string(&#ret_value).unwrap();
+                        proxy::recorder::record::record_ret(Some(#display_name), Some(&res_str), false);
                         res
                     }
                 }
             } else {
                 parse_quote! {
-                    #[allow(unused_variables)]
-                    #sig {}
+                    #sig {
+                        #record_args
+                        proxy::recorder::record::record_ret(Some(#display_name), None, false);
+                    }
                 }
             }
         } else {
@@ -53,17 +143,52 @@ impl State {
             let arms: Vec<_> = self
                 .funcs
                 .iter()
-                .filter(|(path, _)| path[0] != "exports" && path[0] != "proxy")
+                .filter(|(path, _)| path.first().is_none_or(|p| p != "exports" && p != "proxy"))
                 .flat_map(|(path, resources)| {
                     resources.iter().flat_map(move |(resource, sigs)| {
+                        let resource_ty: Option<syn::Type> = resource.as_ref().map(|resource| {
+                            let path = make_path(path, resource);
+                            parse_quote! { #path }
+                        });
+                        // resources of the exported interfaces are kept in a live pool, see FuzzTrait
+                        let pooled = resource.as_ref().is_some_and(|resource| {
+                            self.is_resource_type(resource_ty.as_ref().unwrap())
+                                && !self.has_mock_conversion(path, resource)
+                        });
+                        let drop_arm = pooled.then(|| {
+                            let resource_ty = resource_ty.as_ref().unwrap();
+                            let display_name = wit_resource_drop_name(path, resource.as_ref().unwrap());
+                            (display_name.clone(), quote! {
+                                {
+                                    let Ok(__self) = u.arbitrary::<#resource_ty>() else {
+                                        break 'call;
+                                    };
+                                    let __params = [wasm_wave::to_string(&ToValue::to_value(&__self)).unwrap()];
+                                    proxy::recorder::record::record_args(Some(#display_name), &__params, true);
+                                    drop(__self);
+                                    proxy::recorder::record::record_ret(Some(#display_name), None, true);
+                                }
+                            })
+                        });
                         sigs.iter().filter_map(move |sig| {
                             let (kind, args) = extract_arg_info(sig);
-                            if matches!(kind, Some(ResourceFuncKind::Method)) {
+                            let is_method = matches!(kind, Some(ResourceFuncKind::Method));
+                            if is_method && !pooled {
                                 return None;
                             }
+                            let self_arg = if is_method {
+                                let resource_ty = resource_ty.as_ref().unwrap();
+                                quote! {
+                                    let Ok(__self) = u.arbitrary::<&#resource_ty>() else {
+                                        break 'call;
+                                    };
+                                    __params.push(wasm_wave::to_string(&ToValue::to_value(&__self)).unwrap());
+                                }
+                            } else {
+                                quote! {}
+                            };
                             let arg_name: Vec<_> = args.iter().map(|arg| &arg.ident).collect();
-                            let call_param = args.iter().map(|arg| arg.call_param());
-                            let ty = args.iter().map(|arg| {
+                            let ty: Vec<_> = args.iter().map(|arg| {
                                 let mut ty = arg.ty.clone();
                                 FullTypePath { module_path: path }.visit_type_mut(&mut ty);
                                 if let Some(owned) = get_owned_type(&ty) {
@@ -71,40 +196,141 @@ impl State {
                                 } else {
                                     ty
                                 }
+                            }).collect();
+                            let display_name = wit_func_name(path, resource, &sig.ident, &kind);
+                            let seeds = self.seeds.export_args.get(&display_name).filter(|_| {
+                                ty.iter().any(|ty| !self.may_have_handle(ty))
                             });
+                            let choose_seed = if let Some(seeds) = seeds {
+                                let seeds = seeds.iter().map(|args| quote! { &[#(#args),*] });
+                                quote! {
+                                    const SEEDS: &[&[&str]] = &[#(#seeds),*];
+                                    let __seed = choose_seed(SEEDS, &mut u);
+                                }
+                            } else {
+                                quote! {}
+                            };
+                            let (arbitrary, arg_value): (Vec<_>, Vec<_>) = ty.iter().zip(&arg_name).enumerate().map(|(idx, (ty, arg_name))| {
+                                // the recorded args of a method start with the borrowed self
+                                let idx = idx + is_method as usize;
+                                // skip the call when the pool has no resource for the argument
+                                let arbitrary = if stream::has_async_handle(ty) {
+                                    let arbitrary = stream::arbitrary(ty);
+                                    quote! { let #arg_name: #ty = #arbitrary; }
+                                } else if seeds.is_some() && !self.may_have_handle(ty) {
+                                    quote! {
+                                        let Some(#arg_name) = __seed
+                                            .and_then(|seed| seed.get(#idx))
+                                            .and_then(|wave| mutate_seed::<#ty>(wave, &mut u))
+                                            .or_else(|| u.arbitrary::<#ty>().ok()) else {
+                                            break 'call;
+                                        };
+                                    }
+                                } else {
+                                    quote! {
+                                        let Ok(#arg_name) = u.arbitrary::<#ty>() else {
+                                            break 'call;
+                                        };
+                                    }
+                                };
+                                (arbitrary, stream::to_value(ty, quote! { &#arg_name }))
+                            }).unzip();
                             let func_name = if let Some(resource) = resource {
                                 format!("{}::{}", resource, sig.ident)
                             } else {
                                 sig.ident.to_string()
                             };
                             let func = make_path(path, &func_name);
-                            let display_name = wit_func_name(path, resource, &sig.ident, &kind);
-                            Some(quote! {
+                            let self_param: Option<syn::Expr> = is_method.then(|| parse_quote! { __self });
+                            let call = self.call_export(sig, &func, self_param.into_iter().chain(args.iter().map(|arg| arg.call_param())));
+                            let call_ret = if let Some(mut ret_ty) = get_return_type(&sig.output) {
+                                FullTypePath { module_path: path }.visit_type_mut(&mut ret_ty);
+                                if matches!(kind, Some(ResourceFuncKind::Constructor)) {
+                                    ret_ty = resource_ty.clone().unwrap();
+                                }
+                                let ret_value = stream::to_value(&ret_ty, quote! { &res });
+                                quote! {
+                                    let res = #call;
+                                    let ret_value = #ret_value;
+                                    let wave_res = wasm_wave::to_string(&ret_value).unwrap();
+                                    proxy::recorder::record::record_ret(Some(#display_name), Some(&wave_res), true);
+                                    // returned resources join the pool
+                                    if resource_pool_register(&ret_value) {
+                                        let _ = std::mem::ManuallyDrop::new(res);
+                                    }
+                                }
+                            } else {
+                                quote! {
+                                    let _ = #call;
+                                    proxy::recorder::record::record_ret(Some(#display_name), None, true);
+                                }
+                            };
+                            Some((display_name.clone(), quote! {
                                 {
                                     let mut __params: Vec<String> = Vec::new();
+                                    #choose_seed
+                                    #self_arg
                                     #(
-                                        let #arg_name: #ty = u.arbitrary().unwrap();
-                                        __params.push(wasm_wave::to_string(&ToValue::to_value(&#arg_name)).unwrap());
+                                        #arbitrary
+                                        __params.push(wasm_wave::to_string(&#arg_value).unwrap());
                                     )*
-                                    proxy::util::debug::print(&format!("export: {}({})", #display_name, __params.join(", ")));
-                                    let _ = #func(#(#call_param),*);
+                                    proxy::recorder::record::record_args(Some(#display_name), &__params, true);
+                                    #call_ret
                                 }
-                            })
-                        })
+                            }))
+                        }).chain(drop_arm)
                     })
                 })
                 .collect();
-            let func_len = arms.iter().len();
-            let idxs = 1..=func_len;
+            let config = &self.fuzz;
+            // each arm takes a range of the picked number as wide as its weight
+            let mut total = 0u32;
+            let (ranges, arms): (Vec<_>, Vec<_>) = arms
+                .into_iter()
+                .filter(|(name, _)| {
+                    (config.include.is_empty()
+                        || config.include.iter().any(|p| glob_match(p, name)))
+                        && !config.exclude.iter().any(|p| glob_match(p, name))
+                })
+                .filter_map(|(name, arm)| {
+                    let weight = config
+                        .weights
+                        .iter()
+                        .rev()
+                        .find(|(p, _)| glob_match(p, &name))
+                        .map_or(1, |(_, weight)| *weight);
+                    if weight == 0 {
+                        return None;
+                    }
+                    let lo = Literal::u32_unsuffixed(total);
+                    total += weight;
+                    let hi = Literal::u32_unsuffixed(total - 1);
+                    Some((quote! { #lo..=#hi }, arm))
+                })
+                .unzip();
+            if arms.is_empty() {
+                return parse_quote! { #sig {} };
+            }
+            let max = Literal::u32_unsuffixed(total - 1);
+            let seed = match config.seed {
+                Some(seed) => quote! { Some(#seed) },
+                None => quote! { None },
+            };
+            let buffer_size = config.buffer_size;
+            let iterations = config.iterations;
             parse_quote! {
+                #[allow(unused_labels)]
                 #sig {
-                    let __buf = proxy::util::debug::get_random();
+                    let __buf = proxy::util::debug::get_random(#seed, #buffer_size);
                     let mut u = Unstructured::new(&__buf);
-                    for _ in 0..10 {
-                        let idx = u.int_in_range(1..=#func_len).unwrap();
-                        match idx {
-                            #(#idxs => #arms)*
-                            _ => unreachable!(),
+                    for _ in 0..#iterations {
+                        let idx: u32 = u.int_in_range(0..=#max).unwrap();
+                        // a call is skipped by breaking out of the block, so that the clean up still runs
+                        'call: {
+                            match idx {
+                                #(#ranges => #arms)*
+                                _ => unreachable!(),
+                            }
                         }
                         // clean up borrowed resources from input args
                         SCOPED_ALLOC.with(|alloc| {
@@ -0,0 +1,179 @@
+use super::State;
+use crate::util::{ResourceFuncKind, extract_arg_info, get_return_type, glob_match, wit_func_name};
+use anyhow::Result;
+use heck::ToKebabCase;
+use quote::quote;
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use syn::{Signature, parse_quote};
+use trace::{FuncCall, Logger};
+
+/// Mock rules, loaded from a TOML or JSON file, e.g.,
+/// ```toml
+/// [[rule]]
+/// method = "docs:adder/add.add"
+/// args = ["1", "_"]
+/// rets = ["3", "4"]
+/// ```
+#[derive(Deserialize, Default)]
+#[serde(deny_unknown_fields)]
+pub struct Rules {
+    #[serde(default)]
+    rule: Vec<Rule>,
+}
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Rule {
+    /// The display name of the import function. `*` matches any characters.
+    method: String,
+    /// The arguments in WAVE. `_` matches any value. Without it, the rule matches any arguments.
+    args: Option<Vec<String>>,
+    /// The return value in WAVE.
+    ret: Option<String>,
+    /// The return values of the consecutive calls in WAVE. The last value is repeated.
+    rets: Option<Vec<String>>,
+    /// Return the default value of the return type, i.e., zeros, empty lists, `none` and the first cases.
+    #[serde(default)]
+    default: bool,
+}
+impl Rules {
+    /// Load the rules file, followed by the rules of the recorded import calls in the traces.
+    pub fn load(path: Option<&Path>, traces: &[PathBuf]) -> Result<Self> {
+        let mut rules = match path {
+            Some(path) => {
+                let text = std::fs::read_to_string(path)?;
+                match path.extension().and_then(|ext| ext.to_str()) {
+                    Some("json") => serde_json::from_str(&text)?,
+                    _ => toml::from_str(&text)?,
+                }
+            }
+            None => Rules::default(),
+        };
+        for path in traces {
+            rules.rule.extend(Self::from_trace(path)?);
+        }
+        for rule in &rules.rule {
+            let count = rule.ret.is_some() as u8 + rule.rets.is_some() as u8 + rule.default as u8;
+            if count != 1 {
+                anyhow::bail!(
+                    "mock rule for {} needs exactly one of `ret`, `rets` and `default`",
+                    rule.method
+                );
+            }
+            if rule.rets.as_ref().is_some_and(|rets| rets.is_empty()) {
+                anyhow::bail!("mock rule for {} has empty `rets`", rule.method);
+            }
+        }
+        Ok(rules)
+    }
+    /// One rule for each recorded method and arguments, which returns the recorded values in order.
+    fn from_trace(path: &Path) -> Result<Vec<Rule>> {
+        let mut logger = Logger::new();
+        logger
+            .load(&std::fs::read(path)?)
+            .map_err(|err| anyhow::anyhow!("{}: {err}", path.display()))?;
+        let mut rules: Vec<Rule> = Vec::new();
+        let mut index = BTreeMap::new();
+        let mut pending = Vec::new();
+        for call in logger.0 {
+            match call {
+                FuncCall::ImportArgs { method, args } => pending.push((method, args)),
+                FuncCall::ImportRet { ret, .. } => {
+                    let Some((Some(method), args)) = pending.pop() else {
+                        anyhow::bail!("unmatched import return in {}", path.display());
+                    };
+                    // functions without a return value don't need a rule
+                    let Some(ret) = ret else {
+                        continue;
+                    };
+                    let idx = *index
+                        .entry((method.clone(), args.clone()))
+                        .or_insert_with(|| {
+                            rules.push(Rule {
+                                method,
+                                args: Some(args),
+                                ret: None,
+                                rets: Some(Vec::new()),
+                                default: false,
+                            });
+                            rules.len() - 1
+                        });
+                    rules[idx].rets.as_mut().unwrap().push(ret);
+                }
+                _ => (),
+            }
+        }
+        Ok(rules)
+    }
+    fn matching(&self, method: &str) -> impl Iterator<Item = &Rule> {
+        self.rule
+            .iter()
+            .filter(move |rule| glob_match(&rule.method, method))
+    }
+}
+
+impl State {
+    pub fn generate_mock_func(
+        &self,
+        module_path: &[String],
+        sig: &Signature,
+        resource: &Option<String>,
+    ) -> syn::ImplItemFn {
+        let (kind, args) = extract_arg_info(sig);
+        let display_name = wit_func_name(module_path, resource, &sig.ident, &kind);
+        let rules: Vec<_> = self
+            .mocks
+            .matching(&display_name)
+            .map(|rule| {
+                let matchers = match &rule.args {
+                    Some(args) => quote! { Some(&[#(#args),*]) },
+                    None => quote! { None },
+                };
+                let rets: Vec<_> = match (&rule.ret, &rule.rets) {
+                    (Some(ret), _) => vec![ret.clone()],
+                    (_, Some(rets)) => rets.clone(),
+                    // the default value
+                    (None, None) => Vec::new(),
+                };
+                quote! { MockRule { args: #matchers, rets: &[#(#rets),*] } }
+            })
+            .collect();
+        let Some(ret_ty) = get_return_type(&sig.output) else {
+            return parse_quote! {
+                #[allow(unused_variables)]
+                #sig {}
+            };
+        };
+        let wit_name = resource.as_ref().map(|name| name.to_kebab_case());
+        // the calls without a rule panic in mock_import with their arguments
+        if rules.is_empty() && matches!(kind, Some(ResourceFuncKind::Constructor)) {
+            return parse_quote! {
+                #[allow(unused_variables)]
+                #sig {
+                    MockedResource { handle: next_mock_handle(), name: #wit_name.to_string() }
+                }
+            };
+        }
+        let (self_value, self_type) = if matches!(kind, Some(ResourceFuncKind::Method)) {
+            (
+                // Use ToValue::to_value to avoid the auto-deref from self.to_value()
+                quote! { wasm_wave::to_string(&ToValue::to_value(&self)).unwrap(), },
+                quote! { Type::handle(#wit_name), },
+            )
+        } else {
+            (quote! {}, quote! {})
+        };
+        let arg_names = args.iter().map(|arg| &arg.ident);
+        let arg_types = args.iter().map(|arg| &arg.ty);
+        parse_quote! {
+            #sig {
+                const RULES: &[MockRule] = &[#(#rules),*];
+                let args = vec![#self_value #( wasm_wave::to_string(&#arg_names.to_value()).unwrap() ),*];
+                let types = vec![#self_type #( <#arg_types as ValueTyped>::value_type() ),*];
+                let ret = mock_import(#display_name, RULES, &types, &args, &<#ret_ty as ValueTyped>::value_type());
+                ret.to_rust()
+            }
+        }
+    }
+}
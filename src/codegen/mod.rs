@@ -1,4 +1,6 @@
-use crate::util::{FullTypePath, get_resource_from_trait_name, get_return_type, make_path};
+use crate::util::{
+    FullTypePath, get_resource_from_trait_name, get_return_type, glob_match, make_path,
+};
 use anyhow::Result;
 use quote::quote;
 use std::collections::{BTreeMap, BTreeSet};
@@ -8,10 +10,14 @@ use syn::{
     visit_mut::VisitMut,
 };
 
+mod assert;
 mod dialog;
 mod fuzz;
+mod mock;
+mod policy;
 mod record;
 mod replay;
+mod stream;
 
 #[derive(clap::Parser)]
 pub struct GenerateArgs {
@@ -22,6 +28,128 @@ pub struct GenerateArgs {
     /// The path to the output file.
     #[arg(short, long, default_value("lib.rs"))]
     pub output_file: PathBuf,
+    #[command(flatten)]
+    pub fuzz: FuzzArgs,
+    #[command(flatten)]
+    pub faults: FaultArgs,
+    #[command(flatten)]
+    pub mock: MockArgs,
+    #[command(flatten)]
+    pub log: LogArgs,
+    #[command(flatten)]
+    pub profile: ProfileArgs,
+    #[command(flatten)]
+    pub policy: PolicyArgs,
+    #[command(flatten)]
+    pub assert: AssertArgs,
+    /// The header recorded in record mode, and checked in replay mode, set by `instrument`.
+    #[arg(skip)]
+    pub header: Option<trace::TraceHeader>,
+}
+#[derive(clap::Args, Clone)]
+pub struct FuzzArgs {
+    /// The seed of a fuzz run. Without it, the seed is read from the `PROXY_FUZZ_SEED` environment variable, or chosen randomly.
+    #[arg(long)]
+    pub seed: Option<u64>,
+    /// The number of export calls in a fuzz run.
+    #[arg(long, default_value_t = 10)]
+    pub iterations: u32,
+    /// The number of random bytes consumed by a fuzz run.
+    #[arg(long, default_value_t = 1024)]
+    pub buffer_size: u32,
+    /// Only fuzz the export functions matching the pattern, e.g. `*counter.incr`. `*` matches any characters.
+    #[arg(long = "fuzz-include")]
+    pub include: Vec<String>,
+    /// Do not fuzz the export functions matching the pattern.
+    #[arg(long = "fuzz-exclude")]
+    pub exclude: Vec<String>,
+    /// The weight of the export functions matching the pattern, as `<pattern>=<weight>`. The default weight is 1.
+    #[arg(long = "fuzz-weight", value_parser = parse_weight)]
+    pub weights: Vec<(String, u32)>,
+    /// A trace file recorded from a previous execution. The recorded export arguments and import returns are mutated as fuzz inputs.
+    #[arg(long = "seed-trace")]
+    pub seed_traces: Vec<PathBuf>,
+    /// The maximum number of seeds embedded for each function. The shortest recorded values are kept.
+    #[arg(long, default_value_t = 32)]
+    pub max_seeds: usize,
+    /// The recorded values longer than this many bytes are not embedded as seeds.
+    #[arg(long, default_value_t = 1024)]
+    pub max_seed_len: usize,
+}
+/// Fault injection in record mode. The recorded imports that return a `result` can return an arbitrary `err`
+/// instead of calling the host. The faults are recorded in the trace, so the run can be replayed.
+#[derive(clap::Args, Clone)]
+pub struct FaultArgs {
+    /// The probability of injecting a fault into each call that can fail.
+    #[arg(long, default_value_t = 0.0)]
+    pub fault_rate: f64,
+    /// The calls that get a fault, counting from 1 over the calls that can fail, e.g. `3,10`.
+    #[arg(long, value_delimiter = ',')]
+    pub fault_schedule: Vec<u64>,
+    /// Only inject faults into the import functions matching the pattern, e.g. `wasi:filesystem/*`.
+    #[arg(long)]
+    pub fault_include: Vec<String>,
+    /// The seed of the fault rate. Without it, the seed is read from the `PROXY_FAULT_SEED` environment variable, or chosen randomly.
+    #[arg(long)]
+    pub fault_seed: Option<u64>,
+}
+#[derive(clap::Args, Clone)]
+pub struct MockArgs {
+    /// The TOML or JSON file of mock rules in mock mode. Each rule maps an import function, and optionally its arguments, to the return values.
+    #[arg(long)]
+    pub rules: Option<PathBuf>,
+    /// A trace file recorded from the component. The recorded import calls are answered by their method and
+    /// arguments, after the rules given by `--rules`.
+    #[arg(long = "trace")]
+    pub traces: Vec<PathBuf>,
+}
+#[derive(clap::Args, Clone)]
+pub struct LogArgs {
+    /// Only log the functions matching the pattern, e.g. `wasi:http/*`. `*` matches any characters.
+    #[arg(long)]
+    pub log_include: Vec<String>,
+    /// Do not log the functions matching the pattern.
+    #[arg(long)]
+    pub log_exclude: Vec<String>,
+}
+impl LogArgs {
+    pub fn is_logged(&self, display_name: &str) -> bool {
+        (self.log_include.is_empty()
+            || self.log_include.iter().any(|p| glob_match(p, display_name)))
+            && !self.log_exclude.iter().any(|p| glob_match(p, display_name))
+    }
+}
+#[derive(clap::Args, Clone)]
+pub struct ProfileArgs {
+    /// Print the profile as JSON instead of a table.
+    #[arg(long)]
+    pub profile_json: bool,
+}
+#[derive(clap::Args, Clone)]
+pub struct PolicyArgs {
+    /// The TOML or JSON policy file in policy mode. Each rule allows or denies the matching import functions,
+    /// optionally restricting their path and authority arguments.
+    #[arg(long)]
+    pub policy: Option<PathBuf>,
+}
+#[derive(clap::Args, Clone)]
+pub struct AssertArgs {
+    /// The TOML or JSON file of assertion rules in replay mode. Each rule relaxes the comparison of the matching
+    /// export results with the trace, e.g. ignoring fields or comparing floats with a tolerance.
+    #[arg(long)]
+    pub assert_rules: Option<PathBuf>,
+}
+impl FaultArgs {
+    pub fn is_enabled(&self) -> bool {
+        self.fault_rate > 0.0 || !self.fault_schedule.is_empty()
+    }
+}
+fn parse_weight(s: &str) -> Result<(String, u32), String> {
+    let (pattern, weight) = s
+        .rsplit_once('=')
+        .ok_or_else(|| format!("expect <pattern>=<weight>, found {s}"))?;
+    let weight = weight.parse().map_err(|e| format!("{e}"))?;
+    Ok((pattern.to_string(), weight))
 }
 #[derive(clap::ValueEnum, clap::Parser, Clone)]
 pub enum GenerateMode {
@@ -38,10 +166,25 @@ pub enum GenerateMode {
     Fuzz,
     /// A virtualized component with no imports, with implementation for dialog.
     Dialog,
+    /// A virtualized component with no imports, which answers the import calls by the mock rules.
+    Mock,
+    /// An instrument component which logs all calls to stderr.
+    Log,
+    /// An instrument component which profiles all calls.
+    Profile,
+    /// An instrument component which checks the import calls against the policy given by `--policy`.
+    Policy,
 }
 impl GenerateMode {
     pub fn is_instrument(&self) -> bool {
-        matches!(self, GenerateMode::Instrument | GenerateMode::Record)
+        matches!(
+            self,
+            GenerateMode::Instrument
+                | GenerateMode::Record
+                | GenerateMode::Log
+                | GenerateMode::Profile
+                | GenerateMode::Policy
+        )
     }
 }
 
@@ -49,8 +192,22 @@ pub struct State {
     pub mode: GenerateMode,
     pub traits: BTreeMap<Vec<String>, Vec<ItemTrait>>,
     pub types: BTreeMap<Vec<String>, Vec<TypeInfo>>,
+    /// Type aliases by their full path, e.g. the types used from other interfaces.
+    pub type_aliases: BTreeMap<String, syn::Type>,
     pub funcs: BTreeMap<Vec<String>, BTreeMap<Option<String>, Vec<Signature>>>,
     pub module_paths: BTreeSet<Vec<String>>,
+    pub has_stream: bool,
+    pub has_future: bool,
+    pub fuzz: FuzzArgs,
+    pub seeds: fuzz::Seeds,
+    pub faults: FaultArgs,
+    /// The trace header in JSON
+    pub header: Option<String>,
+    pub mocks: mock::Rules,
+    pub log: LogArgs,
+    pub profile: ProfileArgs,
+    pub policy: policy::Policy,
+    pub asserts: assert::Rules,
     pub output: Vec<Item>,
 }
 pub enum TypeInfo {
@@ -73,12 +230,47 @@ impl GenerateArgs {
             mode: self.mode.clone(),
             traits: BTreeMap::new(),
             types: BTreeMap::new(),
+            type_aliases: BTreeMap::new(),
             funcs: BTreeMap::new(),
             module_paths: BTreeSet::new(),
+            has_stream: false,
+            has_future: false,
+            fuzz: self.fuzz.clone(),
+            seeds: fuzz::Seeds::load(&self.fuzz)?,
+            faults: self.faults.clone(),
+            header: self
+                .header
+                .as_ref()
+                .map(serde_json::to_string)
+                .transpose()?,
+            mocks: mock::Rules::load(self.mock.rules.as_deref(), &self.mock.traces)?,
+            log: self.log.clone(),
+            profile: self.profile.clone(),
+            policy: policy::Policy::load(self.policy.policy.as_deref())?,
+            asserts: assert::Rules::load(self.assert.assert_rules.as_deref())?,
             output: Vec::new(),
         };
         state.generate_preamble();
         state.find_all_items(&ast.items, vec![]);
+        if state.has_stream || state.has_future {
+            match state.mode {
+                GenerateMode::Mock => {
+                    anyhow::bail!("mock mode does not support streams and futures")
+                }
+                GenerateMode::Log => anyhow::bail!("log mode does not support streams and futures"),
+                GenerateMode::Profile => {
+                    anyhow::bail!("profile mode does not support streams and futures")
+                }
+                GenerateMode::Policy => {
+                    anyhow::bail!("policy mode does not support streams and futures")
+                }
+                GenerateMode::Replay if state.has_wrapped_imports() => anyhow::bail!(
+                    "replay mode does not support streams and futures with live imports"
+                ),
+                _ => (),
+            }
+            state.check_async_types()?;
+        }
         state.generate_stubs();
         let trait_generator = crate::traits::TraitGenerator::new(&state);
         let traits = trait_generator.generate();
@@ -112,7 +304,9 @@ impl State {
         let trait_name = &trait_item.ident.to_string();
         let trait_path = make_path(module_path, trait_name);
         let resource = get_resource_from_trait_name(trait_name);
-        let stub: syn::Path = match (&self.mode.is_instrument(), &resource) {
+        // in replay mode, the live imports are wrapped as in record mode
+        let is_proxy = self.mode.is_instrument() || is_wrapped(module_path);
+        let stub: syn::Path = match (is_proxy, &resource) {
             (true, Some(resource)) => {
                 let import_path = get_proxy_path(module_path);
                 make_path(&import_path, resource)
@@ -126,7 +320,7 @@ impl State {
             match item {
                 TraitItem::Type(assoc_type) => {
                     let type_name = &assoc_type.ident;
-                    let stub: syn::Path = if self.mode.is_instrument() {
+                    let stub: syn::Path = if is_proxy {
                         let import_path = get_proxy_path(module_path);
                         make_path(&import_path, &type_name.to_string())
                     } else {
@@ -157,7 +351,17 @@ impl State {
                                 unimplemented!()
                             }
                         },
-                        GenerateMode::Instrument | GenerateMode::Record => {
+                        GenerateMode::Instrument
+                        | GenerateMode::Record
+                        | GenerateMode::Log
+                        | GenerateMode::Profile
+                        | GenerateMode::Policy => {
+                            self.generate_instrument_func(module_path, &sig, &resource)
+                        }
+                        GenerateMode::Replay
+                            if is_proxy
+                                || (module_path.is_empty() && is_wrapped_func(&sig.ident)) =>
+                        {
                             self.generate_instrument_func(module_path, &sig, &resource)
                         }
                         GenerateMode::Replay => {
@@ -167,6 +371,7 @@ impl State {
                         GenerateMode::Dialog => {
                             self.generate_dialog_func(module_path, &sig, &resource)
                         }
+                        GenerateMode::Mock => self.generate_mock_func(module_path, &sig, &resource),
                     };
                     methods.push(syn::ImplItem::Fn(stub_impl));
                 }
@@ -180,12 +385,32 @@ impl State {
         }
     }
 
+    /// Call an export function from the synchronous `start` loop. Async functions, and all
+    /// functions when the world has streams or futures, are driven by `block_on`, so that the
+    /// tasks spawned to produce stream and future values can make progress.
+    fn call_export(
+        &self,
+        sig: &Signature,
+        func: &syn::Path,
+        call_param: impl Iterator<Item = syn::Expr>,
+    ) -> proc_macro2::TokenStream {
+        let call = quote! { #func(#(#call_param),*) };
+        if sig.asyncness.is_some() {
+            quote! { wit_bindgen::block_on(#call) }
+        } else if self.has_stream || self.has_future {
+            quote! { wit_bindgen::block_on(async { #call }) }
+        } else {
+            call
+        }
+    }
     fn generate_conversion_func(&self, sig: &Signature) -> syn::ImplItemFn {
         let func_name = &sig.ident.to_string();
         let body = if func_name.starts_with("get_wrapped_") {
             quote! { x.to_proxy() }
         } else if func_name.starts_with("get_host_") {
             quote! { x.to_proxy() }
+        } else if func_name == "policy_reset" {
+            quote! { policy_reset() }
         } else if func_name.starts_with("get_mock_") {
             let resource = get_return_type(&sig.output).unwrap();
             let name = func_name
@@ -214,6 +439,10 @@ impl State {
 }
 
 pub fn get_proxy_path(src_path: &[String]) -> Vec<String> {
+    if src_path.is_empty() {
+        // world-level items are proxied by name, see get_proxy_func_name
+        return Vec::new();
+    }
     assert!(src_path.len() >= 3);
     let mut res = src_path.to_vec();
     let mut wrapped_idx = 0;
@@ -229,3 +458,21 @@ pub fn get_proxy_path(src_path: &[String]) -> Vec<String> {
     }
     res
 }
+/// Interfaces wrapped by the imports component, e.g. `exports::wrapped_wasi::cli::stdout`.
+pub fn is_wrapped(module_path: &[String]) -> bool {
+    module_path
+        .get(1)
+        .is_some_and(|pkg| pkg.starts_with("wrapped_"))
+}
+/// World-level functions wrapped by the imports component, see `get_proxy_func_name`.
+pub fn is_wrapped_func(func_name: &Ident) -> bool {
+    func_name.to_string().starts_with("wrapped_")
+}
+/// World-level functions are wrapped by name instead of by package: `foo` <-> `wrapped_foo`.
+pub fn get_proxy_func_name(func_name: &Ident) -> Ident {
+    let name = func_name.to_string();
+    match name.strip_prefix("wrapped_") {
+        Some(name) => Ident::new(name, func_name.span()),
+        None => Ident::new(&format!("wrapped_{name}"), func_name.span()),
+    }
+}
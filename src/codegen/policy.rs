@@ -0,0 +1,253 @@
+use super::State;
+use crate::util::{ArgInfo, ResourceFuncKind, get_result_err_type, glob_match};
+use anyhow::Result;
+use proc_macro2::TokenStream;
+use quote::{ToTokens, quote};
+use serde::Deserialize;
+use std::path::Path;
+
+/// The policy of the import calls, loaded from a TOML or JSON file, e.g.,
+/// ```toml
+/// default = "allow"
+///
+/// [[rule]]
+/// method = "wasi:sockets/*"
+/// action = "deny"
+///
+/// [[rule]]
+/// method = "wasi:filesystem/*"
+/// paths = ["data", "tmp/cache"]
+/// error = "access"
+///
+/// [[rule]]
+/// method = "wasi:http/*"
+/// max-calls = 10
+/// ```
+#[derive(Deserialize, Default)]
+#[serde(deny_unknown_fields)]
+pub struct Policy {
+    /// The action for the functions without a matching rule.
+    #[serde(default)]
+    default: Action,
+    #[serde(default)]
+    rule: Vec<Rule>,
+}
+#[derive(Deserialize, Default, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Action {
+    #[default]
+    Allow,
+    Deny,
+}
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+pub struct Rule {
+    /// The display name of the import function. `*` matches any characters.
+    /// The `[method]` and `[constructor]` prefixes are optional.
+    method: String,
+    #[serde(default)]
+    action: Action,
+    /// The allowed path prefixes, relative to the preopened directories. Applies to the string
+    /// arguments named `path` or `*-path`.
+    paths: Option<Vec<String>>,
+    /// The allowed authorities, e.g. `example.com:8080`. Applies to the arguments named `authority`.
+    authorities: Option<Vec<String>>,
+    /// The `err` value in WAVE returned by the denied calls. Functions that do not return a `result`
+    /// trap instead.
+    error: Option<String>,
+    /// The number of calls allowed in each export call, shared by the functions matching the rule.
+    max_calls: Option<u64>,
+    /// The argument and return sizes in WAVE allowed in each export call, shared by the functions
+    /// matching the rule.
+    max_bytes: Option<u64>,
+}
+impl Policy {
+    pub fn load(path: Option<&Path>) -> Result<Self> {
+        let Some(path) = path else {
+            return Ok(Policy::default());
+        };
+        let text = std::fs::read_to_string(path)?;
+        let mut policy: Policy = match path.extension().and_then(|ext| ext.to_str()) {
+            Some("json") => serde_json::from_str(&text)?,
+            _ => toml::from_str(&text)?,
+        };
+        for rule in &mut policy.rule {
+            if rule.action == Action::Deny && (rule.paths.is_some() || rule.authorities.is_some()) {
+                anyhow::bail!(
+                    "policy rule for {} cannot deny with `paths` or `authorities`",
+                    rule.method
+                );
+            }
+            if rule.action == Action::Deny && (rule.max_calls.is_some() || rule.max_bytes.is_some())
+            {
+                anyhow::bail!(
+                    "policy rule for {} cannot deny with `max-calls` or `max-bytes`",
+                    rule.method
+                );
+            }
+            for prefix in rule.paths.iter_mut().flatten() {
+                let parts: Vec<_> = prefix
+                    .split('/')
+                    .filter(|part| !part.is_empty() && *part != ".")
+                    .collect();
+                if parts.contains(&"..") {
+                    anyhow::bail!("policy path {prefix} for {} contains `..`", rule.method);
+                }
+                *prefix = parts.join("/");
+            }
+        }
+        Ok(policy)
+    }
+    /// The first rule matching the method, with or without the `[method]` or `[constructor]` prefix.
+    fn find(&self, method: &str) -> Option<(usize, &Rule)> {
+        let name = method.split_once(']').map_or(method, |(_, name)| name);
+        self.rule
+            .iter()
+            .enumerate()
+            .find(|(_, rule)| glob_match(&rule.method, method) || glob_match(&rule.method, name))
+    }
+    /// The handles are tracked with their directories only when there are path rules.
+    fn has_paths(&self) -> bool {
+        self.rule.iter().any(|rule| rule.paths.is_some())
+    }
+    /// The quotas are reset by the exports component at the start of each export call.
+    pub fn has_quotas(&self) -> bool {
+        self.rule
+            .iter()
+            .any(|rule| rule.max_calls.is_some() || rule.max_bytes.is_some())
+    }
+}
+
+/// The policy check of an import function, see `State::policy_check`.
+pub enum PolicyCheck {
+    Allow,
+    /// The function body that denies the call.
+    Deny(TokenStream),
+    /// The check before calling the host, and the code that tracks the returned handles and the
+    /// return size after.
+    Check(TokenStream, TokenStream),
+}
+
+impl State {
+    pub fn policy_check(
+        &self,
+        display_name: &str,
+        kind: &Option<ResourceFuncKind>,
+        args: &[ArgInfo],
+        ret_ty: Option<&syn::Type>,
+    ) -> PolicyCheck {
+        let (rule_idx, rule) = self.policy.find(display_name).unzip();
+        let action = rule.map_or(self.policy.default, |rule| rule.action);
+        // The denied call returns the error if the function returns a result without handles
+        let err_ty = ret_ty
+            .and_then(get_result_err_type)
+            .filter(|err_ty| !self.may_have_handle(err_ty));
+        let deny = |reason: TokenStream| match (rule.and_then(|rule| rule.error.as_ref()), &err_ty)
+        {
+            (Some(error), Some(err_ty)) => {
+                quote! { return Err(policy_error::<#err_ty>(#display_name, #error)) }
+            }
+            _ => quote! { policy_deny(#display_name, #reason) },
+        };
+        if action == Action::Deny {
+            return PolicyCheck::Deny(deny(quote! { "the function is not allowed" }));
+        }
+        let mut check = TokenStream::new();
+        let mut dir = quote! { None };
+        let paths = rule.and_then(|rule| rule.paths.as_ref());
+        // The path arguments, with the nearest handle argument before them as their base directory
+        let mut base = matches!(kind, Some(ResourceFuncKind::Method))
+            .then(|| quote! { Some(wasm_wave::to_string(&ToValue::to_value(&self)).unwrap()) });
+        let mut path_args = Vec::new();
+        for arg in args {
+            let ident = &arg.ident;
+            let name = ident.to_string();
+            if is_string(&arg.ty) && (name == "path" || name.ends_with("_path")) {
+                let base = base.clone().unwrap_or_else(|| quote! { None });
+                path_args.push(quote! { (#base, #ident.as_str()) });
+            } else if self.may_have_handle(&arg.ty) {
+                base = Some(
+                    quote! { Some(wasm_wave::to_string(&ToValue::to_value(&#ident)).unwrap()) },
+                );
+            }
+        }
+        if let Some(prefixes) = paths
+            && !path_args.is_empty()
+        {
+            let deny = deny(quote! { &__reason });
+            check.extend(quote! {
+                let __dir = match policy_check_paths(&[#(#path_args),*], &[#(#prefixes),*]) {
+                    Ok(dir) => dir,
+                    Err(__reason) => #deny,
+                };
+            });
+            dir = quote! { Some(__dir.as_str()) };
+        }
+        if let Some(authorities) = rule.and_then(|rule| rule.authorities.as_ref()) {
+            for arg in args.iter().filter(|arg| arg.ident == "authority") {
+                let ident = &arg.ident;
+                let authority = if is_string(&arg.ty) {
+                    quote! { Some(#ident.as_str()) }
+                } else if is_optional_string(&arg.ty) {
+                    quote! { #ident.as_deref() }
+                } else {
+                    continue;
+                };
+                let deny = deny(quote! { &__reason });
+                check.extend(quote! {
+                    if let Err(__reason) = policy_check_authority(#authority, &[#(#authorities),*]) {
+                        #deny;
+                    }
+                });
+            }
+        }
+        let mut track = TokenStream::new();
+        if let (Some(idx), Some(rule)) = (rule_idx, rule)
+            && (rule.max_calls.is_some() || rule.max_bytes.is_some())
+        {
+            let max_calls = option_tokens(rule.max_calls);
+            let max_bytes = option_tokens(rule.max_bytes);
+            // the sizes are only measured for the byte quotas
+            let (args_size, ret_size) = if rule.max_bytes.is_some() {
+                let idents = args.iter().map(|arg| &arg.ident);
+                (
+                    quote! { 0 #(+ wasm_wave::to_string(&ToValue::to_value(&#idents)).unwrap().len() as u64)* },
+                    quote! { wasm_wave::to_string(&res.to_value()).unwrap().len() as u64 },
+                )
+            } else {
+                (quote! { 0 }, quote! {})
+            };
+            let deny = deny(quote! { &__reason });
+            check.extend(quote! {
+                if let Err(__reason) = policy_quota(#idx, #max_calls, #max_bytes, #args_size) {
+                    #deny;
+                }
+            });
+            if rule.max_bytes.is_some() && ret_ty.is_some() {
+                track.extend(quote! { policy_quota_ret(#idx, #ret_size); });
+            }
+        }
+        // Handles returned by the calls without a path are at the root of the preopened directories
+        if self.policy.has_paths() && ret_ty.is_some_and(|ty| self.may_have_handle(ty)) {
+            track.extend(quote! { policy_track_dirs(&res.to_value(), #dir); });
+        }
+        if check.is_empty() && track.is_empty() {
+            PolicyCheck::Allow
+        } else {
+            PolicyCheck::Check(check, track)
+        }
+    }
+}
+
+fn option_tokens(value: Option<u64>) -> TokenStream {
+    match value {
+        Some(value) => quote! { Some(#value) },
+        None => quote! { None },
+    }
+}
+fn is_string(ty: &syn::Type) -> bool {
+    ty.to_token_stream().to_string() == "String"
+}
+fn is_optional_string(ty: &syn::Type) -> bool {
+    ty.to_token_stream().to_string() == "Option < String >"
+}
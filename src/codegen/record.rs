@@ -1,7 +1,12 @@
-use super::{GenerateMode, State, get_proxy_path};
-use crate::util::{ResourceFuncKind, extract_arg_info, get_return_type, wit_func_name};
+use super::{
+    GenerateMode, State, get_proxy_func_name, get_proxy_path, policy::PolicyCheck, stream,
+};
+use crate::util::{
+    FullTypePath, ResourceFuncKind, extract_arg_info, get_result_err_type, get_return_type,
+    glob_match, make_path, wit_func_name,
+};
 use quote::quote;
-use syn::{Signature, parse_quote};
+use syn::{Signature, parse_quote, visit_mut::VisitMut};
 
 impl State {
     pub fn generate_instrument_func(
@@ -13,8 +18,15 @@ impl State {
         let func_name = &sig.ident;
         let (kind, args) = extract_arg_info(sig);
         let import_path = get_proxy_path(module_path);
+        // world-level functions share the same types between the import and the wrapped export
+        let is_world_func = module_path.is_empty();
+        let import_func = if is_world_func {
+            get_proxy_func_name(func_name)
+        } else {
+            func_name.clone()
+        };
         let import_sig = self
-            .find_function(&import_path, resource, func_name)
+            .find_function(&import_path, resource, &import_func)
             .unwrap();
         let (_, import_args) = extract_arg_info(import_sig);
         let arg_names = args.iter().map(|arg| &arg.ident);
@@ -29,6 +41,16 @@ impl State {
                     parse_quote! { #ident }
                 }
             });
+        let to_proxy = if is_world_func {
+            quote! {}
+        } else {
+            quote! { .to_proxy() }
+        };
+        let await_call = if sig.asyncness.is_some() {
+            quote! { .await }
+        } else {
+            quote! {}
+        };
         let (func, res): (syn::Expr, _) = match (resource.is_some(), &kind) {
             (true, Some(ResourceFuncKind::Method)) => {
                 (parse_quote! { self.#func_name }, quote! { res.to_proxy() })
@@ -37,52 +59,195 @@ impl State {
                 (parse_quote! { Self::#func_name }, quote! { res })
             }
             (true, None) => (parse_quote! { Self::#func_name }, quote! { res.to_proxy() }),
-            (false, _) => (
-                syn::parse_str(&format!("{}::{}", import_path.join("::"), func_name)).unwrap(),
-                quote! { res.to_proxy() },
-            ),
+            (false, _) => {
+                let path = make_path(&import_path, &import_func.to_string());
+                (parse_quote! { #path }, quote! { res #to_proxy })
+            }
+        };
+        let display_name = wit_func_name(module_path, resource, func_name, &kind);
+        let init_vec = if matches!(kind, Some(ResourceFuncKind::Method)) {
+            quote! { vec![wasm_wave::to_string(&ToValue::to_value(&self)).unwrap()] }
+        } else {
+            quote! { Vec::new() }
+        };
+        let is_mut = if args.is_empty() {
+            quote! {}
+        } else {
+            quote! { mut }
+        };
+        let arg_values = args.iter().map(|arg| {
+            let ident = &arg.ident;
+            stream::to_value(&arg.ty, quote! { &#ident })
+        });
+        let is_export = match module_path.get(1) {
+            Some(pkg) => !pkg.starts_with("wrapped_"),
+            None => !func_name.to_string().starts_with("wrapped_"),
         };
         match &self.mode {
             GenerateMode::Instrument => parse_quote! {
                 #sig {
-                    let res = #func(#(#call_args.to_proxy()),*);
+                    let res = #func(#(#call_args #to_proxy),*)#await_call;
                     #res
                 }
             },
-            GenerateMode::Record => {
-                let init_vec = if matches!(kind, Some(ResourceFuncKind::Method)) {
-                    quote! { vec![wasm_wave::to_string(&ToValue::to_value(&self)).unwrap()] }
+            GenerateMode::Log if !self.log.is_logged(&display_name) => parse_quote! {
+                #sig {
+                    let res = #func(#(#call_args #to_proxy),*)#await_call;
+                    #res
+                }
+            },
+            GenerateMode::Policy if !is_export => {
+                let ret_ty = get_return_type(&sig.output);
+                match self.policy_check(&display_name, &kind, &args, ret_ty.as_ref()) {
+                    PolicyCheck::Allow => parse_quote! {
+                        #sig {
+                            let res = #func(#(#call_args #to_proxy),*)#await_call;
+                            #res
+                        }
+                    },
+                    PolicyCheck::Deny(deny) => parse_quote! {
+                        #[allow(unused_variables)]
+                        #sig {
+                            #deny
+                        }
+                    },
+                    PolicyCheck::Check(check, track) => parse_quote! {
+                        #sig {
+                            #(let #arg_names = #arg_names #to_proxy;)*
+                            #check
+                            let res = #func(#(#call_args),*)#await_call;
+                            #track
+                            #res
+                        }
+                    },
+                }
+            }
+            GenerateMode::Policy => {
+                let reset = if self.policy.has_quotas() {
+                    quote! { proxy::conversion::conversion::policy_reset(); }
                 } else {
-                    quote! { Vec::new() }
-                };
-                let is_mut = if args.is_empty() {
                     quote! {}
+                };
+                parse_quote! {
+                    #sig {
+                        #reset
+                        let res = #func(#(#call_args #to_proxy),*)#await_call;
+                        #res
+                    }
+                }
+            }
+            GenerateMode::Replay => {
+                // the recorded return of a live import only labels the live resources
+                let ret_ty = get_return_type(&import_sig.output).map(|mut ty| {
+                    FullTypePath {
+                        module_path: &import_path,
+                    }
+                    .visit_type_mut(&mut ty);
+                    ty
+                });
+                let replay_import = quote! {
+                    proxy::recorder::replay::replay_import(Some(#display_name), Some(&params)).unwrap_or_else(|err| panic!("{err}"))
+                };
+                let (check_args, register_ret) = match ret_ty {
+                    Some(ret_ty) if self.may_have_handle(&ret_ty) => (
+                        quote! { let recorded_ret = #replay_import; },
+                        quote! {
+                            if let Some(wave) = recorded_ret {
+                                let recorded: Value = wasm_wave::from_str(&<#ret_ty as ValueTyped>::value_type(), &wave).unwrap();
+                                replay_table_register(&res.to_value(), &recorded);
+                            }
+                        },
+                    ),
+                    _ => (quote! { #replay_import; }, quote! {}),
+                };
+                parse_quote! {
+                    #sig {
+                        let #is_mut params: Vec<String> = #init_vec;
+                        #(
+                            let #arg_names = #arg_names #to_proxy;
+                            params.push(wasm_wave::to_string(&#arg_values).unwrap());
+                        )*
+                        // the live call is checked against the recorded arguments
+                        #check_args
+                        let res = #func(#(#call_args),*)#await_call;
+                        #register_ret
+                        #res
+                    }
+                }
+            }
+            GenerateMode::Record | GenerateMode::Log | GenerateMode::Profile => {
+                let clear_labels = if self.has_stream || self.has_future {
+                    quote! { clear_async_labels(); }
                 } else {
-                    quote! { mut }
+                    quote! {}
                 };
-                let display_name = wit_func_name(module_path, resource, func_name, &kind);
-                let is_export = !module_path[1].starts_with("wrapped_");
-                let record_ret = if get_return_type(&sig.output).is_none() {
+                let record_ret = if let Some(ret_ty) = get_return_type(&sig.output) {
+                    let ret_value = if stream::has_async_handle(&ret_ty) {
+                        stream::to_value(&ret_ty, quote! { &res })
+                    } else {
+                        quote! { res.to_value() }
+                    };
+                    let fault = self.fault_err_type(&display_name, &ret_ty, is_export);
+                    let inject_fault = if let Some(err_ty) = fault {
+                        // the fault replaces the host call, and is recorded as its return
+                        quote! {
+                            if let Some(err) = inject_fault::<#err_ty>(#display_name) {
+                                let res: #ret_ty = Err(err);
+                                let wave_res = wasm_wave::to_string(&#ret_value).unwrap();
+                                proxy::recorder::record::record_ret(Some(#display_name), Some(&wave_res), false);
+                                return res;
+                            }
+                        }
+                    } else {
+                        quote! {}
+                    };
+                    let record_ret =
+                        self.record_ret(&display_name, is_export, quote! { Some(&wave_res) });
                     quote! {
-                        #func(#(#call_args),*);
-                        proxy::recorder::record::record_ret(Some(#display_name), None, #is_export);
+                       #inject_fault
+                       let res = #func(#(#call_args),*)#await_call;
+                       let wave_res = wasm_wave::to_string(&#ret_value).unwrap();
+                       #record_ret
+                       #res
                     }
                 } else {
+                    let record_ret = self.record_ret(&display_name, is_export, quote! { None });
                     quote! {
-                       let res = #func(#(#call_args),*);
-                       let wave_res = wasm_wave::to_string(&res.to_value()).unwrap();
-                       proxy::recorder::record::record_ret(Some(#display_name), Some(&wave_res), #is_export);
-                       #res
+                        #func(#(#call_args),*)#await_call;
+                        #record_ret
                     }
                 };
+                let record_args = match self.mode {
+                    GenerateMode::Log => {
+                        quote! { let __start = log_call(#display_name, &params, #is_export); }
+                    }
+                    GenerateMode::Profile => {
+                        quote! { let __start = profile_call(#display_name, #is_export); }
+                    }
+                    _ => {
+                        quote! { proxy::recorder::record::record_args(Some(#display_name), &params, #is_export); }
+                    }
+                };
+                // the header is recorded before the first export call
+                let record_header = match &self.header {
+                    Some(header) if is_export && matches!(self.mode, GenerateMode::Record) => {
+                        quote! {
+                            static HEADER: std::sync::Once = std::sync::Once::new();
+                            HEADER.call_once(|| proxy::recorder::record::record_header(#header));
+                        }
+                    }
+                    _ => quote! {},
+                };
                 parse_quote! {
                     #sig {
+                        #record_header
+                        #clear_labels
                         let #is_mut params: Vec<String> = #init_vec;
                         #(
-                            let #arg_names = #arg_names.to_proxy();
-                            params.push(wasm_wave::to_string(&ToValue::to_value(&#arg_names)).unwrap());
+                            let #arg_names = #arg_names #to_proxy;
+                            params.push(wasm_wave::to_string(&#arg_values).unwrap());
                         )*
-                        proxy::recorder::record::record_args(Some(#display_name), &params, #is_export);
+                        #record_args
                         #record_ret
                     }
                 }
@@ -90,4 +255,42 @@ impl State {
             _ => unreachable!(),
         }
     }
+    /// Record the return value `ret` of type `Option<&str>`, after the args are recorded.
+    fn record_ret(
+        &self,
+        display_name: &str,
+        is_export: bool,
+        ret: proc_macro2::TokenStream,
+    ) -> proc_macro2::TokenStream {
+        match self.mode {
+            GenerateMode::Log => quote! { log_ret(#display_name, #ret, #is_export, __start); },
+            GenerateMode::Profile => {
+                quote! { profile_ret(#display_name, #is_export, &params, #ret, __start); }
+            }
+            _ => {
+                quote! { proxy::recorder::record::record_ret(Some(#display_name), #ret, #is_export); }
+            }
+        }
+    }
+    /// The error type of an import that can get an injected fault, see `FaultArgs`.
+    fn fault_err_type(
+        &self,
+        display_name: &str,
+        ret_ty: &syn::Type,
+        is_export: bool,
+    ) -> Option<syn::Type> {
+        let faults = &self.faults;
+        if is_export || !faults.is_enabled() {
+            return None;
+        }
+        if !faults.fault_include.is_empty()
+            && !faults
+                .fault_include
+                .iter()
+                .any(|p| glob_match(p, display_name))
+        {
+            return None;
+        }
+        get_result_err_type(ret_ty).filter(|err_ty| !self.may_have_handle(err_ty))
+    }
 }
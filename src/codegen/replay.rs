@@ -1,7 +1,7 @@
-use super::State;
+use super::{State, stream};
 use crate::util::{
     FullTypePath, ResourceFuncKind, extract_arg_info, get_owned_type, get_return_type, make_path,
-    wit_func_name,
+    wit_func_name, wit_resource_drop_name,
 };
 use quote::quote;
 use syn::{Signature, parse_quote, visit_mut::VisitMut};
@@ -17,18 +17,36 @@ impl State {
         let is_export = module_path.join("::") == "exports::proxy::recorder::start_replay";
         if !is_export {
             let (kind, args) = extract_arg_info(sig);
-            let arg_names = args.iter().map(|arg| &arg.ident);
+            let arg_values = args.iter().map(|arg| {
+                let ident = &arg.ident;
+                if stream::has_async_handle(&arg.ty) {
+                    stream::to_value(&arg.ty, quote! { &#ident })
+                } else {
+                    quote! { #ident.to_value() }
+                }
+            });
             let display_name = wit_func_name(module_path, resource, func_name, &kind);
             let ret_ty = get_return_type(&sig.output);
             let replay_import = if let Some(ret_ty) = ret_ty {
+                let (value_type, to_rust) = if stream::has_async_handle(&ret_ty) {
+                    (
+                        stream::value_type(&ret_ty),
+                        stream::to_rust(&ret_ty, quote! { &ret }),
+                    )
+                } else {
+                    (
+                        quote! { <#ret_ty as ValueTyped>::value_type() },
+                        quote! { ret.to_rust() },
+                    )
+                };
                 quote! {
-                    let wave = proxy::recorder::replay::replay_import(Some(#display_name), Some(&args)).unwrap();
-                    let ret: Value = wasm_wave::from_str(&<#ret_ty as ValueTyped>::value_type(), &wave).unwrap();
-                    ret.to_rust()
+                    let wave = proxy::recorder::replay::replay_import(Some(#display_name), Some(&args)).unwrap_or_else(|err| panic!("{err}")).unwrap();
+                    let ret: Value = wasm_wave::from_str(&#value_type, &wave).unwrap();
+                    #to_rust
                 }
             } else {
                 quote! {
-                    let wave = proxy::recorder::replay::replay_import(Some(#display_name), Some(&args));
+                    let wave = proxy::recorder::replay::replay_import(Some(#display_name), Some(&args)).unwrap_or_else(|err| panic!("{err}"));
                     assert!(wave.is_none());
                 }
             };
@@ -40,7 +58,7 @@ impl State {
             };
             parse_quote! {
                 #sig {
-                    let args = vec![#self_value #( wasm_wave::to_string(&#arg_names.to_value()).unwrap() ),*];
+                    let args = vec![#self_value #( wasm_wave::to_string(&#arg_values).unwrap() ),*];
                     #replay_import
                 }
             }
@@ -49,62 +67,148 @@ impl State {
             let arms = self
                 .funcs
                 .iter()
-                .filter(|(path, _)| path[0] != "exports" && path[0] != "proxy")
+                .filter(|(path, _)| path.first().is_none_or(|p| p != "exports" && p != "proxy"))
                 .flat_map(|(path, resources)| {
                     resources.iter().flat_map(move |(resource, sigs)| {
-                        sigs.iter().filter_map(move |sig| {
-                            let (kind, args) = extract_arg_info(sig);
-                            if matches!(kind, Some(ResourceFuncKind::Method)) {
+                        // drops of the resources in the replay table, recorded by fuzz runs
+                        let drop_arm = resource.as_ref().and_then(|resource| {
+                            let path_ty = make_path(path, resource);
+                            let resource_ty: syn::Type = parse_quote! { #path_ty };
+                            if !self.is_resource_type(&resource_ty) || self.has_mock_conversion(path, resource) {
                                 return None;
                             }
+                            let display_name = wit_resource_drop_name(path, resource);
+                            Some(quote! {
+                                #display_name => {
+                                    let arg_value: Value = wasm_wave::from_str(&<#resource_ty as ValueTyped>::value_type(), &args[0]).unwrap();
+                                    let res: #resource_ty = arg_value.to_rust();
+                                    drop(res);
+                                    proxy::recorder::replay::assert_export_ret(Some(#display_name), None).unwrap_or_else(|err| panic!("{err}"));
+                                }
+                            })
+                        });
+                        sigs.iter().map(move |sig| {
+                            let (kind, args) = extract_arg_info(sig);
+                            let is_method = matches!(kind, Some(ResourceFuncKind::Method));
+                            let resource_ty: Option<syn::Type> = resource.as_ref().map(|resource| {
+                                let path = make_path(path, resource);
+                                parse_quote! { #path }
+                            });
+                            // the recorded args of a method start with the borrowed self
+                            let self_arg = if is_method {
+                                let resource_ty = resource_ty.as_ref().unwrap();
+                                quote! {
+                                    let arg_value: Value = wasm_wave::from_str(&<&#resource_ty as ValueTyped>::value_type(), &args[0]).unwrap();
+                                    let __self: &#resource_ty = arg_value.to_rust();
+                                }
+                            } else {
+                                quote! {}
+                            };
                             let arg_name: Vec<_> = args.iter().map(|arg| &arg.ident).collect();
-                            let arg_idx = args.iter().enumerate().map(|(idx, _)| quote! { args[#idx] });
-                            let call_param = args.iter().map(|arg| arg.call_param());
-                            let ty = args.iter().map(|arg| {
+                            let arg_idx = args.iter().enumerate().map(|(idx, _)| {
+                                let idx = idx + is_method as usize;
+                                quote! { args[#idx] }
+                            });
+                            let ty: Vec<_> = args.iter().map(|arg| {
                                 let mut ty = arg.ty.clone();
                                 FullTypePath {
                                     module_path: path,
                                 }.visit_type_mut(&mut ty);
-                                if let Some(owned) = get_owned_type(&ty) {
-                                    owned
+                                match &ty {
+                                    // borrowed resources are looked up in the replay table
+                                    syn::Type::Reference(ty_ref) if self.is_resource_type(&ty_ref.elem) => ty,
+                                    _ => get_owned_type(&ty).unwrap_or(ty),
+                                }
+                            }).collect();
+                            let (value_type, to_rust): (Vec<_>, Vec<_>) = ty.iter().map(|ty| {
+                                if stream::has_async_handle(ty) {
+                                    (stream::value_type(ty), stream::to_rust(ty, quote! { &arg_value }))
                                 } else {
-                                    ty
+                                    (quote! { <#ty as ValueTyped>::value_type() }, quote! { arg_value.to_rust() })
                                 }
-                            });
+                            }).unzip();
                             let func_name = if let Some(resource) = resource {
                                 format!("{}::{}", resource, sig.ident)
                             } else {
                                 sig.ident.to_string()
                             };
                             let func = make_path(path, &func_name);
+                            let self_param: Option<syn::Expr> = is_method.then(|| parse_quote! { __self });
+                            let call = self.call_export(sig, &func, self_param.into_iter().chain(args.iter().map(|arg| arg.call_param())));
                             let display_name = wit_func_name(path, resource, &sig.ident, &kind);
-                            let assert_ret = if get_return_type(&sig.output).is_none() {
+                            let assert_ret = if let Some(mut ret_ty) = get_return_type(&sig.output) {
+                                FullTypePath { module_path: path }.visit_type_mut(&mut ret_ty);
+                                if matches!(kind, Some(ResourceFuncKind::Constructor)) {
+                                    ret_ty = resource_ty.clone().unwrap();
+                                }
+                                let (value_type, ret_value) = if stream::has_async_handle(&ret_ty) {
+                                    (stream::value_type(&ret_ty), stream::to_value(&ret_ty, quote! { &res }))
+                                } else {
+                                    (quote! { <#ret_ty as ValueTyped>::value_type() }, quote! { res.to_value() })
+                                };
+                                let assert_rule = match self.asserts.generate(&display_name) {
+                                    Some(rule) => quote! {
+                                        let mut wave_res = wasm_wave::to_string(&ret_value).unwrap();
+                                        // the result allowed by the assertion rule is reported as the recorded one
+                                        if let Some(wave) = recorded_ret {
+                                            let recorded: Value = wasm_wave::from_str(&#value_type, &wave).unwrap();
+                                            if assert_value(&#rule, "ret", &recorded, &ret_value) {
+                                                wave_res = wave;
+                                            }
+                                        }
+                                    },
+                                    None => quote! {
+                                        let wave_res = wasm_wave::to_string(&ret_value).unwrap();
+                                    },
+                                };
                                 quote! {
-                                    assert!(res == ());
-                                    proxy::recorder::replay::assert_export_ret(Some(#display_name), None);
+                                    let mut ret_value = #ret_value;
+                                    let recorded_ret = proxy::recorder::replay::replay_export_ret();
+                                    // returned resources are kept in the replay table for later calls
+                                    let owned = recorded_ret.as_ref().is_some_and(|wave| {
+                                        let recorded: Value = wasm_wave::from_str(&#value_type, wave).unwrap();
+                                        replay_table_register(&ret_value, &recorded)
+                                    });
+                                    if owned {
+                                        ret_value = #ret_value;
+                                    }
+                                    #assert_rule
+                                    proxy::recorder::replay::assert_export_ret(Some(#display_name), Some(&wave_res)).unwrap_or_else(|err| panic!("{err}"));
+                                    if owned {
+                                        let _ = std::mem::ManuallyDrop::new(res);
+                                    }
                                 }
                             } else {
                                 quote! {
-                                    let wave_res = wasm_wave::to_string(&res.to_value()).unwrap();
-                                    proxy::recorder::replay::assert_export_ret(Some(#display_name), Some(&wave_res));
+                                    assert!(res == ());
+                                    proxy::recorder::replay::assert_export_ret(Some(#display_name), None).unwrap_or_else(|err| panic!("{err}"));
                                 }
                             };
-                            Some(quote! {
+                            quote! {
                                 #display_name => {
+                                    #self_arg
                                     #(
-                                        let arg_value: Value = wasm_wave::from_str(&<#ty as ValueTyped>::value_type(), &#arg_idx).unwrap();
-                                        let #arg_name: #ty = arg_value.to_rust();
+                                        let arg_value: Value = wasm_wave::from_str(&#value_type, &#arg_idx).unwrap();
+                                        let #arg_name: #ty = #to_rust;
                                     )*
-                                    let res = #func(#(#call_param),*);
+                                    let res = #call;
                                     #assert_ret
                                 }
-                            })
-                        })
+                            }
+                        }).chain(drop_arm)
                     })
                 });
+            let check_header = self.header.as_ref().map(|header| {
+                quote! {
+                    if let Some(err) = proxy::recorder::replay::check_header(#header) {
+                        panic!("{err}");
+                    }
+                }
+            });
             parse_quote! {
                 #sig {
-                    while let Some((method, args)) = proxy::recorder::replay::replay_export() {
+                    #check_header
+                    while let Some((method, args)) = proxy::recorder::replay::replay_export().unwrap_or_else(|err| panic!("{err}")) {
                         match method.as_str() {
                             #(#arms)*
                             _ => unreachable!(),
@@ -0,0 +1,308 @@
+//! wit-bindgen represents `stream<T>` and `future<T>` as `StreamReader<T>` and `FutureReader<T>`.
+//! We cannot implement the wasm-wave and arbitrary traits for them, so the conversions of the
+//! values that contain them are generated inline, through option, result, tuple and list.
+use super::{State, TypeInfo};
+use crate::util::{extract_arg_info, get_return_type};
+use anyhow::Result;
+use proc_macro2::TokenStream;
+use quote::quote;
+use syn::{GenericArgument, PathArguments, Type};
+
+enum AsyncHandle {
+    Stream,
+    Future,
+}
+
+fn generic_args(ty: &Type) -> Option<(String, Vec<&Type>)> {
+    let Type::Path(path) = ty else {
+        return None;
+    };
+    let segment = path.path.segments.last()?;
+    let args = match &segment.arguments {
+        PathArguments::AngleBracketed(args) => args
+            .args
+            .iter()
+            .filter_map(|arg| match arg {
+                GenericArgument::Type(ty) => Some(ty),
+                _ => None,
+            })
+            .collect(),
+        _ => Vec::new(),
+    };
+    Some((segment.ident.to_string(), args))
+}
+fn async_handle(ty: &Type) -> Option<AsyncHandle> {
+    match generic_args(ty)? {
+        (name, args) if name == "StreamReader" && args.len() == 1 => Some(AsyncHandle::Stream),
+        (name, args) if name == "FutureReader" && args.len() == 1 => Some(AsyncHandle::Future),
+        _ => None,
+    }
+}
+fn is_unit(ty: &Type) -> bool {
+    matches!(ty, Type::Tuple(tuple) if tuple.elems.is_empty())
+}
+
+/// Whether the type contains a stream or future handle, outside of the user-defined types.
+pub fn has_async_handle(ty: &Type) -> bool {
+    match ty {
+        Type::Tuple(tuple) => tuple.elems.iter().any(has_async_handle),
+        Type::Reference(ty_ref) => has_async_handle(&ty_ref.elem),
+        Type::Slice(slice) => has_async_handle(&slice.elem),
+        _ => {
+            async_handle(ty).is_some()
+                || generic_args(ty).is_some_and(|(_, args)| args.into_iter().any(has_async_handle))
+        }
+    }
+}
+
+/// Check that the streams and futures in `ty` are only nested in options, results, tuples and
+/// lists, which are converted inline.
+fn check(ty: &Type) -> Result<()> {
+    if !has_async_handle(ty) || async_handle(ty).is_some() {
+        return Ok(());
+    }
+    if let Type::Tuple(tuple) = ty {
+        return tuple.elems.iter().try_for_each(check);
+    }
+    match generic_args(ty) {
+        Some((name, args))
+            if matches!(
+                (name.as_str(), args.len()),
+                ("Option", 1) | ("Vec", 1) | ("Result", 2)
+            ) =>
+        {
+            args.into_iter().try_for_each(check)
+        }
+        _ => anyhow::bail!("stream or future in {} is not supported", quote! { #ty }),
+    }
+}
+
+impl State {
+    /// Streams and futures in records and variants are not supported yet, because their
+    /// conversions are generated inline instead of by the traits of the types.
+    pub fn check_async_types(&self) -> Result<()> {
+        for (module_path, types) in &self.types {
+            for info in types {
+                let (name, fields): (_, Vec<_>) = match info {
+                    TypeInfo::Struct(item) => (&item.ident, item.fields.iter().collect()),
+                    TypeInfo::Enum(item) => (
+                        &item.ident,
+                        item.variants.iter().flat_map(|v| &v.fields).collect(),
+                    ),
+                    _ => continue,
+                };
+                if fields.iter().any(|field| has_async_handle(&field.ty)) {
+                    anyhow::bail!(
+                        "stream or future in type {}::{name} is not supported",
+                        module_path.join("::")
+                    );
+                }
+            }
+        }
+        for resources in self.funcs.values() {
+            for sig in resources.values().flatten() {
+                let (_, args) = extract_arg_info(sig);
+                for ty in args
+                    .iter()
+                    .map(|arg| &arg.ty)
+                    .chain(get_return_type(&sig.output).as_ref())
+                {
+                    check(ty)
+                        .map_err(|err| anyhow::anyhow!("{err}, used by function {}", sig.ident))?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Expression of the wasm-wave `Type` of `ty`.
+pub fn value_type(ty: &Type) -> TokenStream {
+    if !has_async_handle(ty) {
+        return quote! { <#ty as ValueTyped>::value_type() };
+    }
+    match async_handle(ty) {
+        Some(AsyncHandle::Stream) => return quote! { Type::handle("stream") },
+        Some(AsyncHandle::Future) => return quote! { Type::handle("future") },
+        None => (),
+    }
+    if let Type::Tuple(tuple) = ty {
+        let elems = tuple.elems.iter().map(value_type);
+        return quote! { Type::tuple(vec![#(#elems),*]).unwrap() };
+    }
+    let (name, args) = generic_args(ty).unwrap();
+    match (name.as_str(), args.as_slice()) {
+        ("Option", [inner]) => {
+            let inner = value_type(inner);
+            quote! { Type::option(#inner) }
+        }
+        ("Vec", [inner]) => {
+            let inner = value_type(inner);
+            quote! { Type::list(#inner) }
+        }
+        ("Result", [ok, err]) => {
+            let [ok, err] = [ok, err].map(|ty| {
+                if is_unit(ty) {
+                    quote! { None }
+                } else {
+                    let ty = value_type(ty);
+                    quote! { Some(#ty) }
+                }
+            });
+            quote! { Type::result(#ok, #err) }
+        }
+        _ => unreachable!("checked by State::check_async_types"),
+    }
+}
+
+/// Expression that converts `expr`, a reference to `ty`, into a wasm-wave `Value`.
+/// Handles are labeled by `async_label`, see traits::stream.
+pub fn to_value(ty: &Type, expr: TokenStream) -> TokenStream {
+    if !has_async_handle(ty) {
+        return quote! { ToValue::to_value(#expr) };
+    }
+    match async_handle(ty) {
+        Some(AsyncHandle::Stream) => {
+            return quote! {{
+                let _ = #expr;
+                Value::make_handle(async_label("stream").into())
+            }};
+        }
+        Some(AsyncHandle::Future) => {
+            return quote! {{
+                let _ = #expr;
+                Value::make_handle(async_label("future").into())
+            }};
+        }
+        None => (),
+    }
+    let value_ty = value_type(ty);
+    if let Type::Tuple(tuple) = ty {
+        let elems = tuple.elems.iter().enumerate().map(|(idx, elem)| {
+            let idx = syn::Index::from(idx);
+            to_value(elem, quote! { &t.#idx })
+        });
+        return quote! {{
+            let t = #expr;
+            Value::make_tuple(&#value_ty, vec![#(#elems),*]).unwrap()
+        }};
+    }
+    let (name, args) = generic_args(ty).unwrap();
+    match (name.as_str(), args.as_slice()) {
+        ("Option", [inner]) => {
+            let inner = to_value(inner, quote! { x });
+            quote! { Value::make_option(&#value_ty, (#expr).as_ref().map(|x| #inner)).unwrap() }
+        }
+        ("Vec", [inner]) => {
+            let inner = to_value(inner, quote! { x });
+            quote! { Value::make_list(&#value_ty, (#expr).iter().map(|x| #inner)).unwrap() }
+        }
+        ("Result", [ok, err]) => {
+            let [ok, err] = [ok, err].map(|ty| {
+                if is_unit(ty) {
+                    (quote! { _ }, quote! { None })
+                } else {
+                    let inner = to_value(ty, quote! { x });
+                    (quote! { x }, quote! { Some(#inner) })
+                }
+            });
+            let ((ok_pat, ok), (err_pat, err)) = (ok, err);
+            quote! {
+                Value::make_result(&#value_ty, match #expr {
+                    Ok(#ok_pat) => Ok(#ok),
+                    Err(#err_pat) => Err(#err),
+                }).unwrap()
+            }
+        }
+        _ => unreachable!("checked by State::check_async_types"),
+    }
+}
+
+/// Expression that converts `expr`, a reference to a wasm-wave `Value`, into `ty`.
+/// Stream chunks and future values are replayed from the trace.
+pub fn to_rust(ty: &Type, expr: TokenStream) -> TokenStream {
+    if !has_async_handle(ty) {
+        return quote! { <Value as ToRust<#ty>>::to_rust(#expr) };
+    }
+    match async_handle(ty) {
+        Some(AsyncHandle::Stream) => return quote! { replay_stream_reader(#expr) },
+        Some(AsyncHandle::Future) => return quote! { replay_future_reader(#expr) },
+        None => (),
+    }
+    if let Type::Tuple(tuple) = ty {
+        let elems = tuple.elems.iter().enumerate().map(|(idx, elem)| {
+            let idx = syn::Index::from(idx);
+            to_rust(elem, quote! { &t[#idx] })
+        });
+        return quote! {{
+            let t: Vec<_> = (#expr).unwrap_tuple().collect();
+            (#(#elems,)*)
+        }};
+    }
+    let (name, args) = generic_args(ty).unwrap();
+    match (name.as_str(), args.as_slice()) {
+        ("Option", [inner]) => {
+            let inner = to_rust(inner, quote! { &x });
+            quote! { (#expr).unwrap_option().map(|x| #inner) }
+        }
+        ("Vec", [inner]) => {
+            let inner = to_rust(inner, quote! { &x });
+            quote! { (#expr).unwrap_list().map(|x| #inner).collect() }
+        }
+        ("Result", [ok, err]) => {
+            let [ok, err] = [ok, err].map(|ty| {
+                if is_unit(ty) {
+                    (quote! { _ }, quote! { () })
+                } else {
+                    (quote! { x }, to_rust(ty, quote! { &x.unwrap() }))
+                }
+            });
+            let ((ok_pat, ok), (err_pat, err)) = (ok, err);
+            quote! {
+                match (#expr).unwrap_result() {
+                    Ok(#ok_pat) => Ok(#ok),
+                    Err(#err_pat) => Err(#err),
+                }
+            }
+        }
+        _ => unreachable!("checked by State::check_async_types"),
+    }
+}
+
+/// Expression that generates an arbitrary `ty` from `u`.
+pub fn arbitrary(ty: &Type) -> TokenStream {
+    if !has_async_handle(ty) {
+        return quote! { u.arbitrary::<#ty>().unwrap() };
+    }
+    match async_handle(ty) {
+        Some(AsyncHandle::Stream) => return quote! { arbitrary_stream_reader(&mut u) },
+        Some(AsyncHandle::Future) => return quote! { arbitrary_future_reader(&mut u) },
+        None => (),
+    }
+    if let Type::Tuple(tuple) = ty {
+        let elems = tuple.elems.iter().map(arbitrary);
+        return quote! { (#(#elems,)*) };
+    }
+    let (name, args) = generic_args(ty).unwrap();
+    match (name.as_str(), args.as_slice()) {
+        ("Option", [inner]) => {
+            let inner = arbitrary(inner);
+            quote! {
+                if u.arbitrary::<bool>().unwrap() { Some(#inner) } else { None }
+            }
+        }
+        ("Vec", [inner]) => {
+            let inner = arbitrary(inner);
+            quote! {
+                (0..u.arbitrary_len::<u8>().unwrap()).map(|_| #inner).collect()
+            }
+        }
+        ("Result", [ok, err]) => {
+            let [ok, err] = [ok, err].map(|ty| arbitrary(ty));
+            quote! {
+                if u.arbitrary::<bool>().unwrap() { Ok(#ok) } else { Err(#err) }
+            }
+        }
+        _ => unreachable!("checked by State::check_async_types"),
+    }
+}
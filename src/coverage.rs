@@ -0,0 +1,387 @@
+//! Edge coverage for the root component. Each basic block of the root core modules calls `hit`
+//! of a coverage core module with a block id, which turns the sequence of block ids into edges,
+//! AFL-style, and keeps the edge map in its own linear memory. Only the first hit of each edge in
+//! a run is reported to the host driver in `run` through `proxy:util/coverage.edge`.
+use anyhow::Result;
+use std::collections::BTreeSet;
+use std::convert::Infallible;
+use wasm_encoder::reencode::{
+    Error, Reencode, ReencodeComponent, component_utils,
+    utils::{self, parse_core_module},
+};
+use wasm_encoder::{
+    Alias, BlockType, CanonicalFunctionSection, CodeSection, Component, ComponentAliasSection,
+    ComponentExportKind, ComponentImportSection, ComponentSectionId, ComponentTypeRef,
+    ComponentTypeSection, ComponentValType, ConstExpr, EntityType, ExportKind, ExportSection,
+    Function, FunctionSection, GlobalSection, GlobalType, ImportSection, InstanceSection,
+    InstanceType, Instruction, MemArg, MemorySection, MemoryType, Module, ModuleArg, ModuleSection,
+    PrimitiveValType, RawSection, SectionId, TypeSection, ValType,
+};
+use wasmparser::{Operator, Parser, Payload, TypeRef};
+
+pub const COVERAGE_INTERFACE: &str = "proxy:util/coverage";
+const COVERAGE_MODULE: &str = "proxy-coverage";
+
+/// Add the coverage import to the root component and instrument its core modules.
+/// Modules of the nested components are left untouched.
+pub fn instrument(wasm: &[u8]) -> Result<Vec<u8>> {
+    let mut component = Component::new();
+    // The coverage items are defined first, so that the existing items of each index space
+    // are shifted: (type 0), (instance 0), (func 0), (core func 0), (core module 0), and
+    // (core instance 0) and (core instance 1).
+    let mut types = ComponentTypeSection::new();
+    let mut instance = InstanceType::new();
+    instance
+        .ty()
+        .function()
+        .params([("id", ComponentValType::Primitive(PrimitiveValType::U32))])
+        .result(None);
+    instance.export("edge", ComponentTypeRef::Func(0));
+    types.instance(&instance);
+    component.section(&types);
+    let mut imports = ComponentImportSection::new();
+    imports.import(COVERAGE_INTERFACE, ComponentTypeRef::Instance(0));
+    component.section(&imports);
+    let mut aliases = ComponentAliasSection::new();
+    aliases.alias(Alias::InstanceExport {
+        instance: 0,
+        kind: ComponentExportKind::Func,
+        name: "edge",
+    });
+    component.section(&aliases);
+    let mut canonical = CanonicalFunctionSection::new();
+    canonical.lower(0, []);
+    component.section(&canonical);
+    component.section(&ModuleSection(&edge_map()));
+    let mut instances = InstanceSection::new();
+    instances.export_items([("edge", ExportKind::Func, 0)]);
+    instances.instantiate(0, [("host", ModuleArg::Instance(0))]);
+    component.section(&instances);
+
+    let mut reencoder = ComponentCoverage {
+        depth: 0,
+        module_count: 0,
+        instrumented: BTreeSet::new(),
+        block_count: 0,
+    };
+    reencoder
+        .parse_component(&mut component, Parser::new(0), wasm)
+        .map_err(|e| anyhow::anyhow!("failed to instrument coverage: {e}"))?;
+    eprintln!(
+        "Instrumented {} basic blocks in {} core modules",
+        reencoder.block_count,
+        reencoder.instrumented.len()
+    );
+    Ok(component.finish())
+}
+
+/// The core module that keeps the edge map in its memory. `hit(id)` computes the edge from the
+/// previous block, and calls `host.edge` when the edge is reached for the first time.
+fn edge_map() -> Module {
+    let mut types = TypeSection::new();
+    types.ty().function([ValType::I32], []);
+    let mut imports = ImportSection::new();
+    imports.import("host", "edge", EntityType::Function(0));
+    let mut funcs = FunctionSection::new();
+    funcs.function(0);
+    let mut memories = MemorySection::new();
+    memories.memory(MemoryType {
+        minimum: 1,
+        maximum: Some(1),
+        memory64: false,
+        shared: false,
+        page_size_log2: None,
+    });
+    let mut globals = GlobalSection::new();
+    globals.global(
+        GlobalType {
+            val_type: ValType::I32,
+            mutable: true,
+            shared: false,
+        },
+        &ConstExpr::i32_const(0),
+    );
+    let mut exports = ExportSection::new();
+    exports.export("hit", ExportKind::Func, 1);
+
+    let byte = MemArg {
+        offset: 0,
+        align: 0,
+        memory_index: 0,
+    };
+    let (id, edge) = (0, 1);
+    let mut f = Function::new([(1, ValType::I32)]);
+    f.instruction(&Instruction::LocalGet(id))
+        .instruction(&Instruction::GlobalGet(0))
+        .instruction(&Instruction::I32Xor)
+        .instruction(&Instruction::I32Const(EDGE_MASK))
+        .instruction(&Instruction::I32And)
+        .instruction(&Instruction::LocalTee(edge))
+        .instruction(&Instruction::I32Load8U(byte))
+        .instruction(&Instruction::I32Eqz)
+        .instruction(&Instruction::If(BlockType::Empty))
+        .instruction(&Instruction::LocalGet(edge))
+        .instruction(&Instruction::I32Const(1))
+        .instruction(&Instruction::I32Store8(byte))
+        .instruction(&Instruction::LocalGet(edge))
+        .instruction(&Instruction::Call(0))
+        .instruction(&Instruction::End)
+        .instruction(&Instruction::LocalGet(id))
+        .instruction(&Instruction::I32Const(1))
+        .instruction(&Instruction::I32ShrU)
+        .instruction(&Instruction::GlobalSet(0))
+        .instruction(&Instruction::End);
+    let mut code = CodeSection::new();
+    code.function(&f);
+
+    let mut module = Module::new();
+    module
+        .section(&types)
+        .section(&imports)
+        .section(&funcs)
+        .section(&memories)
+        .section(&globals)
+        .section(&exports)
+        .section(&code);
+    module
+}
+/// The edge map is one page of memory, one byte per edge.
+const EDGE_MASK: i32 = 0xffff;
+
+struct ComponentCoverage {
+    depth: u32,
+    module_count: u32,
+    instrumented: BTreeSet<u32>,
+    block_count: u32,
+}
+impl ComponentCoverage {
+    fn shift(&self, idx: u32) -> u32 {
+        if self.depth == 0 { idx + 1 } else { idx }
+    }
+}
+impl Reencode for ComponentCoverage {
+    type Error = Infallible;
+    fn function_index(&mut self, func: u32) -> Result<u32, Error> {
+        Ok(self.shift(func))
+    }
+}
+impl ReencodeComponent for ComponentCoverage {
+    fn component_type_index(&mut self, ty: u32) -> u32 {
+        self.shift(ty)
+    }
+    fn component_instance_index(&mut self, instance: u32) -> u32 {
+        self.shift(instance)
+    }
+    fn component_func_index(&mut self, func: u32) -> u32 {
+        self.shift(func)
+    }
+    fn module_index(&mut self, module: u32) -> u32 {
+        self.shift(module)
+    }
+    fn instance_index(&mut self, instance: u32) -> u32 {
+        if self.depth == 0 {
+            instance + 2
+        } else {
+            instance
+        }
+    }
+    fn outer_component_type_index(&mut self, count: u32, ty: u32) -> u32 {
+        // only the aliases that reach the root component are shifted
+        if count == self.depth { ty + 1 } else { ty }
+    }
+    fn outer_module_index(&mut self, count: u32, module: u32) -> u32 {
+        if count == self.depth {
+            module + 1
+        } else {
+            module
+        }
+    }
+    fn push_depth(&mut self) {
+        self.depth += 1;
+    }
+    fn pop_depth(&mut self) {
+        self.depth -= 1;
+    }
+    fn parse_component_submodule(
+        &mut self,
+        component: &mut Component,
+        parser: Parser,
+        module: &[u8],
+    ) -> Result<(), Error> {
+        if self.depth > 0 {
+            return component_utils::parse_component_submodule(self, component, parser, module);
+        }
+        let idx = self.module_count;
+        self.module_count += 1;
+        let Some(mut reencoder) = ModuleCoverage::new(module, self.block_count)? else {
+            return component_utils::parse_component_submodule(self, component, parser, module);
+        };
+        let mut new_module = Module::new();
+        parse_core_module(&mut reencoder, &mut new_module, parser, module)?;
+        self.block_count = reencoder.block_count;
+        self.instrumented.insert(idx);
+        component.section(&RawSection {
+            id: ComponentSectionId::CoreModule as u8,
+            data: new_module.as_slice(),
+        });
+        Ok(())
+    }
+    fn parse_instance(
+        &mut self,
+        instances: &mut InstanceSection,
+        instance: wasmparser::Instance<'_>,
+    ) -> Result<(), Error> {
+        match instance {
+            wasmparser::Instance::Instantiate { module_index, args }
+                if self.depth == 0 && self.instrumented.contains(&module_index) =>
+            {
+                let args: Vec<_> = args
+                    .iter()
+                    .map(|arg| {
+                        (
+                            arg.name,
+                            ModuleArg::Instance(self.instance_index(arg.index)),
+                        )
+                    })
+                    .chain([(COVERAGE_MODULE, ModuleArg::Instance(1))])
+                    .collect();
+                instances.instantiate(self.module_index(module_index), args);
+                Ok(())
+            }
+            _ => component_utils::parse_instance(self, instances, instance),
+        }
+    }
+}
+
+/// Appends the `hit` import after the existing function imports, so the defined functions
+/// are shifted by one.
+struct ModuleCoverage {
+    func_imports: u32,
+    hit_type: u32,
+    has_imports: bool,
+    import_added: bool,
+    block_count: u32,
+}
+impl ModuleCoverage {
+    fn new(module: &[u8], block_count: u32) -> Result<Option<Self>, Error> {
+        let mut func_imports = 0;
+        let mut hit_type = 0;
+        let mut has_imports = false;
+        let mut has_code = false;
+        for payload in Parser::new(0).parse_all(module) {
+            match payload? {
+                Payload::TypeSection(section) => {
+                    for group in section {
+                        hit_type += group?.types().len() as u32;
+                    }
+                }
+                Payload::ImportSection(section) => {
+                    has_imports = true;
+                    for import in section.into_imports() {
+                        if matches!(import?.ty, TypeRef::Func(_) | TypeRef::FuncExact(_)) {
+                            func_imports += 1;
+                        }
+                    }
+                }
+                Payload::CodeSectionStart { count, .. } => has_code = count > 0,
+                _ => (),
+            }
+        }
+        Ok(has_code.then_some(ModuleCoverage {
+            func_imports,
+            hit_type,
+            has_imports,
+            import_added: false,
+            block_count,
+        }))
+    }
+    fn hit(&mut self, f: &mut Function) {
+        // scatter the block ids, so that the edge ids computed by the edge map are spread out
+        let id = self.block_count.wrapping_mul(0x9e37_79b1);
+        self.block_count += 1;
+        f.instruction(&Instruction::I32Const(id as i32));
+        f.instruction(&Instruction::Call(self.func_imports));
+    }
+    fn add_import(&mut self, imports: &mut ImportSection) {
+        imports.import(COVERAGE_MODULE, "hit", EntityType::Function(self.hit_type));
+        self.import_added = true;
+    }
+}
+impl Reencode for ModuleCoverage {
+    type Error = Infallible;
+    fn function_index(&mut self, func: u32) -> Result<u32, Error> {
+        Ok(if func >= self.func_imports {
+            func + 1
+        } else {
+            func
+        })
+    }
+    fn parse_type_section(
+        &mut self,
+        types: &mut TypeSection,
+        section: wasmparser::TypeSectionReader<'_>,
+    ) -> Result<(), Error> {
+        utils::parse_type_section(self, types, section)?;
+        types.ty().function([ValType::I32], []);
+        Ok(())
+    }
+    fn parse_import_section(
+        &mut self,
+        imports: &mut ImportSection,
+        section: wasmparser::ImportSectionReader<'_>,
+    ) -> Result<(), Error> {
+        utils::parse_import_section(self, imports, section)?;
+        self.add_import(imports);
+        Ok(())
+    }
+    fn intersperse_section_hook(
+        &mut self,
+        module: &mut Module,
+        _after: Option<SectionId>,
+        before: Option<SectionId>,
+    ) -> Result<(), Error> {
+        let past_imports = before.is_none_or(|id| id as u8 > SectionId::Import as u8);
+        if !self.has_imports && !self.import_added && past_imports {
+            let mut imports = ImportSection::new();
+            self.add_import(&mut imports);
+            module.section(&imports);
+        }
+        Ok(())
+    }
+    fn parse_function_body(
+        &mut self,
+        code: &mut wasm_encoder::CodeSection,
+        func: wasmparser::FunctionBody<'_>,
+    ) -> Result<(), Error> {
+        let mut f = self.new_function_with_parsed_locals(&func)?;
+        self.hit(&mut f);
+        let mut depth = 1;
+        let mut reader = func.get_operators_reader()?;
+        while !reader.eof() {
+            let op = reader.read()?;
+            // a basic block starts at the target of each branch and after each conditional branch
+            let new_block = match &op {
+                Operator::Block { .. } | Operator::Try { .. } | Operator::TryTable { .. } => {
+                    depth += 1;
+                    false
+                }
+                Operator::Loop { .. } | Operator::If { .. } => {
+                    depth += 1;
+                    true
+                }
+                Operator::Else | Operator::BrIf { .. } => true,
+                Operator::End => {
+                    depth -= 1;
+                    depth > 0
+                }
+                _ => false,
+            };
+            f.instruction(&self.instruction(op)?);
+            if new_block {
+                self.hit(&mut f);
+            }
+        }
+        code.function(&f);
+        Ok(())
+    }
+}
@@ -17,17 +17,100 @@ pub struct InstrumentArgs {
     /// Whether to use the host recorder implementation or link the recorder component
     #[arg(long)]
     pub use_host_recorder: bool,
+    #[command(flatten)]
+    pub fuzz: codegen::FuzzArgs,
+    #[command(flatten)]
+    pub faults: codegen::FaultArgs,
+    #[command(flatten)]
+    pub mock: codegen::MockArgs,
+    #[command(flatten)]
+    pub log: codegen::LogArgs,
+    #[command(flatten)]
+    pub profile: codegen::ProfileArgs,
+    #[command(flatten)]
+    pub policy: codegen::PolicyArgs,
+    #[command(flatten)]
+    pub assert: codegen::AssertArgs,
+    /// Instrument the core modules of the component with edge counters for coverage-guided fuzzing
+    #[arg(long)]
+    pub coverage: bool,
+    /// Only virtualize the imported interfaces matching the pattern, e.g. `wasi:http/*`. `*` matches any characters.
+    /// The other imports go straight to the host.
+    #[arg(long = "include")]
+    pub include_imports: Vec<String>,
+    /// Do not virtualize the imported interfaces matching the pattern. They go straight to the host.
+    #[arg(long = "exclude")]
+    pub exclude_imports: Vec<String>,
+    /// The WIT file or directory of the host interfaces in adapt mode, e.g. `wasi:http@0.2.6`.
+    /// The imports of the other versions of these packages are forwarded to them.
+    #[arg(long)]
+    pub to: Option<PathBuf>,
 }
 
 const DEBUG_WASM: &[u8] = include_bytes!("../assets/debug.wasm");
 const RECORDER_WASM: &[u8] = include_bytes!("../assets/recorder.wasm");
 
 pub fn run(args: InstrumentArgs) -> Result<()> {
-    if args.use_host_recorder && !matches!(args.mode, Mode::Record | Mode::Replay) {
-        anyhow::bail!("--use-host-recorder only works in record or replay mode");
+    if args.use_host_recorder && !matches!(args.mode, Mode::Record | Mode::Replay | Mode::Fuzz) {
+        anyhow::bail!("--use-host-recorder only works in record, replay or fuzz mode");
+    }
+    if args.coverage && !matches!(args.mode, Mode::Fuzz) {
+        anyhow::bail!("--coverage only works in fuzz mode");
+    }
+    if !args.fuzz.seed_traces.is_empty() && !matches!(args.mode, Mode::Fuzz) {
+        anyhow::bail!("--seed-trace only works in fuzz mode");
+    }
+    if args.faults.is_enabled() && !matches!(args.mode, Mode::Record) {
+        anyhow::bail!("fault injection only works in record mode");
+    }
+    anyhow::ensure!(
+        (0.0..=1.0).contains(&args.faults.fault_rate),
+        "--fault-rate must be between 0 and 1"
+    );
+    if (args.mock.rules.is_some() || !args.mock.traces.is_empty())
+        && !matches!(args.mode, Mode::Mock | Mode::Provider)
+    {
+        anyhow::bail!("--rules and --trace only work in mock or provider mode");
+    }
+    if args.mock.rules.is_none()
+        && args.mock.traces.is_empty()
+        && matches!(args.mode, Mode::Provider)
+    {
+        anyhow::bail!("provider mode requires --trace or --rules");
+    }
+    if (!args.log.log_include.is_empty() || !args.log.log_exclude.is_empty())
+        && !matches!(args.mode, Mode::Log)
+    {
+        anyhow::bail!("--log-include and --log-exclude only work in log mode");
+    }
+    if args.profile.profile_json && !matches!(args.mode, Mode::Profile) {
+        anyhow::bail!("--profile-json only works in profile mode");
     }
+    if args.policy.policy.is_some() && !matches!(args.mode, Mode::Policy) {
+        anyhow::bail!("--policy only works in policy mode");
+    }
+    if args.policy.policy.is_none() && matches!(args.mode, Mode::Policy) {
+        anyhow::bail!("policy mode requires --policy");
+    }
+    if args.assert.assert_rules.is_some() && !matches!(args.mode, Mode::Replay) {
+        anyhow::bail!("--assert-rules only works in replay mode");
+    }
+    if args.to.is_some() && !matches!(args.mode, Mode::Adapt) {
+        anyhow::bail!("--to only works in adapt mode");
+    }
+    if args.to.is_none() && matches!(args.mode, Mode::Adapt) {
+        anyhow::bail!("adapt mode requires --to");
+    }
+    if (!args.include_imports.is_empty() || !args.exclude_imports.is_empty())
+        && matches!(args.mode, Mode::Adapt)
+    {
+        anyhow::bail!("--include and --exclude do not work in adapt mode");
+    }
+    // The mock mode keeps the exports of the main component, so there is no exports component.
+    // The provider mode only builds the imports component.
+    let has_exports = !matches!(args.mode, Mode::Mock | Mode::Provider);
     // 1. Create a tmp directory and initialize a new Rust project in it.
-    let tmp_dir = init_rust_project()?;
+    let tmp_dir = init_rust_project(has_exports)?;
     let wit_dir = tmp_dir.join("wit");
 
     // 2. run `wasm-tools component wit {wasm_file from CLI} --out-dir {tmp_dir/wit}`
@@ -43,8 +126,16 @@ pub fn run(args: InstrumentArgs) -> Result<()> {
 
     // 3. Parse the main wit file from tmp_dir/wit and feed into opts.generate_component
     let (resolve, world) = parse_wit(&wit_dir, None)?;
-    let mut opts = crate::ast::Opt::new(&args);
+    let adapter = match &args.to {
+        Some(to) => Some(crate::adapt::Adapter::new(to, &wit_dir)?),
+        None => None,
+    };
+    let mut opts = crate::ast::Opt::new(&args, adapter.as_ref());
     opts.generate_wrapped_wits(&wit_dir)?;
+    // Replace the adapted packages with the host versions, after wrapping the old versions
+    if let Some(adapter) = &adapter {
+        adapter.write_packages(&wit_dir)?;
+    }
     let mut files = Files::default();
     opts.generate_component(&resolve, world, &mut files)?;
 
@@ -54,8 +145,10 @@ pub fn run(args: InstrumentArgs) -> Result<()> {
         std::fs::write(&path, content)?;
     }
     // Re-generate exports world to bring in extra imports
-    let (export_resolve, export_world) = parse_wit(&wit_dir, Some("tmp-exports"))?;
-    opts.generate_exports_world(&export_resolve, export_world, &mut files);
+    if has_exports {
+        let (export_resolve, export_world) = parse_wit(&wit_dir, Some("tmp-exports"))?;
+        opts.generate_exports_world(&export_resolve, export_world, &mut files)?;
+    }
     for (name, content) in files.iter() {
         let path = wit_dir.as_path().join(name);
         eprintln!("Generating: {}", path.display());
@@ -63,8 +156,33 @@ pub fn run(args: InstrumentArgs) -> Result<()> {
     }
 
     // 5. Generate Rust binding for both import and export interface
-    bindgen(&tmp_dir, &wit_dir, &args.mode, "imports", "record_imports")?;
-    bindgen(&tmp_dir, &wit_dir, &args.mode, "exports", "record_exports")?;
+    let header = match args.mode {
+        Mode::Record | Mode::Replay => Some(trace_header(
+            &args.wasm_file,
+            &resolve,
+            world,
+            opts.host_imports(),
+        )?),
+        _ => None,
+    };
+    bindgen(
+        &tmp_dir,
+        &wit_dir,
+        &args,
+        header.as_ref(),
+        "imports",
+        "record_imports",
+    )?;
+    if has_exports {
+        bindgen(
+            &tmp_dir,
+            &wit_dir,
+            &args,
+            header.as_ref(),
+            "exports",
+            "record_exports",
+        )?;
+    }
     // 6. cargo build
     let mut cmd = Command::new("cargo");
     cmd.arg("build")
@@ -73,16 +191,39 @@ pub fn run(args: InstrumentArgs) -> Result<()> {
     let status = cmd.status()?;
     assert!(status.success());
 
-    let exports_wasm_path =
-        component_new(&tmp_dir, &wit_dir, "exports", "debug/record_exports.wasm")?;
+    let exports_wasm_path = if has_exports {
+        Some(component_new(
+            &tmp_dir,
+            &wit_dir,
+            "exports",
+            "debug/record_exports.wasm",
+        )?)
+    } else {
+        None
+    };
     let imports_wasm_path =
         component_new(&tmp_dir, &wit_dir, "imports", "debug/record_imports.wasm")?;
+    if matches!(args.mode, Mode::Provider) {
+        // the imports component exports the imports of the main component, so it can be
+        // composed with other components as a fake backend
+        let output_file = "provider.wasm";
+        fs::copy(&imports_wasm_path, output_file)?;
+        eprintln!("Generated component: {output_file}");
+        return Ok(());
+    }
     // 7. run wac
-    opts.generate_wac(&imports_wasm_path, &exports_wasm_path, &wit_dir)?;
+    opts.generate_wac(&imports_wasm_path, exports_wasm_path.as_deref(), &wit_dir)?;
     let output_file = "composed.wasm";
     let imports = format!("import:proxy={}", imports_wasm_path.display());
-    let exports = format!("export:proxy={}", exports_wasm_path.display());
-    let root = format!("root:component={}", args.wasm_file.display());
+    let root_path = if args.coverage {
+        let wasm = fs::read(&args.wasm_file)?;
+        let path = tmp_dir.join("root.wasm");
+        fs::write(&path, crate::coverage::instrument(&wasm)?)?;
+        path
+    } else {
+        args.wasm_file.clone()
+    };
+    let root = format!("root:component={}", root_path.display());
     fs::write(tmp_dir.join("debug.wasm"), DEBUG_WASM)?;
     let debug = format!("import:debug={}/debug.wasm", tmp_dir.display());
     let wac_path = tmp_dir.join("wit/compose.wac");
@@ -91,14 +232,16 @@ pub fn run(args: InstrumentArgs) -> Result<()> {
         .arg("--dep")
         .arg(&imports)
         .arg("--dep")
-        .arg(&exports)
-        .arg("--dep")
         .arg(&debug)
         .arg("--dep")
         .arg(&root)
         .arg(&wac_path)
         .arg("-o")
         .arg(output_file);
+    if let Some(exports_wasm_path) = &exports_wasm_path {
+        let exports = format!("export:proxy={}", exports_wasm_path.display());
+        cmd.arg("--dep").arg(&exports);
+    }
     if !args.use_host_recorder {
         let wasm_path = tmp_dir.join("recorder.wasm");
         fs::write(&wasm_path, RECORDER_WASM)?;
@@ -122,10 +265,55 @@ fn parse_wit(dir: &Path, world: Option<&str>) -> Result<(Resolve, WorldId)> {
         .context("Failed to select a world from the parsed wit files")?;
     Ok((resolve, world))
 }
+/// The trace header of the component, with the WIT of the proxied imports and the exports.
+fn trace_header(
+    wasm_file: &Path,
+    resolve: &Resolve,
+    world: WorldId,
+    host_imports: &std::collections::BTreeSet<String>,
+) -> Result<trace::TraceHeader> {
+    use wit_parser::WorldItem;
+    let world_item = &resolve.worlds[world];
+    let imports = world_item.imports.iter().map(|item| ("import", item));
+    let exports = world_item.exports.iter().map(|item| ("export", item));
+    let mut wit = std::collections::BTreeMap::new();
+    for (kind, (key, item)) in imports.chain(exports) {
+        let WorldItem::Interface { id, .. } = item else {
+            continue;
+        };
+        let name = resolve.name_world_key(key);
+        if kind == "import" && host_imports.contains(&name) {
+            continue;
+        }
+        let mut printer = wit_component::WitPrinter::default();
+        printer.emit_docs(false);
+        printer.print_interface(resolve, *id)?;
+        wit.insert(format!("{kind} {name}"), printer.output.to_string());
+    }
+    // FNV-1a, which is enough to tell the binaries apart
+    let hash = fs::read(wasm_file)?
+        .iter()
+        .fold(0xcbf29ce484222325u64, |hash, byte| {
+            (hash ^ *byte as u64).wrapping_mul(0x100000001b3)
+        });
+    let world_name = match resolve.worlds[world].package {
+        Some(pkg) => format!("{}/{}", resolve.packages[pkg].name, world_item.name),
+        None => world_item.name.clone(),
+    };
+    Ok(trace::TraceHeader {
+        version: trace::SCHEMA_VERSION,
+        component: format!("{hash:016x}"),
+        world: world_name,
+        wit,
+        time: 0,
+        tool: env!("CARGO_PKG_VERSION").to_string(),
+    })
+}
 fn bindgen(
     tmp_dir: &Path,
     wit_dir: &Path,
-    mode: &Mode,
+    args: &InstrumentArgs,
+    header: Option<&trace::TraceHeader>,
     world_name: &str,
     dest_name: &str,
 ) -> Result<()> {
@@ -144,16 +332,29 @@ fn bindgen(
         .status()?;
     assert!(status.success());
     let binding_file = out_dir.join(world_name.to_owned() + ".rs");
-    let codegen_mode = match mode {
+    let codegen_mode = match args.mode {
         Mode::Record => codegen::GenerateMode::Record,
         Mode::Replay => codegen::GenerateMode::Replay,
         Mode::Fuzz => codegen::GenerateMode::Fuzz,
         Mode::Dialog => codegen::GenerateMode::Dialog,
+        Mode::Mock | Mode::Provider => codegen::GenerateMode::Mock,
+        Mode::Log => codegen::GenerateMode::Log,
+        Mode::Profile => codegen::GenerateMode::Profile,
+        Mode::Policy => codegen::GenerateMode::Policy,
+        Mode::Adapt => codegen::GenerateMode::Instrument,
     };
     let codegen_opt = codegen::GenerateArgs {
         bindings: binding_file.clone(),
         output_file: out_dir.join("lib.rs"),
         mode: codegen_mode,
+        fuzz: args.fuzz.clone(),
+        faults: args.faults.clone(),
+        mock: args.mock.clone(),
+        log: args.log.clone(),
+        profile: args.profile.clone(),
+        policy: args.policy.clone(),
+        assert: args.assert.clone(),
+        header: header.cloned(),
     };
     codegen_opt.generate()?;
     let status = Command::new("mv")
@@ -194,7 +395,7 @@ fn component_new(
     assert!(status.success());
     Ok(wasm_path)
 }
-fn init_rust_project() -> Result<PathBuf> {
+fn init_rust_project(has_exports: bool) -> Result<PathBuf> {
     /*let tmp_dir = tempfile::Builder::new()
     .prefix("proxy-component-")
     .disable_cleanup(true)
@@ -205,25 +406,28 @@ fn init_rust_project() -> Result<PathBuf> {
         fs::remove_dir_all(&tmp_dir)?;
     }
     fs::create_dir_all(&tmp_dir)?;
-    fs::write(
-        tmp_dir.join("Cargo.toml"),
-        include_str!("../assets/workspace_cargo.toml"),
-    )?;
+    let mut workspace = include_str!("../assets/workspace_cargo.toml").to_string();
+    if !has_exports {
+        workspace = workspace.replace(", \"record_exports\"", "");
+    }
+    fs::write(tmp_dir.join("Cargo.toml"), workspace)?;
 
     let wit_dir = tmp_dir.join("wit");
     let import_src_dir = tmp_dir.join("record_imports");
     let export_src_dir = tmp_dir.join("record_exports");
     fs::create_dir_all(&wit_dir)?;
     fs::create_dir_all(&import_src_dir)?;
-    fs::create_dir_all(&export_src_dir)?;
     let toml = include_str!("../assets/proj_cargo.toml");
     fs::write(
         import_src_dir.join("Cargo.toml"),
         toml.replace("{proj_name}", "record_imports"),
     )?;
-    fs::write(
-        export_src_dir.join("Cargo.toml"),
-        toml.replace("{proj_name}", "record_exports"),
-    )?;
+    if has_exports {
+        fs::create_dir_all(&export_src_dir)?;
+        fs::write(
+            export_src_dir.join("Cargo.toml"),
+            toml.replace("{proj_name}", "record_exports"),
+        )?;
+    }
     Ok(tmp_dir)
 }
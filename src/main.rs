@@ -1,7 +1,9 @@
 use clap::{Parser, ValueEnum};
 
+mod adapt;
 mod ast;
 mod codegen;
+mod coverage;
 mod instrument;
 mod traits;
 mod util;
@@ -21,6 +23,12 @@ pub enum Mode {
     Replay,
     Fuzz,
     Dialog,
+    Mock,
+    Log,
+    Profile,
+    Policy,
+    Adapt,
+    Provider,
 }
 
 #[derive(Parser)]
@@ -51,6 +59,19 @@ impl Mode {
             Mode::Replay => "replay",
             Mode::Fuzz => "fuzz",
             Mode::Dialog => "dialog",
+            Mode::Mock => "mock",
+            Mode::Log => "log",
+            Mode::Profile => "profile",
+            Mode::Policy => "policy",
+            Mode::Adapt => "adapt",
+            Mode::Provider => "provider",
         }
     }
+    /// Whether the imports and exports are wrapped and forwarded to the original ones.
+    fn is_instrument(&self) -> bool {
+        matches!(
+            self,
+            Mode::Record | Mode::Log | Mode::Profile | Mode::Policy | Mode::Adapt
+        )
+    }
 }
@@ -1,11 +1,14 @@
 use clap::Parser;
+use std::collections::{BTreeMap, HashSet};
+use std::hash::{Hash, Hasher};
 use std::path::PathBuf;
-use trace::Logger;
+use trace::{ContractCall, Divergence, Format, Logger};
 use wasmtime::component::types::{ComponentFunc, ComponentItem as CItem};
 use wasmtime::component::wasm_wave::{untyped::UntypedFuncCall, wasm::WasmFunc};
 use wasmtime::component::{Component, HasSelf, Linker, ResourceTable, Val};
 use wasmtime::*;
-use wasmtime_wasi::{WasiCtx, WasiCtxBuilder, WasiCtxView, WasiView, p2::add_to_linker_sync};
+use wasmtime_wasi::p2::{add_to_linker_async, pipe::MemoryOutputPipe};
+use wasmtime_wasi::{I32Exit, WasiCtx, WasiCtxBuilder, WasiCtxView, WasiView};
 
 #[derive(Parser)]
 pub struct RunArgs {
@@ -17,12 +20,49 @@ pub struct RunArgs {
     /// Replay a trace file
     #[arg(short, long)]
     trace: Option<PathBuf>,
+    /// The format of the recorded trace: json, binary, or zstd for compressed binary. The format of a
+    /// replayed trace is detected from the file.
+    #[arg(long, default_value = "json")]
+    trace_format: Format,
+    /// How the replayed import calls are matched with the trace, e.g. `fifo,wasi:clocks/*=skip`:
+    /// strict, fifo, args or skip, with the policies of interfaces.
+    #[arg(long = "match")]
+    matching: Option<String>,
+    /// Run a fuzz component repeatedly with different seeds. Invokes `start()` by default.
+    #[arg(long)]
+    fuzz: bool,
+    /// The number of fuzz runs
+    #[arg(long, default_value_t = 100)]
+    runs: u64,
+    /// The seed of the first fuzz run
+    #[arg(long, default_value_t = 0)]
+    seed: u64,
+    /// The fuel limit of each fuzz run. Runs that exceed it are reported as hangs.
+    #[arg(long, default_value_t = 10_000_000_000)]
+    fuel: u64,
+    /// The directory to save the crashing inputs, and the queue of coverage-guided fuzzing
+    #[arg(long, default_value = "crashes")]
+    corpus: PathBuf,
+    /// The maximum input size of coverage-guided fuzzing
+    #[arg(long, default_value_t = 1024)]
+    max_len: usize,
+    /// Verify a provider against the trace of a consumer given by `--trace`. The recorded import calls of
+    /// the interface, e.g. `my:svc/api`, are replayed as export calls, and the returns are compared.
+    #[arg(long)]
+    contract: Vec<String>,
+    /// Write the contract report as JSON to the file
+    #[arg(long)]
+    contract_json: Option<PathBuf>,
+    /// Write the divergence report as JSON to the file, when the replay does not match the trace
+    #[arg(long)]
+    divergence_json: Option<PathBuf>,
 }
 
 mod bindings {
     wasmtime::component::bindgen!({
         path: "assets/recorder.wit",
         world: "host",
+        imports: { "proxy:recorder/record.record-header": trappable },
     });
 }
 
@@ -31,40 +71,140 @@ pub struct State {
     resource_table: ResourceTable,
     logger: Logger,
     exit_called: bool,
+    label_id: u32,
+    verbose: bool,
+    // edges reached by the current run, see crate::coverage
+    edges: HashSet<u32>,
+    // the replayed calls in contract verification, and the current call
+    contract: Option<Vec<ContractCall>>,
+    export_call: Option<(String, Vec<String>)>,
+    // where the replay diverges from the trace
+    divergence: Option<Box<Divergence>>,
 }
 impl bindings::proxy::recorder::record::Host for State {
     fn record_args(&mut self, method: Option<String>, args: Vec<String>, is_export: bool) {
         let call = self.logger.record_args(method, args, is_export);
-        println!("call: {}", call.to_string());
+        if self.verbose {
+            println!("call: {}", call.to_string());
+        }
     }
     fn record_ret(&mut self, method: Option<String>, ret: Option<String>, is_export: bool) {
         let call = self.logger.record_ret(method, ret, is_export);
-        println!("ret: {}", call.to_string());
+        if self.verbose {
+            println!("ret: {}", call.to_string());
+        }
+    }
+    fn new_label(&mut self, kind: String) -> String {
+        let id = self.label_id;
+        self.label_id += 1;
+        format!("{kind}-{id}")
+    }
+    fn record_stream(&mut self, label: String, values: Option<Vec<String>>) {
+        let call = self.logger.record_stream(label, values);
+        if self.verbose {
+            println!("stream: {}", call.to_string());
+        }
+    }
+    fn record_header(&mut self, header: String) -> Result<()> {
+        let header = serde_json::from_str(&header)
+            .map_err(|err| Error::msg(format!("invalid trace header: {err}")))?;
+        if let Some(call) = self.logger.record_header(header)
+            && self.verbose
+        {
+            println!("header: {}", call.to_string());
+        }
+        Ok(())
     }
 }
 impl bindings::proxy::recorder::replay::Host for State {
-    fn replay_export(&mut self) -> Option<(String, Vec<String>)> {
-        self.logger.replay_export()
+    fn replay_export(&mut self) -> Result<Option<(String, Vec<String>)>, String> {
+        let call = self
+            .logger
+            .replay_export()
+            .map_err(|err| self.diverged(err))?;
+        if self.contract.is_some() {
+            self.export_call = call.clone();
+        }
+        Ok(call)
+    }
+    fn replay_export_ret(&mut self) -> Option<String> {
+        self.logger.replay_export_ret()
     }
-    fn assert_export_ret(&mut self, assert_method: Option<String>, assert_ret: Option<String>) {
-        self.logger.assert_export_ret(assert_method, assert_ret);
+    fn assert_export_ret(
+        &mut self,
+        assert_method: Option<String>,
+        assert_ret: Option<String>,
+    ) -> Result<(), String> {
+        let Some(calls) = &mut self.contract else {
+            return self
+                .logger
+                .assert_export_ret(assert_method, assert_ret)
+                .map_err(|err| self.diverged(err));
+        };
+        // the mismatches are reported after the run, instead of stopping at the first one
+        if let Some(expected) = self.logger.pop_export_ret()
+            && let Some((method, args)) = self.export_call.take()
+        {
+            calls.push(ContractCall {
+                method,
+                args,
+                expected,
+                actual: assert_ret,
+            });
+        }
+        Ok(())
     }
     fn replay_import(
         &mut self,
         assert_method: Option<String>,
         assert_args: Option<Vec<String>>,
-    ) -> Option<String> {
-        let (exit_called, ret) = self.logger.replay_import(assert_method, assert_args, false);
+    ) -> Result<Option<String>, String> {
+        let (exit_called, ret) = self
+            .logger
+            .replay_import(assert_method, assert_args, false)
+            .map_err(|err| self.diverged(err))?;
         if exit_called {
             self.exit_called = exit_called;
-            return Some("Something that can crash".to_string());
+            return Ok(Some("Something that can crash".to_string()));
+        }
+        Ok(ret)
+    }
+    fn replay_stream(&mut self, label: String) -> Option<Vec<String>> {
+        self.logger.replay_stream(&label)
+    }
+    fn check_header(&mut self, header: String) -> Option<String> {
+        match serde_json::from_str(&header) {
+            Ok(header) => self.logger.check_header(&header),
+            Err(err) => Some(format!("invalid trace header: {err}")),
         }
-        ret
     }
 }
 
 const MAX_FUEL: u64 = u64::MAX;
 
+impl State {
+    /// Keep the divergence for `--divergence-json`, and report it to the guest.
+    fn diverged(&mut self, divergence: Box<Divergence>) -> String {
+        let report = divergence.to_string();
+        self.divergence = Some(divergence);
+        report
+    }
+    fn new(wasi_ctx: WasiCtx, verbose: bool) -> Self {
+        State {
+            wasi_ctx,
+            resource_table: ResourceTable::new(),
+            logger: Logger::new(),
+            exit_called: false,
+            label_id: 0,
+            verbose,
+            edges: HashSet::new(),
+            contract: None,
+            export_call: None,
+            divergence: None,
+        }
+    }
+}
+
 pub fn run(args: RunArgs) -> anyhow::Result<()> {
     // Patch ctrlc until https://github.com/console-rs/dialoguer/issues/77 is fixed
     let _ = ctrlc::try_set_handler(move || {
@@ -72,63 +212,72 @@ pub fn run(args: RunArgs) -> anyhow::Result<()> {
         let _ = term.show_cursor();
     });
     let mut config = Config::new();
+    // Async store to drive component-model async functions, streams and futures
     config
+        .async_support(true)
+        .wasm_component_model_async(true)
         .consume_fuel(true)
         //.debug_info(true)
         .wasm_backtrace_details(WasmBacktraceDetails::Enable);
     let engine = Engine::new(&config)?;
 
     let mut linker = Linker::<State>::new(&engine);
-    add_to_linker_sync(&mut linker)?;
-    let wasi = WasiCtxBuilder::new().inherit_stdio().inherit_args().build();
-    let mut state = State {
-        wasi_ctx: wasi,
-        resource_table: ResourceTable::new(),
-        logger: Logger::new(),
-        exit_called: false,
-    };
+    add_to_linker_async(&mut linker)?;
     dialog_bindings::proxy::util::dialog::add_to_linker::<State, HasSelf<State>>(
         &mut linker,
         |state| state,
     )?;
-    if let Some(path) = &args.trace {
+    coverage_bindings::proxy::util::coverage::add_to_linker::<State, HasSelf<State>>(
+        &mut linker,
+        |state| state,
+    )?;
+    if args.trace.is_some() {
         bindings::proxy::recorder::replay::add_to_linker::<_, HasSelf<_>>(&mut linker, |state| {
             state
         })?;
-        let trace = std::fs::read_to_string(path)?;
-        state.logger.load_trace(&trace);
     } else {
         bindings::proxy::recorder::record::add_to_linker::<State, HasSelf<State>>(
             &mut linker,
             |state| state,
         )?;
     }
+    let component = Component::from_file(&engine, &args.wasm_file)?;
+    if args.fuzz {
+        anyhow::ensure!(args.trace.is_none(), "--fuzz cannot be used with --trace");
+        let invoke = args.invoke.as_deref().unwrap_or("start()");
+        return fuzz(&args, &engine, &linker, &component, invoke);
+    }
 
+    let wasi = WasiCtxBuilder::new()
+        .inherit_stdio()
+        .inherit_args()
+        .env("PROXY_TRACE_FORMAT", args.trace_format.to_string())
+        .env(
+            "PROXY_REPLAY_MATCH",
+            args.matching.as_deref().unwrap_or_default(),
+        )
+        .build();
+    let mut state = State::new(wasi, true);
+    if let Some(path) = &args.trace {
+        let trace = std::fs::read(path)?;
+        state
+            .logger
+            .load(&trace)
+            .map_err(|err| anyhow::anyhow!("{}: {err}", path.display()))?;
+    }
+    if let Some(matching) = &args.matching {
+        let matching = matching.parse().map_err(anyhow::Error::msg)?;
+        state.logger.set_matching(matching);
+    }
+    if !args.contract.is_empty() {
+        anyhow::ensure!(args.trace.is_some(), "--contract requires --trace");
+        state.logger.contract_trace(&args.contract);
+        state.contract = Some(Vec::new());
+    }
     let mut store = Store::new(&engine, state);
     store.set_fuel(MAX_FUEL)?;
-    let component = Component::from_file(&engine, args.wasm_file)?;
     if let Some(invoke) = &args.invoke {
-        let untyped_call = UntypedFuncCall::parse(invoke)?;
-        let exports = collect_export_funcs(&engine, &component);
-        //println!("Exported funcs: {exports:?}");
-        let mut find_export = exports.into_iter().filter_map(|(names, func)| {
-            let func_name = names.last().unwrap();
-            (func_name == untyped_call.name()).then_some((names, func))
-        });
-        let (names, func_type) = &find_export.next().unwrap();
-        let export = names
-            .iter()
-            .fold(None, |instance, name| {
-                component.get_export_index(instance.as_ref(), name)
-            })
-            .unwrap();
-        let instance = linker.instantiate(&mut store, &component)?;
-
-        let param_types = WasmFunc::params(func_type).collect::<Vec<_>>();
-        let params = untyped_call.to_wasm_params(&param_types)?;
-        let func = instance.get_func(&mut store, export).unwrap();
-        let mut results = vec![Val::Bool(false); func_type.results().len()];
-        match func.call(&mut store, &params, &mut results) {
+        let res = match call_export(&mut store, &linker, &component, invoke) {
             Ok(_) => Ok(()),
             Err(e) => {
                 if store.data().exit_called {
@@ -137,9 +286,17 @@ pub fn run(args: RunArgs) -> anyhow::Result<()> {
                     Err(e)
                 }
             }
-        }?;
+        };
+        if let (Some(divergence), Some(path)) = (&store.data().divergence, &args.divergence_json) {
+            std::fs::write(path, serde_json::to_string_pretty(divergence)?)?;
+        }
+        if let Some(calls) = &store.data().contract {
+            let remaining = store.data().logger.0.len();
+            report_contract(&args, calls, remaining, res.is_err())?;
+        }
+        res?;
         if args.trace.is_none() {
-            let trace = store.data().logger.dump_trace();
+            let trace = store.data().logger.dump(args.trace_format);
             std::fs::write("trace.out", &trace)?;
         }
     }
@@ -148,6 +305,252 @@ pub fn run(args: RunArgs) -> anyhow::Result<()> {
     Ok(())
 }
 
+/// Print the replayed calls that do not match the trace, and fail if there is any.
+fn report_contract(
+    args: &RunArgs,
+    calls: &[ContractCall],
+    remaining: usize,
+    has_error: bool,
+) -> anyhow::Result<()> {
+    let failed: Vec<_> = calls
+        .iter()
+        .enumerate()
+        .filter(|(_, call)| !call.passed())
+        .collect();
+    for (idx, call) in &failed {
+        println!(
+            "FAIL #{} {}({}): expected {}, got {}",
+            idx + 1,
+            call.method,
+            call.args.join(", "),
+            call.expected.as_deref().unwrap_or("()"),
+            call.actual.as_deref().unwrap_or("()")
+        );
+    }
+    println!(
+        "Contract {}: {} of {} calls passed",
+        args.contract.join(", "),
+        calls.len() - failed.len(),
+        calls.len()
+    );
+    if has_error {
+        println!("The provider stopped with {remaining} recorded events left.");
+    }
+    if let Some(path) = &args.contract_json {
+        std::fs::write(path, serde_json::to_string_pretty(calls)?)?;
+    }
+    anyhow::ensure!(failed.is_empty(), "the provider breaks the contract");
+    Ok(())
+}
+
+/// Instantiate the component in `store` and call the exported function in `invoke`.
+fn call_export(
+    store: &mut Store<State>,
+    linker: &Linker<State>,
+    component: &Component,
+    invoke: &str,
+) -> wasmtime::Result<()> {
+    let untyped_call = UntypedFuncCall::parse(invoke)?;
+    let exports = collect_export_funcs(store.engine(), component);
+    //println!("Exported funcs: {exports:?}");
+    let mut find_export = exports.into_iter().filter_map(|(names, func)| {
+        let func_name = names.last().unwrap();
+        (func_name == untyped_call.name()).then_some((names, func))
+    });
+    let (names, func_type) = &find_export.next().unwrap();
+    let export = names
+        .iter()
+        .fold(None, |instance, name| {
+            component.get_export_index(instance.as_ref(), name)
+        })
+        .unwrap();
+    let instance =
+        wasmtime_wasi::runtime::in_tokio(linker.instantiate_async(&mut *store, component))?;
+
+    let param_types = WasmFunc::params(func_type).collect::<Vec<_>>();
+    let params = untyped_call.to_wasm_params(&param_types)?;
+    let func = instance.get_func(&mut *store, export).unwrap();
+    let mut results = vec![Val::Bool(false); func_type.results().len()];
+    wasmtime_wasi::runtime::in_tokio(func.call_async(&mut *store, &params, &mut results))
+}
+
+/// Call `invoke` on a fresh instance for each seed. The fuzz component reads the seed from
+/// `PROXY_FUZZ_SEED`, see the debug component. Crashes are de-duplicated by the backtrace, and
+/// the first crash of each backtrace is saved in the corpus directory.
+///
+/// When the root component is instrumented with `--coverage`, the inputs are passed in
+/// `PROXY_FUZZ_INPUT` instead, and the inputs that reach new edges are kept and mutated.
+fn fuzz(
+    args: &RunArgs,
+    engine: &Engine,
+    linker: &Linker<State>,
+    component: &Component,
+    invoke: &str,
+) -> anyhow::Result<()> {
+    let guided = component
+        .component_type()
+        .imports(engine)
+        .any(|(name, _)| name == crate::coverage::COVERAGE_INTERFACE);
+    if guided {
+        println!("Coverage-guided fuzzing");
+        std::fs::create_dir_all(args.corpus.join("queue"))?;
+    }
+    std::fs::create_dir_all(&args.corpus)?;
+    let mut rng = Rng(args.seed);
+    let mut queue: Vec<Vec<u8>> = Vec::new();
+    let mut coverage: HashSet<u32> = HashSet::new();
+    let mut crashes: BTreeMap<u64, u32> = BTreeMap::new();
+    for seed in args.seed..args.seed + args.runs {
+        let stdout = MemoryOutputPipe::new(1 << 24);
+        let stderr = MemoryOutputPipe::new(1 << 20);
+        let mut wasi = WasiCtxBuilder::new();
+        wasi.inherit_args()
+            .stdout(stdout.clone())
+            .stderr(stderr.clone())
+            .env("PROXY_TRACE_FORMAT", args.trace_format.to_string());
+        let input = if guided {
+            let input = next_input(&mut rng, &queue, args.max_len);
+            wasi.env("PROXY_FUZZ_INPUT", to_hex(&input));
+            Some(input)
+        } else {
+            wasi.env("PROXY_FUZZ_SEED", seed.to_string());
+            None
+        };
+        let mut store = Store::new(engine, State::new(wasi.build(), false));
+        store.set_fuel(args.fuel)?;
+        let result = call_export(&mut store, linker, component, invoke);
+        if let Some(input) = &input {
+            let edges = &store.data().edges;
+            let new_edges = edges.difference(&coverage).count();
+            if new_edges > 0 {
+                coverage.extend(edges);
+                let path = args.corpus.join(format!("queue/input-{}", queue.len()));
+                std::fs::write(path, to_hex(input))?;
+                queue.push(input.clone());
+                println!(
+                    "run {seed}: {new_edges} new edges, {} edges, {} inputs in queue",
+                    coverage.len(),
+                    queue.len()
+                );
+            }
+        }
+        let Err(e) = result else {
+            continue;
+        };
+        if e.downcast_ref::<I32Exit>().is_some_and(|exit| exit.0 == 0) {
+            continue;
+        }
+        let kind = match e.downcast_ref::<Trap>() {
+            Some(Trap::OutOfFuel) => "hang".to_string(),
+            Some(trap) => trap.to_string(),
+            None => "error".to_string(),
+        };
+        // the frames identify the crash site, regardless of the input
+        let mut hasher = std::collections::hash_map::DefaultHasher::new();
+        kind.hash(&mut hasher);
+        match e.downcast_ref::<WasmBacktrace>() {
+            Some(backtrace) => {
+                for frame in backtrace.frames() {
+                    frame.module().name().hash(&mut hasher);
+                    frame.func_index().hash(&mut hasher);
+                    frame.func_offset().hash(&mut hasher);
+                }
+            }
+            None => e.to_string().hash(&mut hasher),
+        }
+        let id = hasher.finish();
+        let count = crashes.entry(id).or_default();
+        *count += 1;
+        if *count > 1 {
+            continue;
+        }
+        let dir = args.corpus.join(format!("crash-{id:016x}"));
+        std::fs::create_dir_all(&dir)?;
+        match &input {
+            Some(input) => std::fs::write(dir.join("input"), to_hex(input))?,
+            None => std::fs::write(dir.join("seed"), seed.to_string())?,
+        }
+        std::fs::write(dir.join("backtrace.txt"), format!("{kind}\n{e:?}\n"))?;
+        std::fs::write(dir.join("stderr.txt"), stderr.contents())?;
+        // the guest recorder prints the trace to stdout
+        let trace = store.data().logger.dump(args.trace_format);
+        if store.data().logger.0.is_empty() {
+            std::fs::write(dir.join("trace.out"), stdout.contents())?;
+        } else {
+            std::fs::write(dir.join("trace.out"), trace)?;
+        }
+        println!("run {seed}: {kind}, saved to {}", dir.display());
+    }
+    let total: u32 = crashes.values().sum();
+    println!(
+        "{} runs, {total} crashes, {} unique crashes",
+        args.runs,
+        crashes.len()
+    );
+    Ok(())
+}
+
+/// splitmix64, the same generator as the debug component
+struct Rng(u64);
+impl Rng {
+    fn next(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e3779b97f4a7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+        z ^ (z >> 31)
+    }
+    fn below(&mut self, n: usize) -> usize {
+        (self.next() % n as u64) as usize
+    }
+}
+
+/// Mutate an input from the queue with a few libFuzzer-style mutations, or occasionally
+/// start from a fresh random input.
+fn next_input(rng: &mut Rng, queue: &[Vec<u8>], max_len: usize) -> Vec<u8> {
+    if queue.is_empty() || rng.below(10) == 0 {
+        return (0..max_len).map(|_| rng.next() as u8).collect();
+    }
+    let mut input = queue[rng.below(queue.len())].clone();
+    for _ in 0..1 + rng.below(4) {
+        let len = input.len();
+        match rng.below(6) {
+            // flip a bit
+            0 if len > 0 => input[rng.below(len)] ^= 1 << rng.below(8),
+            // change a byte
+            1 if len > 0 => input[rng.below(len)] = rng.next() as u8,
+            // insert a byte
+            2 if len < max_len => input.insert(rng.below(len + 1), rng.next() as u8),
+            // erase a byte
+            3 if len > 0 => {
+                input.remove(rng.below(len));
+            }
+            // copy a chunk within the input
+            4 if len > 0 => {
+                let (src, dst) = (rng.below(len), rng.below(len));
+                let n = 1 + rng.below(len - src.max(dst));
+                input.copy_within(src..src + n, dst);
+            }
+            // cross over with another input
+            5 => {
+                let other = &queue[rng.below(queue.len())];
+                if !other.is_empty() {
+                    let from = rng.below(other.len());
+                    input.truncate(rng.below(len + 1));
+                    input.extend_from_slice(&other[from..]);
+                    input.truncate(max_len);
+                }
+            }
+            _ => (),
+        }
+    }
+    input
+}
+
+fn to_hex(input: &[u8]) -> String {
+    input.iter().map(|b| format!("{b:02x}")).collect()
+}
+
 fn collect_exports(
     engine: &Engine,
     item: CItem,
@@ -201,6 +604,19 @@ impl WasiView for State {
     }
 }
 
+mod coverage_bindings {
+    wasmtime::component::bindgen!({
+        path: "assets/util.wit",
+        world: "host-coverage",
+    });
+}
+
+impl coverage_bindings::proxy::util::coverage::Host for State {
+    fn edge(&mut self, id: u32) {
+        self.edges.insert(id);
+    }
+}
+
 mod dialog_bindings {
     wasmtime::component::bindgen!({
         path: "assets/util.wit",
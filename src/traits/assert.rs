@@ -0,0 +1,135 @@
+use crate::codegen::ItemFlag;
+use crate::traits::Trait;
+use syn::{Item, ItemEnum, ItemStruct, parse_quote};
+
+/// Runtime support for the assertion rules of the replayed export results, see
+/// `codegen::assert::Rules`.
+pub struct AssertTrait;
+
+impl Trait for AssertTrait {
+    fn resource_trait(&self, _module_path: &[String], _item: &ItemStruct) -> Vec<Item> {
+        Vec::new()
+    }
+    fn struct_trait(&self, _module_path: &[String], _item: &ItemStruct) -> Vec<Item> {
+        Vec::new()
+    }
+    fn enum_trait(&self, _module_path: &[String], _item: &ItemEnum) -> Vec<Item> {
+        Vec::new()
+    }
+    fn flag_trait(&self, _module_path: &[String], _item: &ItemFlag) -> Vec<Item> {
+        Vec::new()
+    }
+    fn trait_defs(&self) -> Vec<Item> {
+        let ast: syn::File = parse_quote! {
+          thread_local! {
+              // The compiled regexes of the rules, by their patterns
+              static ASSERT_REGEXES: std::cell::RefCell<std::collections::BTreeMap<&'static str, regex_lite::Regex>> =
+                  const { std::cell::RefCell::new(std::collections::BTreeMap::new()) };
+          }
+          #[allow(dead_code)]
+          struct AssertRule {
+              ignore: &'static [&'static str],
+              float_tolerance: f64,
+              // The paths and their anchored regexes
+              regex: &'static [(&'static str, &'static str)],
+              variant_only: bool,
+          }
+          #[allow(dead_code)]
+          fn assert_path_match(pattern: &str, path: &str) -> bool {
+              let mut parts = pattern.split('*');
+              let Some(mut rest) = path.strip_prefix(parts.next().unwrap()) else {
+                  return false;
+              };
+              let mut parts: Vec<_> = parts.collect();
+              let Some(last) = parts.pop() else {
+                  return rest.is_empty();
+              };
+              for part in parts {
+                  match rest.find(part) {
+                      Some(idx) => rest = &rest[idx + part.len()..],
+                      None => return false,
+                  }
+              }
+              rest.ends_with(last)
+          }
+          // Compare the recorded and the live values of the same type under the rule.
+          #[allow(dead_code)]
+          fn assert_value(rule: &AssertRule, path: &str, recorded: &Value, live: &Value) -> bool {
+              use wasm_wave::wasm::WasmTypeKind;
+              if rule.ignore.iter().any(|pattern| assert_path_match(pattern, path)) {
+                  return true;
+              }
+              if let Some((_, regex)) = rule.regex.iter().find(|(pattern, _)| assert_path_match(pattern, path)) {
+                  return live.kind() == WasmTypeKind::String
+                      && ASSERT_REGEXES.with_borrow_mut(|regexes| {
+                          regexes
+                              .entry(*regex)
+                              .or_insert_with(|| regex_lite::Regex::new(regex).unwrap())
+                              .is_match(&live.unwrap_string())
+                      });
+              }
+              match live.kind() {
+                  WasmTypeKind::F32 => {
+                      let (recorded, live) = (recorded.unwrap_f32() as f64, live.unwrap_f32() as f64);
+                      (recorded.is_nan() && live.is_nan()) || (recorded - live).abs() <= rule.float_tolerance
+                  }
+                  WasmTypeKind::F64 => {
+                      let (recorded, live) = (recorded.unwrap_f64(), live.unwrap_f64());
+                      (recorded.is_nan() && live.is_nan()) || (recorded - live).abs() <= rule.float_tolerance
+                  }
+                  WasmTypeKind::List => {
+                      recorded.unwrap_list().count() == live.unwrap_list().count()
+                          && recorded
+                              .unwrap_list()
+                              .zip(live.unwrap_list())
+                              .enumerate()
+                              .all(|(idx, (r, l))| assert_value(rule, &format!("{path}[{idx}]"), &r, &l))
+                  }
+                  WasmTypeKind::Tuple => recorded
+                      .unwrap_tuple()
+                      .zip(live.unwrap_tuple())
+                      .enumerate()
+                      .all(|(idx, (r, l))| assert_value(rule, &format!("{path}[{idx}]"), &r, &l)),
+                  WasmTypeKind::Record => recorded
+                      .unwrap_record()
+                      .zip(live.unwrap_record())
+                      .all(|((name, r), (_, l))| assert_value(rule, &format!("{path}.{name}"), &r, &l)),
+                  WasmTypeKind::Option => match (recorded.unwrap_option(), live.unwrap_option()) {
+                      (Some(r), Some(l)) => {
+                          rule.variant_only || assert_value(rule, &format!("{path}.some"), &r, &l)
+                      }
+                      (None, None) => true,
+                      _ => false,
+                  },
+                  WasmTypeKind::Result => match (recorded.unwrap_result(), live.unwrap_result()) {
+                      (Ok(r), Ok(l)) => match (r, l) {
+                          (Some(r), Some(l)) => {
+                              rule.variant_only || assert_value(rule, &format!("{path}.ok"), &r, &l)
+                          }
+                          _ => true,
+                      },
+                      (Err(r), Err(l)) => match (r, l) {
+                          (Some(r), Some(l)) => {
+                              rule.variant_only || assert_value(rule, &format!("{path}.err"), &r, &l)
+                          }
+                          _ => true,
+                      },
+                      _ => false,
+                  },
+                  WasmTypeKind::Variant => {
+                      let ((r_case, r), (l_case, l)) = (recorded.unwrap_variant(), live.unwrap_variant());
+                      r_case == l_case
+                          && match (r, l) {
+                              (Some(r), Some(l)) => {
+                                  rule.variant_only || assert_value(rule, &format!("{path}.{l_case}"), &r, &l)
+                              }
+                              _ => true,
+                          }
+                  }
+                  _ => wasm_wave::to_string(recorded).unwrap() == wasm_wave::to_string(live).unwrap(),
+              }
+          }
+        };
+        ast.items
+    }
+}
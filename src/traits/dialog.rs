@@ -1,12 +1,15 @@
+use crate::codegen::State;
 use crate::traits::Trait;
 use crate::util::{make_path, wit_func_name};
 use heck::{ToKebabCase, ToSnakeCase};
 use quote::quote;
 use syn::{Item, ItemEnum, ItemStruct, parse_quote};
 
-pub struct DialogTrait;
+pub struct DialogTrait<'a> {
+    pub state: &'a State,
+}
 
-impl Trait for DialogTrait {
+impl Trait for DialogTrait<'_> {
     fn resource_trait(&self, module_path: &[String], resource: &ItemStruct) -> Vec<Item> {
         let mut res = Vec::new();
         let resource_path = make_path(module_path, &resource.ident.to_string());
@@ -20,6 +23,27 @@ impl Trait for DialogTrait {
             )
             .to_snake_case();
             let call: syn::Ident = syn::parse_str(&call).unwrap();
+            if !self
+                .state
+                .has_mock_conversion(module_path, &resource.ident.to_string())
+            {
+                // Resources of the exported interfaces cannot be mocked
+                res.push(parse_quote! {
+                impl Dialog for #resource_path {
+                    fn read_value(_dep: u32) -> Self {
+                        panic!("cannot provide {} in dialog mode: the resources of the exported interfaces are only created by the component itself", #wit_name)
+                    }
+                }
+                });
+                res.push(parse_quote! {
+                impl<'a> Dialog for &'a #resource_path {
+                    fn read_value(_dep: u32) -> Self {
+                        panic!("cannot provide {} in dialog mode: the resources of the exported interfaces are only created by the component itself", #wit_name)
+                    }
+                }
+                });
+                return res;
+            }
             res.push(parse_quote! {
             impl Dialog for #resource_path {
                 fn read_value(_dep: u32) -> Self {
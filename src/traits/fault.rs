@@ -0,0 +1,83 @@
+use crate::codegen::{ItemFlag, State};
+use crate::traits::{Trait, fuzz::FuzzTrait};
+use crate::util::make_path;
+use quote::quote;
+use syn::{Item, ItemEnum, ItemStruct, parse_quote};
+
+/// Injected faults in record mode, see `codegen::FaultArgs`. The errors are generated by the
+/// `Arbitrary` impls of the fuzz mode, for the types without handles.
+pub struct FaultTrait<'a> {
+    pub state: &'a State,
+}
+impl FaultTrait<'_> {
+    fn has_handle(&self, module_path: &[String], name: &str) -> bool {
+        let path = make_path(module_path, name);
+        self.state.may_have_handle(&parse_quote! { #path })
+    }
+}
+
+impl Trait for FaultTrait<'_> {
+    fn resource_trait(&self, _module_path: &[String], _item: &ItemStruct) -> Vec<Item> {
+        Vec::new()
+    }
+    fn struct_trait(&self, module_path: &[String], item: &ItemStruct) -> Vec<Item> {
+        if self.has_handle(module_path, &item.ident.to_string()) {
+            return Vec::new();
+        }
+        FuzzTrait { state: self.state }.struct_trait(module_path, item)
+    }
+    fn enum_trait(&self, module_path: &[String], item: &ItemEnum) -> Vec<Item> {
+        if self.has_handle(module_path, &item.ident.to_string()) {
+            return Vec::new();
+        }
+        FuzzTrait { state: self.state }.enum_trait(module_path, item)
+    }
+    fn flag_trait(&self, module_path: &[String], item: &ItemFlag) -> Vec<Item> {
+        FuzzTrait { state: self.state }.flag_trait(module_path, item)
+    }
+    fn trait_defs(&self) -> Vec<Item> {
+        let faults = &self.state.faults;
+        let rate = faults.fault_rate;
+        let schedule = &faults.fault_schedule;
+        let seed = match faults.fault_seed {
+            Some(seed) => quote! { Some(#seed) },
+            None => quote! { None },
+        };
+        let ast: syn::File = parse_quote! {
+          #[allow(unused_imports)]
+          use arbitrary::{Arbitrary, Unstructured, Result};
+          thread_local! {
+            static FAULT_RNG: std::cell::Cell<Option<u64>> = const { std::cell::Cell::new(None) };
+            // The number of calls that can fail so far
+            static FAULT_CALLS: std::cell::Cell<u64> = const { std::cell::Cell::new(0) };
+          }
+          // splitmix64, seeded by the debug component
+          fn fault_next() -> u64 {
+              let state = FAULT_RNG
+                  .get()
+                  .unwrap_or_else(|| proxy::util::debug::get_fault_seed(#seed));
+              let state = state.wrapping_add(0x9e3779b97f4a7c15);
+              FAULT_RNG.set(Some(state));
+              let mut z = state;
+              z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+              z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+              z ^ (z >> 31)
+          }
+          // Returns an arbitrary error if the call is scheduled to fail, or by the fault rate.
+          #[allow(dead_code)]
+          fn inject_fault<E: for<'a> Arbitrary<'a>>(name: &str) -> Option<E> {
+              let call = FAULT_CALLS.get() + 1;
+              FAULT_CALLS.set(call);
+              let scheduled = [#(#schedule),*].contains(&call);
+              if !scheduled && (fault_next() >> 11) as f64 / (1u64 << 53) as f64 >= #rate {
+                  return None;
+              }
+              let bytes: Vec<u8> = (0..8).flat_map(|_| fault_next().to_le_bytes()).collect();
+              let err = Unstructured::new(&bytes).arbitrary().ok()?;
+              proxy::util::debug::eprint(&format!("injected fault into call #{call}: {name}"));
+              Some(err)
+          }
+        };
+        ast.items
+    }
+}
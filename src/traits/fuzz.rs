@@ -1,12 +1,15 @@
+use crate::codegen::State;
 use crate::traits::Trait;
 use crate::util::make_path;
 use heck::{ToKebabCase, ToSnakeCase};
 use quote::quote;
 use syn::{Item, ItemEnum, ItemStruct, parse_quote};
 
-pub struct FuzzTrait;
+pub struct FuzzTrait<'a> {
+    pub state: &'a State,
+}
 
-impl Trait for FuzzTrait {
+impl Trait for FuzzTrait<'_> {
     fn resource_trait(&self, module_path: &[String], resource: &ItemStruct) -> Vec<Item> {
         let mut res = Vec::new();
         let resource_path = make_path(module_path, &resource.ident.to_string());
@@ -20,10 +23,36 @@ impl Trait for FuzzTrait {
             )
             .to_snake_case();
             let call: syn::Ident = syn::parse_str(&call).unwrap();
+            if !self
+                .state
+                .has_mock_conversion(module_path, &resource.ident.to_string())
+            {
+                // Resources of the exported interfaces are picked from the live pool
+                res.push(parse_quote! {
+                impl Arbitrary<'_> for #resource_path {
+                    fn arbitrary(u: &mut Unstructured<'_>) -> Result<Self> {
+                        let handle = resource_pool_take(#wit_name, u)?;
+                        // Safety: the pool owns the handle, see resource_pool_register
+                        Ok(unsafe { #resource_path::from_handle(handle) })
+                    }
+                }
+                });
+                res.push(parse_quote! {
+                impl<'a> Arbitrary<'_> for &'a #resource_path {
+                    fn arbitrary(u: &mut Unstructured<'_>) -> Result<Self> {
+                        let handle = resource_pool_get(#wit_name, u)?;
+                        // The pool keeps the ownership
+                        let live = std::mem::ManuallyDrop::new(unsafe { #resource_path::from_handle(handle) });
+                        Ok(SCOPED_ALLOC.with(|alloc| alloc.borrow_mut().alloc(live)))
+                    }
+                }
+                });
+                return res;
+            }
             res.push(parse_quote! {
             impl Arbitrary<'_> for #resource_path {
                 fn arbitrary(_u: &mut Unstructured<'_>) -> Result<Self> {
-                    Ok(proxy::conversion::conversion::#call(42))
+                    Ok(proxy::conversion::conversion::#call(new_handle()))
                 }
                 fn size_hint(_: usize) -> (usize, Option<usize>) {
                     (0, Some(0))
@@ -35,7 +64,7 @@ impl Trait for FuzzTrait {
                 fn arbitrary(_u: &mut Unstructured<'_>) -> Result<Self> {
                     SCOPED_ALLOC.with(|alloc| {
                         let mut alloc = alloc.borrow_mut();
-                        Ok(alloc.alloc(proxy::conversion::conversion::#call(42)))
+                        Ok(alloc.alloc(proxy::conversion::conversion::#call(new_handle())))
                     })
                 }
             }
@@ -46,7 +75,7 @@ impl Trait for FuzzTrait {
             impl Arbitrary<'_> for #resource_path {
                 fn arbitrary(_u: &mut Unstructured<'_>) -> Result<Self> {
                     Ok(#resource_path::new(MockedResource {
-                        handle: 42,
+                        handle: new_handle(),
                         name: #wit_name.to_string(),
                     }))
                 }
@@ -187,7 +216,7 @@ impl Trait for FuzzTrait {
           impl Arbitrary<'_> for MockedResource {
               fn arbitrary(_u: &mut Unstructured<'_>) -> Result<Self> {
                   Ok(Self {
-                      handle: 42,
+                      handle: new_handle(),
                       name: "mocked-resource".to_string(),
                   })
               }
@@ -195,6 +224,165 @@ impl Trait for FuzzTrait {
                   (0, Some(0))
               }
           }
+          thread_local! {
+            static HANDLE_ID: std::cell::Cell<u32> = const { std::cell::Cell::new(1) };
+            // Live resources returned by the export calls, by resource name. The handles are owned by the pool.
+            static RESOURCE_POOL: RefCell<std::collections::BTreeMap<String, Vec<u32>>> = RefCell::new(std::collections::BTreeMap::new());
+          }
+          // Each mocked resource gets its own handle
+          fn new_handle() -> u32 {
+              let id = HANDLE_ID.get();
+              HANDLE_ID.set(id + 1);
+              id
+          }
+          // Move the handles in the value into the pool.
+          // Returns true if the pool takes the ownership of any handle.
+          #[allow(dead_code)]
+          fn resource_pool_register(value: &Value) -> bool {
+              use wasm_wave::wasm::WasmTypeKind;
+              match value.kind() {
+                  WasmTypeKind::Handle => {
+                      let label = value.unwrap_handle();
+                      let (name, handle) = label
+                          .rsplit_once('-')
+                          .and_then(|(name, handle)| handle.parse::<u32>().ok().map(|h| (name.to_string(), h)))
+                          .expect("invalid handle label");
+                      RESOURCE_POOL.with_borrow_mut(|pool| pool.entry(name).or_default().push(handle));
+                      true
+                  }
+                  WasmTypeKind::List => value.unwrap_list().fold(false, |acc, v| resource_pool_register(&v) | acc),
+                  WasmTypeKind::Tuple => value.unwrap_tuple().fold(false, |acc, v| resource_pool_register(&v) | acc),
+                  WasmTypeKind::Record => value.unwrap_record().fold(false, |acc, (_, v)| resource_pool_register(&v) | acc),
+                  WasmTypeKind::Option => value.unwrap_option().is_some_and(|v| resource_pool_register(&v)),
+                  WasmTypeKind::Result => match value.unwrap_result() {
+                      Ok(Some(v)) | Err(Some(v)) => resource_pool_register(&v),
+                      _ => false,
+                  },
+                  WasmTypeKind::Variant => value.unwrap_variant().1.is_some_and(|v| resource_pool_register(&v)),
+                  _ => false,
+              }
+          }
+          #[allow(dead_code)]
+          fn resource_pool_take(name: &str, u: &mut Unstructured<'_>) -> Result<u32> {
+              RESOURCE_POOL.with_borrow_mut(|pool| {
+                  let handles = pool.entry(name.to_string()).or_default();
+                  let idx = u.choose_index(handles.len())?;
+                  Ok(handles.swap_remove(idx))
+              })
+          }
+          #[allow(dead_code)]
+          fn resource_pool_get(name: &str, u: &mut Unstructured<'_>) -> Result<u32> {
+              RESOURCE_POOL.with_borrow(|pool| {
+                  let handles = pool.get(name).map(Vec::as_slice).unwrap_or_default();
+                  Ok(*u.choose(handles)?)
+              })
+          }
+        };
+        let mut res = ast.items;
+        if !self.state.seeds.is_empty() {
+            res.extend(self.seed_defs());
+        }
+        res
+    }
+}
+
+impl FuzzTrait<'_> {
+    /// Mutators of the values recorded in the seed traces. When the random bytes run out,
+    /// the seeds are used as they are.
+    fn seed_defs(&self) -> Vec<Item> {
+        let ast: syn::File = parse_quote! {
+          #[allow(dead_code)]
+          fn choose_seed<T: Copy>(seeds: &[T], u: &mut Unstructured<'_>) -> Option<T> {
+              if !u.ratio(3u8, 4u8).unwrap_or(true) {
+                  return None;
+              }
+              seeds.get(u.choose_index(seeds.len()).unwrap_or(0)).copied()
+          }
+          #[allow(dead_code)]
+          fn mutate_seed<T: ValueTyped>(wave: &str, u: &mut Unstructured<'_>) -> Option<T>
+          where Value: ToRust<T> {
+              let ty = T::value_type();
+              let value: Value = wasm_wave::from_str(&ty, wave).ok()?;
+              Some(mutate_value(&ty, &value, u).to_rust())
+          }
+          // Replace some of the scalars with arbitrary values, and duplicate or remove some list elements.
+          // Enums, flags and handles are kept.
+          fn mutate_value(ty: &Type, value: &Value, u: &mut Unstructured<'_>) -> Value {
+              use wasm_wave::wasm::{WasmType, WasmTypeKind};
+              let keep = u.ratio(3u8, 4u8).unwrap_or(true);
+              match ty.kind() {
+                  WasmTypeKind::Bool if !keep => Value::make_bool(u.arbitrary().unwrap_or_default()),
+                  WasmTypeKind::S8 if !keep => Value::make_s8(u.arbitrary().unwrap_or_default()),
+                  WasmTypeKind::S16 if !keep => Value::make_s16(u.arbitrary().unwrap_or_default()),
+                  WasmTypeKind::S32 if !keep => Value::make_s32(u.arbitrary().unwrap_or_default()),
+                  WasmTypeKind::S64 if !keep => Value::make_s64(u.arbitrary().unwrap_or_default()),
+                  WasmTypeKind::U8 if !keep => Value::make_u8(u.arbitrary().unwrap_or_default()),
+                  WasmTypeKind::U16 if !keep => Value::make_u16(u.arbitrary().unwrap_or_default()),
+                  WasmTypeKind::U32 if !keep => Value::make_u32(u.arbitrary().unwrap_or_default()),
+                  WasmTypeKind::U64 if !keep => Value::make_u64(u.arbitrary().unwrap_or_default()),
+                  WasmTypeKind::F32 if !keep => Value::make_f32(u.arbitrary().unwrap_or_default()),
+                  WasmTypeKind::F64 if !keep => Value::make_f64(u.arbitrary().unwrap_or_default()),
+                  WasmTypeKind::Char if !keep => Value::make_char(u.arbitrary().unwrap_or_default()),
+                  WasmTypeKind::String if !keep => {
+                      Value::make_string(u.arbitrary::<String>().unwrap_or_default().into())
+                  }
+                  WasmTypeKind::List => {
+                      let elem_ty = ty.list_element_type().unwrap();
+                      let mut elems: Vec<Value> = value
+                          .unwrap_list()
+                          .map(|v| mutate_value(&elem_ty, &v, u))
+                          .collect();
+                      if !elems.is_empty() && !u.ratio(7u8, 8u8).unwrap_or(true) {
+                          let idx = u.choose_index(elems.len()).unwrap_or(0);
+                          if u.arbitrary().unwrap_or(false) {
+                              elems.remove(idx);
+                          } else {
+                              elems.insert(idx, elems[idx].clone());
+                          }
+                      }
+                      Value::make_list(ty, elems).unwrap()
+                  }
+                  WasmTypeKind::Record => {
+                      let fields: Vec<(String, Value)> = ty
+                          .record_fields()
+                          .zip(value.unwrap_record())
+                          .map(|((name, field_ty), (_, v))| (name.to_string(), mutate_value(&field_ty, &v, u)))
+                          .collect();
+                      Value::make_record(ty, fields.iter().map(|(name, v)| (name.as_str(), v.clone()))).unwrap()
+                  }
+                  WasmTypeKind::Tuple => {
+                      let elems: Vec<Value> = ty
+                          .tuple_element_types()
+                          .zip(value.unwrap_tuple())
+                          .map(|(elem_ty, v)| mutate_value(&elem_ty, &v, u))
+                          .collect();
+                      Value::make_tuple(ty, elems).unwrap()
+                  }
+                  WasmTypeKind::Option => {
+                      let some_ty = ty.option_some_type().unwrap();
+                      let v = value.unwrap_option().map(|v| mutate_value(&some_ty, &v, u));
+                      Value::make_option(ty, v).unwrap()
+                  }
+                  WasmTypeKind::Result => {
+                      let (ok_ty, err_ty) = ty.result_types().unwrap();
+                      let v = match value.unwrap_result() {
+                          Ok(v) => Ok(v.zip(ok_ty).map(|(v, ty)| mutate_value(&ty, &v, u))),
+                          Err(v) => Err(v.zip(err_ty).map(|(v, ty)| mutate_value(&ty, &v, u))),
+                      };
+                      Value::make_result(ty, v).unwrap()
+                  }
+                  WasmTypeKind::Variant => {
+                      let (case, v) = value.unwrap_variant();
+                      let payload_ty = ty
+                          .variant_cases()
+                          .find(|(name, _)| *name == case)
+                          .and_then(|(_, ty)| ty);
+                      let v = v.zip(payload_ty).map(|(v, ty)| mutate_value(&ty, &v, u));
+                      Value::make_variant(ty, &case, v).unwrap()
+                  }
+                  _ => value.clone(),
+              }
+          }
         };
         ast.items
     }
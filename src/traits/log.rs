@@ -0,0 +1,53 @@
+use crate::codegen::ItemFlag;
+use crate::traits::Trait;
+use syn::{Item, ItemEnum, ItemStruct, parse_quote};
+
+/// Print the calls to stderr in log mode, through the debug component.
+pub struct LogTrait;
+
+impl Trait for LogTrait {
+    fn resource_trait(&self, _module_path: &[String], _item: &ItemStruct) -> Vec<Item> {
+        Vec::new()
+    }
+    fn struct_trait(&self, _module_path: &[String], _item: &ItemStruct) -> Vec<Item> {
+        Vec::new()
+    }
+    fn enum_trait(&self, _module_path: &[String], _item: &ItemEnum) -> Vec<Item> {
+        Vec::new()
+    }
+    fn flag_trait(&self, _module_path: &[String], _item: &ItemFlag) -> Vec<Item> {
+        Vec::new()
+    }
+    fn trait_defs(&self) -> Vec<Item> {
+        let ast: syn::File = parse_quote! {
+          thread_local! {
+              static LOG_DEPTH: std::cell::Cell<usize> = const { std::cell::Cell::new(0) };
+          }
+          // Imports are called by the exports, which are proxied by the other component,
+          // so they are indented by one more level.
+          fn log_indent(is_export: bool) -> String {
+              "  ".repeat(LOG_DEPTH.get() + !is_export as usize)
+          }
+          #[allow(dead_code)]
+          fn log_call(method: &str, args: &[String], is_export: bool) -> u64 {
+              let indent = log_indent(is_export);
+              proxy::util::debug::eprint(&format!("{indent}{method}({})", args.join(", ")));
+              LOG_DEPTH.set(LOG_DEPTH.get() + 1);
+              proxy::util::debug::now()
+          }
+          #[allow(dead_code)]
+          fn log_ret(method: &str, ret: Option<&str>, is_export: bool, start: u64) {
+              let elapsed = proxy::util::debug::now().saturating_sub(start);
+              LOG_DEPTH.set(LOG_DEPTH.get().saturating_sub(1));
+              let indent = log_indent(is_export);
+              let ret = ret.unwrap_or("()");
+              proxy::util::debug::eprint(&format!(
+                  "{indent}{method} -> {ret} <{}.{:03}ms>",
+                  elapsed / 1_000_000,
+                  elapsed / 1_000 % 1_000
+              ));
+          }
+        };
+        ast.items
+    }
+}
@@ -0,0 +1,119 @@
+use crate::codegen::ItemFlag;
+use crate::traits::Trait;
+use syn::{Item, ItemEnum, ItemStruct, parse_quote};
+
+/// Runtime support for the mock rules, see `codegen::mock::Rules`.
+pub struct MockTrait;
+
+impl Trait for MockTrait {
+    fn resource_trait(&self, _module_path: &[String], _item: &ItemStruct) -> Vec<Item> {
+        Vec::new()
+    }
+    fn struct_trait(&self, _module_path: &[String], _item: &ItemStruct) -> Vec<Item> {
+        Vec::new()
+    }
+    fn enum_trait(&self, _module_path: &[String], _item: &ItemEnum) -> Vec<Item> {
+        Vec::new()
+    }
+    fn flag_trait(&self, _module_path: &[String], _item: &ItemFlag) -> Vec<Item> {
+        Vec::new()
+    }
+    fn trait_defs(&self) -> Vec<Item> {
+        let ast: syn::File = parse_quote! {
+          struct MockRule {
+              args: Option<&'static [&'static str]>,
+              // An empty list returns the default value
+              rets: &'static [&'static str],
+          }
+          thread_local! {
+              // The number of calls answered by each rule, by the method and the rule index
+              static MOCK_CALLS: std::cell::RefCell<std::collections::BTreeMap<(&'static str, usize), usize>> =
+                  std::cell::RefCell::new(std::collections::BTreeMap::new());
+              static MOCK_HANDLE: std::cell::Cell<u32> = const { std::cell::Cell::new(0) };
+          }
+          #[allow(dead_code)]
+          fn next_mock_handle() -> u32 {
+              let handle = MOCK_HANDLE.get() + 1;
+              MOCK_HANDLE.set(handle);
+              handle
+          }
+          fn mock_arg_match(ty: &Type, pattern: &str, arg: &str) -> bool {
+              pattern == "_"
+                  || pattern == arg
+                  || wasm_wave::from_str::<Value>(ty, pattern)
+                      .is_ok_and(|value| wasm_wave::to_string(&value).unwrap() == arg)
+          }
+          #[allow(dead_code)]
+          fn mock_import(method: &'static str, rules: &[MockRule], types: &[Type], args: &[String], ret_ty: &Type) -> Value {
+              let Some((idx, rule)) = rules.iter().enumerate().find(|(_, rule)| {
+                  rule.args.is_none_or(|patterns| {
+                      patterns.len() == args.len()
+                          && patterns
+                              .iter()
+                              .zip(types)
+                              .zip(args)
+                              .all(|((pattern, ty), arg)| mock_arg_match(ty, pattern, arg))
+                  })
+              }) else {
+                  panic!("no mock rule matches {method}({})", args.join(", "));
+              };
+              let count = MOCK_CALLS.with_borrow_mut(|calls| {
+                  let count = calls.entry((method, idx)).or_insert(0);
+                  *count += 1;
+                  *count - 1
+              });
+              match rule.rets.get(count.min(rule.rets.len().saturating_sub(1))) {
+                  Some(wave) => wasm_wave::from_str(ret_ty, wave)
+                      .unwrap_or_else(|e| panic!("invalid mock value for {method}: {wave}: {e}")),
+                  None => mock_default(ret_ty),
+              }
+          }
+          fn mock_default(ty: &Type) -> Value {
+              use wasm_wave::wasm::{WasmType, WasmTypeKind};
+              match ty.kind() {
+                  WasmTypeKind::Bool => Value::make_bool(false),
+                  WasmTypeKind::S8 => Value::make_s8(0),
+                  WasmTypeKind::S16 => Value::make_s16(0),
+                  WasmTypeKind::S32 => Value::make_s32(0),
+                  WasmTypeKind::S64 => Value::make_s64(0),
+                  WasmTypeKind::U8 => Value::make_u8(0),
+                  WasmTypeKind::U16 => Value::make_u16(0),
+                  WasmTypeKind::U32 => Value::make_u32(0),
+                  WasmTypeKind::U64 => Value::make_u64(0),
+                  WasmTypeKind::F32 => Value::make_f32(0.0),
+                  WasmTypeKind::F64 => Value::make_f64(0.0),
+                  WasmTypeKind::Char => Value::make_char('\0'),
+                  WasmTypeKind::String => Value::make_string("".into()),
+                  WasmTypeKind::List => Value::make_list(ty, []).unwrap(),
+                  WasmTypeKind::Record => {
+                      let fields: Vec<(String, Value)> = ty
+                          .record_fields()
+                          .map(|(name, field_ty)| (name.to_string(), mock_default(&field_ty)))
+                          .collect();
+                      Value::make_record(ty, fields.iter().map(|(name, v)| (name.as_str(), v.clone()))).unwrap()
+                  }
+                  WasmTypeKind::Tuple => {
+                      let elems: Vec<Value> = ty.tuple_element_types().map(|ty| mock_default(&ty)).collect();
+                      Value::make_tuple(ty, elems).unwrap()
+                  }
+                  WasmTypeKind::Variant => {
+                      let (case, payload_ty) = ty.variant_cases().next().unwrap();
+                      Value::make_variant(ty, &case, payload_ty.map(|ty| mock_default(&ty))).unwrap()
+                  }
+                  WasmTypeKind::Enum => {
+                      let case = ty.enum_cases().next().unwrap();
+                      Value::make_enum(ty, &case).unwrap()
+                  }
+                  WasmTypeKind::Option => Value::make_option(ty, None).unwrap(),
+                  WasmTypeKind::Result => {
+                      let (ok_ty, _) = ty.result_types().unwrap();
+                      Value::make_result(ty, Ok(ok_ty.map(|ty| mock_default(&ty)))).unwrap()
+                  }
+                  WasmTypeKind::Flags => Value::make_flags(ty, []).unwrap(),
+                  _ => panic!("no default value for {ty}, add a mock rule with `ret`"),
+              }
+          }
+        };
+        ast.items
+    }
+}
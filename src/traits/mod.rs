@@ -1,9 +1,16 @@
 use crate::codegen::{GenerateMode, ItemFlag, State, TypeInfo};
 use syn::{Item, ItemEnum, ItemStruct};
 
+mod assert;
 mod dialog;
+mod fault;
 mod fuzz;
+mod log;
+mod mock;
+mod policy;
+mod profile;
 mod proxy;
+mod stream;
 mod wave;
 mod wit;
 
@@ -28,37 +35,95 @@ impl<'a> TraitGenerator<'a> {
             GenerateMode::Instrument => traits.push(Box::new(proxy::ProxyTrait::new(state))),
             GenerateMode::Record => {
                 traits.push(Box::new(wave::WaveTrait {
+                    state,
                     to_value: true,
                     to_rust: false,
                     has_replay_table: false,
                 }));
                 traits.push(Box::new(proxy::ProxyTrait::new(state)));
+                // only the imports world has the debug component for the random numbers
+                let debug_path = ["proxy", "util", "debug"].map(String::from).to_vec();
+                if state.faults.is_enabled() && state.module_paths.contains(&debug_path) {
+                    traits.push(Box::new(fault::FaultTrait { state }));
+                }
+            }
+            GenerateMode::Log => {
+                traits.push(Box::new(wave::WaveTrait {
+                    state,
+                    to_value: true,
+                    to_rust: false,
+                    has_replay_table: false,
+                }));
+                traits.push(Box::new(proxy::ProxyTrait::new(state)));
+                traits.push(Box::new(log::LogTrait));
+            }
+            GenerateMode::Profile => {
+                traits.push(Box::new(wave::WaveTrait {
+                    state,
+                    to_value: true,
+                    to_rust: false,
+                    has_replay_table: false,
+                }));
+                traits.push(Box::new(proxy::ProxyTrait::new(state)));
+                traits.push(Box::new(profile::ProfileTrait { state }));
+            }
+            GenerateMode::Policy => {
+                traits.push(Box::new(wave::WaveTrait {
+                    state,
+                    to_value: true,
+                    to_rust: false,
+                    has_replay_table: false,
+                }));
+                traits.push(Box::new(proxy::ProxyTrait::new(state)));
+                traits.push(Box::new(policy::PolicyTrait { state }));
             }
             GenerateMode::Replay => {
                 traits.push(Box::new(wave::WaveTrait {
+                    state,
                     to_value: true,
                     to_rust: true,
                     has_replay_table: true,
                 }));
+                if state.has_wrapped_imports() {
+                    traits.push(Box::new(proxy::ProxyTrait::new(state)));
+                }
+                if !state.asserts.is_empty() {
+                    traits.push(Box::new(assert::AssertTrait));
+                }
             }
             GenerateMode::Fuzz => {
                 traits.push(Box::new(wave::WaveTrait {
+                    state,
                     to_value: true,
-                    to_rust: false,
+                    // recorded seeds are converted back to rust values
+                    to_rust: !state.seeds.is_empty(),
                     has_replay_table: true,
                 }));
-                traits.push(Box::new(fuzz::FuzzTrait {}));
+                traits.push(Box::new(fuzz::FuzzTrait { state }));
             }
             GenerateMode::Dialog => {
                 traits.push(Box::new(wave::WaveTrait {
+                    state,
                     to_value: true,
                     to_rust: true,
                     has_replay_table: true,
                 }));
                 traits.push(Box::new(wit::WitTrait {}));
-                traits.push(Box::new(dialog::DialogTrait {}));
+                traits.push(Box::new(dialog::DialogTrait { state }));
+            }
+            GenerateMode::Mock => {
+                traits.push(Box::new(wave::WaveTrait {
+                    state,
+                    to_value: true,
+                    to_rust: true,
+                    has_replay_table: true,
+                }));
+                traits.push(Box::new(mock::MockTrait));
             }
         }
+        if state.has_stream || state.has_future {
+            traits.push(Box::new(stream::StreamTrait::new(state)));
+        }
         TraitGenerator { state, traits }
     }
 
@@ -0,0 +1,198 @@
+use crate::codegen::{ItemFlag, State};
+use crate::traits::{Trait, wave::WaveTrait};
+use crate::util::make_path;
+use syn::{Item, ItemEnum, ItemStruct, parse_quote};
+
+/// Runtime support for the policy checks, see `codegen::policy::Policy`. The error values of the
+/// denied calls are converted by the `ToRust` impls of the types without handles.
+pub struct PolicyTrait<'a> {
+    pub state: &'a State,
+}
+impl PolicyTrait<'_> {
+    fn has_handle(&self, module_path: &[String], name: &str) -> bool {
+        let path = make_path(module_path, name);
+        self.state.may_have_handle(&parse_quote! { #path })
+    }
+    fn to_rust(&self) -> WaveTrait<'_> {
+        WaveTrait {
+            state: self.state,
+            to_value: false,
+            to_rust: true,
+            has_replay_table: false,
+        }
+    }
+}
+/// Only keep the `ToRust` impls, the other traits are generated by the main `WaveTrait`.
+fn only_to_rust(items: Vec<Item>) -> Vec<Item> {
+    items
+        .into_iter()
+        .filter(|item| match item {
+            Item::Impl(item) => item
+                .trait_
+                .as_ref()
+                .is_some_and(|(_, path, _)| path.segments.last().unwrap().ident == "ToRust"),
+            _ => false,
+        })
+        .collect()
+}
+
+impl Trait for PolicyTrait<'_> {
+    fn resource_trait(&self, _module_path: &[String], _item: &ItemStruct) -> Vec<Item> {
+        Vec::new()
+    }
+    fn struct_trait(&self, module_path: &[String], item: &ItemStruct) -> Vec<Item> {
+        if self.has_handle(module_path, &item.ident.to_string()) {
+            return Vec::new();
+        }
+        only_to_rust(self.to_rust().struct_trait(module_path, item))
+    }
+    fn enum_trait(&self, module_path: &[String], item: &ItemEnum) -> Vec<Item> {
+        if self.has_handle(module_path, &item.ident.to_string()) {
+            return Vec::new();
+        }
+        only_to_rust(self.to_rust().enum_trait(module_path, item))
+    }
+    fn flag_trait(&self, module_path: &[String], item: &ItemFlag) -> Vec<Item> {
+        only_to_rust(self.to_rust().flag_trait(module_path, item))
+    }
+    fn trait_defs(&self) -> Vec<Item> {
+        let ast: syn::File = parse_quote! {
+          thread_local! {
+              // The directories of the handles returned by the calls with a checked path, by their labels
+              static POLICY_DIRS: std::cell::RefCell<std::collections::BTreeMap<String, String>> =
+                  const { std::cell::RefCell::new(std::collections::BTreeMap::new()) };
+          }
+          thread_local! {
+              // The calls and bytes used by each rule in the current export call
+              static POLICY_USAGE: std::cell::RefCell<std::collections::BTreeMap<usize, (u64, u64)>> =
+                  const { std::cell::RefCell::new(std::collections::BTreeMap::new()) };
+          }
+          #[allow(dead_code)]
+          fn policy_reset() {
+              POLICY_USAGE.with_borrow_mut(|usage| usage.clear());
+          }
+          #[allow(dead_code)]
+          fn policy_quota(rule: usize, max_calls: Option<u64>, max_bytes: Option<u64>, args_size: u64) -> Result<(), String> {
+              POLICY_USAGE.with_borrow_mut(|usage| {
+                  let (calls, bytes) = usage.entry(rule).or_default();
+                  if let Some(max) = max_calls.filter(|max| *calls >= *max) {
+                      return Err(format!("the quota of {max} calls is used up"));
+                  }
+                  if let Some(max) = max_bytes.filter(|max| *bytes + args_size > *max) {
+                      return Err(format!("the quota of {max} bytes is used up"));
+                  }
+                  *calls += 1;
+                  *bytes += args_size;
+                  Ok(())
+              })
+          }
+          #[allow(dead_code)]
+          fn policy_quota_ret(rule: usize, ret_size: u64) {
+              POLICY_USAGE.with_borrow_mut(|usage| usage.entry(rule).or_default().1 += ret_size);
+          }
+          #[allow(dead_code)]
+          fn policy_deny(method: &str, reason: &str) -> ! {
+              panic!("{method} is denied by the policy: {reason}")
+          }
+          #[allow(dead_code)]
+          fn policy_error<T>(method: &str, error: &str) -> T
+          where
+              T: ValueTyped,
+              Value: ToRust<T>,
+          {
+              let value: Value = wasm_wave::from_str(&T::value_type(), error)
+                  .unwrap_or_else(|e| panic!("invalid policy error for {method}: {error}: {e}"));
+              value.to_rust()
+          }
+          // Resolve the path under the directory of the base handle.
+          // Returns None if the path leaves the preopened directory.
+          #[allow(dead_code)]
+          fn policy_resolve(base: Option<&str>, path: &str) -> Option<String> {
+              let dir = base.and_then(|label| {
+                  let label = label.strip_prefix("borrow-").unwrap_or(label);
+                  POLICY_DIRS.with_borrow(|dirs| dirs.get(label).cloned())
+              });
+              let mut parts: Vec<String> = dir
+                  .iter()
+                  .flat_map(|dir| dir.split('/'))
+                  .filter(|part| !part.is_empty())
+                  .map(String::from)
+                  .collect();
+              for part in path.split('/') {
+                  match part {
+                      "" | "." => (),
+                      ".." => {
+                          parts.pop()?;
+                      }
+                      part => parts.push(part.to_string()),
+                  }
+              }
+              Some(parts.join("/"))
+          }
+          // Returns the resolved directory of the first path, or the reason to deny the call
+          #[allow(dead_code)]
+          fn policy_check_paths(paths: &[(Option<String>, &str)], prefixes: &[&str]) -> Result<String, String> {
+              let mut dir = None;
+              for (base, path) in paths {
+                  let Some(resolved) = policy_resolve(base.as_deref(), path) else {
+                      return Err(format!("path {path} leaves the preopened directory"));
+                  };
+                  let allowed = prefixes.iter().any(|prefix| {
+                      prefix.is_empty()
+                          || resolved == *prefix
+                          || resolved.strip_prefix(prefix).is_some_and(|rest| rest.starts_with('/'))
+                  });
+                  if !allowed {
+                      return Err(format!("path {resolved} is not allowed"));
+                  }
+                  dir.get_or_insert(resolved);
+              }
+              Ok(dir.unwrap_or_default())
+          }
+          #[allow(dead_code)]
+          fn policy_check_authority(authority: Option<&str>, authorities: &[&str]) -> Result<(), String> {
+              match authority {
+                  Some(authority) if !authorities.contains(&authority) => {
+                      Err(format!("authority {authority} is not allowed"))
+                  }
+                  _ => Ok(()),
+              }
+          }
+          // Track the directories of the returned handles. The handles returned without a path are
+          // at the root of the preopened directories, and the reused handle labels are reset.
+          #[allow(dead_code)]
+          fn policy_track_dirs(value: &Value, dir: Option<&str>) {
+              use wasm_wave::wasm::WasmTypeKind;
+              match value.kind() {
+                  WasmTypeKind::Handle => {
+                      let label = value.unwrap_handle().into_owned();
+                      POLICY_DIRS.with_borrow_mut(|dirs| match dir {
+                          Some(dir) => dirs.insert(label, dir.to_string()),
+                          None => dirs.remove(&label),
+                      });
+                  }
+                  WasmTypeKind::List => value.unwrap_list().for_each(|v| policy_track_dirs(&v, dir)),
+                  WasmTypeKind::Tuple => value.unwrap_tuple().for_each(|v| policy_track_dirs(&v, dir)),
+                  WasmTypeKind::Record => value.unwrap_record().for_each(|(_, v)| policy_track_dirs(&v, dir)),
+                  WasmTypeKind::Option => {
+                      if let Some(v) = value.unwrap_option() {
+                          policy_track_dirs(&v, dir);
+                      }
+                  }
+                  WasmTypeKind::Result => {
+                      if let Ok(Some(v)) | Err(Some(v)) = value.unwrap_result() {
+                          policy_track_dirs(&v, dir);
+                      }
+                  }
+                  WasmTypeKind::Variant => {
+                      if let (_, Some(v)) = value.unwrap_variant() {
+                          policy_track_dirs(&v, dir);
+                      }
+                  }
+                  _ => (),
+              }
+          }
+        };
+        ast.items
+    }
+}
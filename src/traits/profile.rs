@@ -0,0 +1,60 @@
+use crate::codegen::{ItemFlag, State};
+use crate::traits::Trait;
+use syn::{Item, ItemEnum, ItemStruct, parse_quote};
+
+/// Collect the call statistics in profile mode. The statistics are kept in the debug component,
+/// which is shared by the imports and exports components.
+pub struct ProfileTrait<'a> {
+    pub state: &'a State,
+}
+
+impl Trait for ProfileTrait<'_> {
+    fn resource_trait(&self, _module_path: &[String], _item: &ItemStruct) -> Vec<Item> {
+        Vec::new()
+    }
+    fn struct_trait(&self, _module_path: &[String], _item: &ItemStruct) -> Vec<Item> {
+        Vec::new()
+    }
+    fn enum_trait(&self, _module_path: &[String], _item: &ItemEnum) -> Vec<Item> {
+        Vec::new()
+    }
+    fn flag_trait(&self, _module_path: &[String], _item: &ItemFlag) -> Vec<Item> {
+        Vec::new()
+    }
+    fn trait_defs(&self) -> Vec<Item> {
+        let json = self.state.profile.profile_json;
+        let ast: syn::File = parse_quote! {
+          thread_local! {
+              // The number of running export calls
+              static PROFILE_DEPTH: std::cell::Cell<usize> = const { std::cell::Cell::new(0) };
+          }
+          #[allow(dead_code)]
+          fn profile_call(method: &str, is_export: bool) -> u64 {
+              // exit does not return, so print the report before calling it
+              if method.starts_with("wasi:cli/exit") {
+                  proxy::util::debug::print_profile(#json);
+              }
+              if is_export {
+                  PROFILE_DEPTH.set(PROFILE_DEPTH.get() + 1);
+              }
+              proxy::util::debug::now()
+          }
+          #[allow(dead_code)]
+          fn profile_ret(method: &str, is_export: bool, args: &[String], ret: Option<&str>, start: u64) {
+              let elapsed = proxy::util::debug::now().saturating_sub(start);
+              // the sizes are measured in the WAVE format
+              let arg_size = args.iter().map(|arg| arg.len() as u64).sum();
+              let ret_size = ret.map_or(0, |ret| ret.len() as u64);
+              proxy::util::debug::add_profile(method, is_export, arg_size, ret_size, elapsed);
+              if is_export {
+                  let depth = PROFILE_DEPTH.get().saturating_sub(1);
+                  PROFILE_DEPTH.set(depth);
+                  if depth == 0 {
+                      proxy::util::debug::print_profile(#json);
+                  }
+              }
+          }
+        };
+        ast.items
+    }
+}
@@ -19,6 +19,31 @@ impl Trait for ProxyTrait<'_> {
         let resource_path = make_path(module_path, &resource.ident.to_string());
         let output_path = self.get_proxy_path(module_path);
         let output_owned = make_path(&output_path, &resource.ident.to_string());
+        // resources that only exist on one side, e.g. the new resources of an adapted interface
+        if self.is_unwrapped(module_path, &output_path)
+            || !self
+                .state
+                .has_type_def(&output_path, &resource.ident.to_string())
+        {
+            return vec![
+                parse_quote! {
+                    impl ToProxy for #resource_path {
+                        type Output = Self;
+                        fn to_proxy(self) -> Self::Output {
+                            self
+                        }
+                    }
+                },
+                parse_quote! {
+                    impl<'a> ToProxy for &'a #resource_path {
+                        type Output = Self;
+                        fn to_proxy(self) -> Self::Output {
+                            self
+                        }
+                    }
+                },
+            ];
+        }
         let in_import = module_path[0] != "exports";
         if in_import {
             let is_import_only = output_path[0] != "exports";
@@ -95,7 +120,9 @@ impl Trait for ProxyTrait<'_> {
         let struct_name = make_path(module_path, &name);
         let (impl_generics, ty_generics, where_clause) = struct_item.generics.split_for_impl();
         let output_path = self.get_proxy_path(module_path);
-        if !self.state.has_type_def(&output_path, &name) {
+        if self.is_unwrapped(module_path, &output_path)
+            || !self.state.has_type_def(&output_path, &name)
+        {
             return vec![parse_quote! {
                 impl #impl_generics ToProxy for #struct_name #ty_generics #where_clause {
                     type Output = #struct_name #ty_generics;
@@ -130,7 +157,9 @@ impl Trait for ProxyTrait<'_> {
         let enum_name = make_path(module_path, &name);
         let (impl_generics, ty_generics, where_clause) = enum_item.generics.split_for_impl();
         let output_path = self.get_proxy_path(module_path);
-        if !self.state.has_type_def(&output_path, &name) {
+        if self.is_unwrapped(module_path, &output_path)
+            || !self.state.has_type_def(&output_path, &name)
+        {
             return vec![parse_quote! {
                 impl #impl_generics ToProxy for #enum_name #ty_generics #where_clause {
                     type Output = #enum_name #ty_generics;
@@ -168,7 +197,9 @@ impl Trait for ProxyTrait<'_> {
         let name = item.name.to_string();
         let flag_name = make_path(module_path, &name);
         let output_path = self.get_proxy_path(module_path);
-        if !self.state.has_type_def(&output_path, &name) {
+        if self.is_unwrapped(module_path, &output_path)
+            || !self.state.has_type_def(&output_path, &name)
+        {
             return vec![parse_quote! {
                 impl ToProxy for #flag_name {
                     type Output = #flag_name;
@@ -262,17 +293,27 @@ impl Trait for ProxyTrait<'_> {
     }
 }
 impl ProxyTrait<'_> {
+    /// World-level types are shared, while the other types without a wrapped counterpart are
+    /// converted to themselves.
+    fn is_unwrapped(&self, module_path: &[String], output_path: &[String]) -> bool {
+        !module_path.is_empty() && output_path == module_path
+    }
     fn get_proxy_path(&self, src_path: &[String]) -> Vec<String> {
+        if src_path.is_empty() {
+            // world-level types are shared by the import and the wrapped export
+            return Vec::new();
+        }
         let from_export = src_path[0] == "exports";
         let mut res = crate::codegen::get_proxy_path(src_path);
-        if from_export {
-            assert!(self.state.module_paths.contains(&res));
-        } else if !self.state.module_paths.contains(&res) {
+        if !from_export && !self.state.module_paths.contains(&res) {
             res.remove(0);
-            assert!(self.state.module_paths.contains(&res));
-        } else {
-            assert!(self.state.module_paths.contains(&res));
-        };
+        }
+        if !self.state.module_paths.contains(&res) {
+            // interfaces only used by world-level types are not wrapped, and in replay mode,
+            // only the live imports are wrapped
+            assert!(!from_export || !self.state.mode.is_instrument());
+            return src_path.to_vec();
+        }
         res
     }
 }
@@ -0,0 +1,323 @@
+use crate::codegen::{GenerateMode, ItemFlag, State};
+use crate::traits::Trait;
+use quote::quote;
+use syn::{Item, ItemEnum, ItemStruct, parse_quote};
+
+/// Helpers for `stream<T>` and `future<T>` handles. wit-bindgen only generates the
+/// `wit_stream` and `wit_future` modules when the world uses them.
+///
+/// Values are moved by spawned tasks, so a handle in the trace is only a label, and the
+/// stream chunks and future values are recorded as separate events under that label.
+pub struct StreamTrait {
+    mode: GenerateMode,
+    has_stream: bool,
+    has_future: bool,
+}
+impl StreamTrait {
+    pub fn new(state: &State) -> Self {
+        StreamTrait {
+            mode: state.mode.clone(),
+            has_stream: state.has_stream,
+            has_future: state.has_future,
+        }
+    }
+}
+
+impl Trait for StreamTrait {
+    fn resource_trait(&self, _module_path: &[String], _item: &ItemStruct) -> Vec<Item> {
+        Vec::new()
+    }
+    fn struct_trait(&self, _module_path: &[String], _item: &ItemStruct) -> Vec<Item> {
+        Vec::new()
+    }
+    fn enum_trait(&self, _module_path: &[String], _item: &ItemEnum) -> Vec<Item> {
+        Vec::new()
+    }
+    fn flag_trait(&self, _module_path: &[String], _item: &ItemFlag) -> Vec<Item> {
+        Vec::new()
+    }
+    fn trait_defs(&self) -> Vec<Item> {
+        let mut res = Vec::new();
+        match &self.mode {
+            GenerateMode::Stubs => (),
+            GenerateMode::Instrument => res.extend(self.proxy_defs(false)),
+            GenerateMode::Record => {
+                res.extend(self.record_label_defs());
+                res.extend(self.proxy_defs(true));
+            }
+            GenerateMode::Replay => {
+                res.extend(self.label_defs());
+                res.extend(self.replay_defs());
+            }
+            GenerateMode::Fuzz => {
+                res.extend(self.label_defs());
+                res.extend(self.fuzz_defs());
+            }
+            GenerateMode::Dialog => {
+                res.extend(self.label_defs());
+                res.extend(self.dialog_defs());
+            }
+            // rejected by GenerateArgs::generate
+            GenerateMode::Mock
+            | GenerateMode::Log
+            | GenerateMode::Profile
+            | GenerateMode::Policy => unreachable!(),
+        }
+        res
+    }
+}
+
+impl StreamTrait {
+    /// In record mode, labels are allocated by the recorder, because the imports and exports
+    /// are proxied by different components.
+    fn record_label_defs(&self) -> Vec<Item> {
+        let ast: syn::File = parse_quote! {
+            thread_local! {
+                // Labels allocated by whichever of `to_value` and `to_proxy` visits a handle first,
+                // waiting to be taken by the other one. Both visit the handles in the same order.
+                static ASYNC_LABELS: std::cell::RefCell<std::collections::VecDeque<(bool, String)>> =
+                    std::cell::RefCell::new(std::collections::VecDeque::new());
+            }
+            fn pending_async_label(kind: &str, from_proxy: bool) -> String {
+                ASYNC_LABELS.with_borrow_mut(|labels| match labels.front() {
+                    Some((pending_from_proxy, _)) if *pending_from_proxy != from_proxy => {
+                        labels.pop_front().unwrap().1
+                    }
+                    _ => {
+                        let label = proxy::recorder::record::new_label(kind);
+                        labels.push_back((from_proxy, label.clone()));
+                        label
+                    }
+                })
+            }
+            #[allow(dead_code)]
+            fn async_label(kind: &str) -> String {
+                pending_async_label(kind, false)
+            }
+            #[allow(dead_code)]
+            fn clear_async_labels() {
+                ASYNC_LABELS.with_borrow_mut(|labels| labels.clear());
+            }
+        };
+        ast.items
+    }
+    /// Without the recorder, handles are labeled in the order they are visited, which follows
+    /// the numbering of the recorder.
+    fn label_defs(&self) -> Vec<Item> {
+        let ast: syn::File = parse_quote! {
+            thread_local! {
+                static ASYNC_LABEL_ID: std::cell::Cell<u32> = const { std::cell::Cell::new(0) };
+            }
+            #[allow(dead_code)]
+            fn async_label(kind: &str) -> String {
+                let id = ASYNC_LABEL_ID.get();
+                ASYNC_LABEL_ID.set(id + 1);
+                format!("{kind}-{id}")
+            }
+            // Handles from the trace keep their labels
+            #[allow(dead_code)]
+            fn visit_async_label(label: &str) {
+                if let Some(id) = label.rsplit_once('-').and_then(|(_, id)| id.parse::<u32>().ok()) {
+                    ASYNC_LABEL_ID.set(id + 1);
+                }
+            }
+        };
+        ast.items
+    }
+    fn proxy_defs(&self, record: bool) -> Vec<Item> {
+        let mut res = Vec::new();
+        let (value_bound, stream_label, record_chunk, record_end, future_label, record_value) =
+            if record {
+                (
+                    quote! { + ToValue },
+                    quote! { let label = pending_async_label("stream", true); },
+                    quote! {
+                        let waves: Vec<String> = values.iter().map(|v| wasm_wave::to_string(&v.to_value()).unwrap()).collect();
+                        proxy::recorder::record::record_stream(&label, Some(&waves));
+                    },
+                    quote! { proxy::recorder::record::record_stream(&label, None); },
+                    quote! { let label = pending_async_label("future", true); },
+                    quote! {
+                        let wave = wasm_wave::to_string(&value.to_value()).unwrap();
+                        proxy::recorder::record::record_stream(&label, Some(&[wave]));
+                    },
+                )
+            } else {
+                (
+                    quote! {},
+                    quote! {},
+                    quote! {},
+                    quote! {},
+                    quote! {},
+                    quote! {},
+                )
+            };
+        if self.has_stream {
+            res.push(parse_quote! {
+                impl<T: ToProxy #value_bound + 'static> ToProxy for wit_bindgen::StreamReader<T>
+                where T::Output: wit_stream::StreamPayload {
+                    type Output = wit_bindgen::StreamReader<T::Output>;
+                    fn to_proxy(mut self) -> Self::Output {
+                        #stream_label
+                        let (mut writer, reader) = wit_stream::new::<T::Output>();
+                        wit_bindgen::spawn(async move {
+                            loop {
+                                let (result, values) = self.read(Vec::with_capacity(64)).await;
+                                if !values.is_empty() {
+                                    #record_chunk
+                                    let values = values.into_iter().map(|v| v.to_proxy()).collect();
+                                    if !writer.write_all(values).await.is_empty() {
+                                        break;
+                                    }
+                                }
+                                if !matches!(result, wit_bindgen::StreamResult::Complete(_)) {
+                                    break;
+                                }
+                            }
+                            #record_end
+                        });
+                        reader
+                    }
+                }
+            });
+        }
+        if self.has_future {
+            res.push(parse_quote! {
+                impl<T: ToProxy #value_bound + 'static> ToProxy for wit_bindgen::FutureReader<T>
+                where T::Output: wit_future::FuturePayload {
+                    type Output = wit_bindgen::FutureReader<T::Output>;
+                    fn to_proxy(self) -> Self::Output {
+                        #future_label
+                        let (writer, reader) = wit_future::new::<T::Output>(|| unreachable!());
+                        wit_bindgen::spawn(async move {
+                            let value = self.await;
+                            #record_value
+                            let _ = writer.write(value.to_proxy()).await;
+                        });
+                        reader
+                    }
+                }
+            });
+        }
+        res
+    }
+    fn replay_defs(&self) -> Vec<Item> {
+        let mut res = Vec::new();
+        if self.has_stream {
+            res.push(parse_quote! {
+                #[allow(dead_code)]
+                fn replay_stream_reader<T: wit_stream::StreamPayload + ValueTyped>(value: &Value) -> wit_bindgen::StreamReader<T>
+                where Value: ToRust<T> {
+                    let label = value.unwrap_handle().to_string();
+                    visit_async_label(&label);
+                    let (mut writer, reader) = wit_stream::new::<T>();
+                    wit_bindgen::spawn(async move {
+                        while let Some(waves) = proxy::recorder::replay::replay_stream(&label) {
+                            let values: Vec<T> = waves.iter().map(|wave| {
+                                let value: Value = wasm_wave::from_str(&T::value_type(), wave).unwrap();
+                                value.to_rust()
+                            }).collect();
+                            if !writer.write_all(values).await.is_empty() {
+                                break;
+                            }
+                        }
+                    });
+                    reader
+                }
+            });
+        }
+        if self.has_future {
+            res.push(parse_quote! {
+                #[allow(dead_code)]
+                fn replay_future_reader<T: wit_future::FuturePayload + ValueTyped>(value: &Value) -> wit_bindgen::FutureReader<T>
+                where Value: ToRust<T> {
+                    let label = value.unwrap_handle().to_string();
+                    visit_async_label(&label);
+                    let (writer, reader) = wit_future::new::<T>(|| unreachable!());
+                    wit_bindgen::spawn(async move {
+                        let waves = proxy::recorder::replay::replay_stream(&label)
+                            .unwrap_or_else(|| panic!("{label} is not resolved in the trace"));
+                        let value: Value = wasm_wave::from_str(&T::value_type(), &waves[0]).unwrap();
+                        let _ = writer.write(value.to_rust()).await;
+                    });
+                    reader
+                }
+            });
+        }
+        res
+    }
+    fn fuzz_defs(&self) -> Vec<Item> {
+        let mut res = Vec::new();
+        if self.has_stream {
+            res.push(parse_quote! {
+                #[allow(dead_code)]
+                fn arbitrary_stream_reader<'a, T: Arbitrary<'a> + wit_stream::StreamPayload>(u: &mut Unstructured<'a>) -> wit_bindgen::StreamReader<T> {
+                    let values: Vec<T> = u.arbitrary().unwrap();
+                    let (mut writer, reader) = wit_stream::new::<T>();
+                    wit_bindgen::spawn(async move {
+                        let _ = writer.write_all(values).await;
+                    });
+                    reader
+                }
+            });
+        }
+        if self.has_future {
+            res.push(parse_quote! {
+                #[allow(dead_code)]
+                fn arbitrary_future_reader<'a, T: Arbitrary<'a> + wit_future::FuturePayload>(u: &mut Unstructured<'a>) -> wit_bindgen::FutureReader<T> {
+                    let value: T = u.arbitrary().unwrap();
+                    let (writer, reader) = wit_future::new::<T>(|| unreachable!());
+                    wit_bindgen::spawn(async move {
+                        let _ = writer.write(value).await;
+                    });
+                    reader
+                }
+            });
+        }
+        res
+    }
+    fn dialog_defs(&self) -> Vec<Item> {
+        let mut res = Vec::new();
+        if self.has_stream {
+            let ast: syn::File = parse_quote! {
+                impl<T: WitName> WitName for wit_bindgen::StreamReader<T> {
+                    fn name() -> String {
+                        format!("stream<{}>", T::name())
+                    }
+                }
+                impl<T: Dialog + wit_stream::StreamPayload> Dialog for wit_bindgen::StreamReader<T> {
+                    fn read_value(dep: u32) -> Self {
+                        let values: Vec<T> = Dialog::read_value(dep);
+                        let (mut writer, reader) = wit_stream::new::<T>();
+                        wit_bindgen::spawn(async move {
+                            let _ = writer.write_all(values).await;
+                        });
+                        reader
+                    }
+                }
+            };
+            res.extend(ast.items);
+        }
+        if self.has_future {
+            let ast: syn::File = parse_quote! {
+                impl<T: WitName> WitName for wit_bindgen::FutureReader<T> {
+                    fn name() -> String {
+                        format!("future<{}>", T::name())
+                    }
+                }
+                impl<T: Dialog + wit_future::FuturePayload> Dialog for wit_bindgen::FutureReader<T> {
+                    fn read_value(dep: u32) -> Self {
+                        let value: T = Dialog::read_value(dep + 1);
+                        let (writer, reader) = wit_future::new::<T>(|| unreachable!());
+                        wit_bindgen::spawn(async move {
+                            let _ = writer.write(value).await;
+                        });
+                        reader
+                    }
+                }
+            };
+            res.extend(ast.items);
+        }
+        res
+    }
+}
@@ -1,17 +1,29 @@
+use crate::codegen::State;
 use crate::traits::Trait;
 use crate::util::make_path;
 use heck::{ToKebabCase, ToSnakeCase};
 use quote::quote;
 use syn::{Item, ItemEnum, ItemStruct, parse_quote};
 
-pub struct WaveTrait {
+pub struct WaveTrait<'a> {
+    pub state: &'a State,
     pub to_value: bool,
     pub to_rust: bool,
     pub has_replay_table: bool,
 }
 
-impl Trait for WaveTrait {
+impl WaveTrait<'_> {
+    /// The live imports wrapped in replay mode only take the values of the host types.
+    fn is_wrapped(&self, module_path: &[String]) -> bool {
+        self.has_replay_table && crate::codegen::is_wrapped(module_path)
+    }
+}
+
+impl Trait for WaveTrait<'_> {
     fn resource_trait(&self, module_path: &[String], resource: &ItemStruct) -> Vec<Item> {
+        if self.is_wrapped(module_path) {
+            return Vec::new();
+        }
         let mut res = Vec::new();
         let resource_path = make_path(module_path, &resource.ident.to_string());
         let wit_name = resource.ident.to_string().to_kebab_case();
@@ -31,7 +43,25 @@ impl Trait for WaveTrait {
                 }
             }
             });
-            if self.to_value {
+            if self.to_value && self.to_rust && self.has_replay_table {
+                // Resources in the replay table keep their recorded labels
+                res.push(parse_quote! {
+                impl ToValue for #resource_path {
+                    fn to_value(&self) -> Value {
+                        let label = replay_table_label(#wit_name, self.handle());
+                        Value::make_handle(label.into())
+                    }
+                }
+                });
+                res.push(parse_quote! {
+                impl<'a> ToValue for &'a #resource_path {
+                    fn to_value(&self) -> Value {
+                        let label = format!("borrow-{}", replay_table_label(#wit_name, self.handle()));
+                        Value::make_handle(label.into())
+                    }
+                }
+                });
+            } else if self.to_value {
                 res.push(parse_quote! {
                 impl ToValue for #resource_path {
                     fn to_value(&self) -> Value {
@@ -50,6 +80,25 @@ impl Trait for WaveTrait {
                 });
             }
             if self.to_rust {
+                let (take_live, get_live) = if self.has_replay_table {
+                    (
+                        quote! {
+                            if let Some(handle) = replay_table_take(&label) {
+                                // Safety: the replay table owns the handle, see replay_table_register
+                                return unsafe { #resource_path::from_handle(handle) };
+                            }
+                        },
+                        quote! {
+                            if let Some(handle) = replay_table_get(label.strip_prefix("borrow-").unwrap()) {
+                                // The replay table keeps the ownership
+                                let live = std::mem::ManuallyDrop::new(unsafe { #resource_path::from_handle(handle) });
+                                return SCOPED_ALLOC.with(|alloc| alloc.borrow_mut().alloc(live));
+                            }
+                        },
+                    )
+                } else {
+                    (quote! {}, quote! {})
+                };
                 let call = format!(
                     "get_mock_{}_magic42_{}",
                     module_path.join("_"),
@@ -57,10 +106,36 @@ impl Trait for WaveTrait {
                 )
                 .to_snake_case();
                 let call: syn::Ident = syn::parse_str(&call).unwrap();
+                if !self
+                    .state
+                    .has_mock_conversion(module_path, &resource.ident.to_string())
+                {
+                    // Resources of the exported interfaces can only come from the replay table
+                    res.push(parse_quote! {
+                    impl ToRust<#resource_path> for Value {
+                        fn to_rust(&self) -> #resource_path {
+                            let label = self.unwrap_handle();
+                            #take_live
+                            panic!("{label} is not returned by any replayed call")
+                        }
+                    }
+                    });
+                    res.push(parse_quote! {
+                    impl<'a> ToRust<&'a #resource_path> for Value {
+                        fn to_rust(&self) -> &'a #resource_path {
+                            let label = self.unwrap_handle();
+                            #get_live
+                            panic!("{label} is not returned by any replayed call")
+                        }
+                    }
+                    });
+                    return res;
+                }
                 res.push(parse_quote! {
                 impl ToRust<#resource_path> for Value {
                     fn to_rust(&self) -> #resource_path {
                         let label = self.unwrap_handle();
+                        #take_live
                         let handle = label
                             .strip_prefix(&format!("{}-", #wit_name))
                             .and_then(|s| s.parse::<u32>().ok())
@@ -73,6 +148,7 @@ impl Trait for WaveTrait {
                 impl<'a> ToRust<&'a #resource_path> for Value {
                     fn to_rust(&self) -> &'a #resource_path {
                         let label = self.unwrap_handle();
+                        #get_live
                         let handle = label
                             .strip_prefix(&format!("borrow-{}-", #wit_name))
                             .and_then(|s| s.parse::<u32>().ok())
@@ -167,6 +243,9 @@ impl Trait for WaveTrait {
         res
     }
     fn struct_trait(&self, module_path: &[String], struct_item: &ItemStruct) -> Vec<Item> {
+        if self.is_wrapped(module_path) {
+            return Vec::new();
+        }
         let mut res = Vec::new();
         let struct_name = make_path(module_path, &struct_item.ident.to_string());
         let (impl_generics, ty_generics, where_clause) = struct_item.generics.split_for_impl();
@@ -226,6 +305,9 @@ impl Trait for WaveTrait {
         res
     }
     fn enum_trait(&self, module_path: &[String], enum_item: &ItemEnum) -> Vec<Item> {
+        if self.is_wrapped(module_path) {
+            return Vec::new();
+        }
         let mut res = Vec::new();
         let enum_name = make_path(module_path, &enum_item.ident.to_string());
         let (impl_generics, ty_generics, where_clause) = enum_item.generics.split_for_impl();
@@ -302,6 +384,9 @@ impl Trait for WaveTrait {
         res
     }
     fn flag_trait(&self, module_path: &[String], item: &crate::codegen::ItemFlag) -> Vec<Item> {
+        if self.is_wrapped(module_path) {
+            return Vec::new();
+        }
         let mut res = Vec::new();
         let flag_path = make_path(module_path, &item.name.to_string());
         let wit_flags = item.flags.iter().map(|f| f.to_string().to_kebab_case());
@@ -442,10 +527,101 @@ impl Trait for WaveTrait {
         } else {
             quote! {}
         };
+        let replay_table = if self.to_rust && self.has_replay_table {
+            quote! {
+                thread_local! {
+                    // Resources returned by replayed export calls, from the recorded label to the live handle.
+                    // The handles are owned by the table until they are moved into an export call.
+                    static REPLAY_TABLE: RefCell<std::collections::BTreeMap<String, u32>> = RefCell::new(std::collections::BTreeMap::new());
+                    // The live handle of each label given by replay_table_label, which can be a recorded label.
+                    static REPLAY_LIVE_HANDLES: RefCell<std::collections::BTreeMap<String, u32>> = RefCell::new(std::collections::BTreeMap::new());
+                }
+                // Match the handles in the live value with the recorded value.
+                // Returns true if the table takes the ownership of any handle in the live value.
+                #[allow(dead_code)]
+                fn replay_table_register(live: &Value, recorded: &Value) -> bool {
+                    use wasm_wave::wasm::WasmTypeKind;
+                    match (live.kind(), recorded.kind()) {
+                        (WasmTypeKind::Handle, WasmTypeKind::Handle) => {
+                            let label = live.unwrap_handle();
+                            // streams and futures are not kept in the table
+                            let Some(handle) = REPLAY_LIVE_HANDLES
+                                .with_borrow(|handles| handles.get(label.as_ref()).copied())
+                            else {
+                                return false;
+                            };
+                            REPLAY_TABLE.with_borrow_mut(|table| {
+                                table.retain(|_, live| *live != handle);
+                                table.insert(recorded.unwrap_handle().to_string(), handle)
+                            });
+                            true
+                        }
+                        (WasmTypeKind::List, WasmTypeKind::List) => live
+                            .unwrap_list()
+                            .zip(recorded.unwrap_list())
+                            .fold(false, |acc, (l, r)| replay_table_register(&l, &r) | acc),
+                        (WasmTypeKind::Tuple, WasmTypeKind::Tuple) => live
+                            .unwrap_tuple()
+                            .zip(recorded.unwrap_tuple())
+                            .fold(false, |acc, (l, r)| replay_table_register(&l, &r) | acc),
+                        (WasmTypeKind::Record, WasmTypeKind::Record) => live
+                            .unwrap_record()
+                            .zip(recorded.unwrap_record())
+                            .fold(false, |acc, ((_, l), (_, r))| replay_table_register(&l, &r) | acc),
+                        (WasmTypeKind::Option, WasmTypeKind::Option) => {
+                            match (live.unwrap_option(), recorded.unwrap_option()) {
+                                (Some(l), Some(r)) => replay_table_register(&l, &r),
+                                _ => false,
+                            }
+                        }
+                        (WasmTypeKind::Result, WasmTypeKind::Result) => {
+                            match (live.unwrap_result(), recorded.unwrap_result()) {
+                                (Ok(Some(l)), Ok(Some(r))) | (Err(Some(l)), Err(Some(r))) => {
+                                    replay_table_register(&l, &r)
+                                }
+                                _ => false,
+                            }
+                        }
+                        (WasmTypeKind::Variant, WasmTypeKind::Variant) => {
+                            match (live.unwrap_variant(), recorded.unwrap_variant()) {
+                                ((l_case, Some(l)), (r_case, Some(r))) if l_case == r_case => {
+                                    replay_table_register(&l, &r)
+                                }
+                                _ => false,
+                            }
+                        }
+                        _ => false,
+                    }
+                }
+                #[allow(dead_code)]
+                fn replay_table_label(name: &str, handle: u32) -> String {
+                    let label = REPLAY_TABLE.with_borrow(|table| {
+                        table
+                            .iter()
+                            .find(|(_, live)| **live == handle)
+                            .map(|(label, _)| label.clone())
+                    })
+                    .unwrap_or_else(|| format!("{name}-{handle}"));
+                    REPLAY_LIVE_HANDLES.with_borrow_mut(|handles| handles.insert(label.clone(), handle));
+                    label
+                }
+                #[allow(dead_code)]
+                fn replay_table_take(label: &str) -> Option<u32> {
+                    REPLAY_TABLE.with_borrow_mut(|table| table.remove(label))
+                }
+                #[allow(dead_code)]
+                fn replay_table_get(label: &str) -> Option<u32> {
+                    REPLAY_TABLE.with_borrow(|table| table.get(label).copied())
+                }
+            }
+        } else {
+            quote! {}
+        };
         let ast: syn::File = parse_quote! {
           #[allow(unused_imports)]
           use wasm_wave::{wasm::WasmValue, value::{Value, Type, convert::{ToRust, ToValue, ValueTyped}}};
           #mocked_resource
+          #replay_table
         };
         ast.items
     }
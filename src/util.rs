@@ -1,5 +1,5 @@
 use crate::codegen::{self, ItemFlag, TypeInfo};
-use heck::ToKebabCase;
+use heck::{ToKebabCase, ToSnakeCase};
 use quote::ToTokens;
 use std::borrow::Cow;
 use std::collections::{BTreeMap, BTreeSet};
@@ -28,6 +28,10 @@ impl ArgInfo {
     }
 }
 pub fn make_path(module_path: &[String], name: &str) -> syn::Path {
+    if module_path.is_empty() {
+        // world-level items live at the root of the bindings
+        return syn::parse_str(name).unwrap();
+    }
     let path = format!("{}::{}", module_path.join("::"), name);
     syn::parse_str(&path).unwrap()
 }
@@ -37,6 +41,11 @@ pub fn wit_func_name(
     func_name: &Ident,
     kind: &Option<ResourceFuncKind>,
 ) -> String {
+    if module_path.is_empty() {
+        // world-level function, the wrapped_ prefix is part of the function name
+        let name = func_name.to_string().to_kebab_case();
+        return name.strip_prefix("wrapped-").unwrap_or(&name).to_string();
+    }
     assert!(module_path.len() >= 3);
     let mut module_path = module_path.to_vec();
     if module_path[0] == "exports" {
@@ -64,6 +73,34 @@ pub fn wit_func_name(
     res.push_str(&func_name.to_string().to_kebab_case());
     res
 }
+/// Whether `name` matches `pattern`, where `*` matches any sequence of characters.
+pub fn glob_match(pattern: &str, name: &str) -> bool {
+    let mut parts = pattern.split('*');
+    let first = parts.next().unwrap();
+    let Some(mut rest) = name.strip_prefix(first) else {
+        return false;
+    };
+    let mut parts: Vec<_> = parts.collect();
+    let Some(last) = parts.pop() else {
+        return rest.is_empty();
+    };
+    for part in parts {
+        match rest.find(part) {
+            Some(idx) => rest = &rest[idx + part.len()..],
+            None => return false,
+        }
+    }
+    rest.ends_with(last)
+}
+pub fn wit_resource_drop_name(module_path: &[String], resource: &str) -> String {
+    let name = wit_func_name(
+        module_path,
+        &Some(resource.to_string()),
+        &quote::format_ident!("drop"),
+        &None,
+    );
+    format!("[resource-drop]{}", name.strip_suffix(".drop").unwrap())
+}
 pub fn get_return_type(ret: &syn::ReturnType) -> Option<Type> {
     match ret {
         syn::ReturnType::Default => None,
@@ -74,6 +111,24 @@ pub fn get_return_type(ret: &syn::ReturnType) -> Option<Type> {
     }
 }
 
+/// The error type of a `Result<T, E>` type.
+pub fn get_result_err_type(ty: &Type) -> Option<Type> {
+    let Type::Path(type_path) = ty else {
+        return None;
+    };
+    let segment = type_path.path.segments.last()?;
+    if segment.ident != "Result" {
+        return None;
+    }
+    let syn::PathArguments::AngleBracketed(args) = &segment.arguments else {
+        return None;
+    };
+    match args.args.iter().nth(1)? {
+        syn::GenericArgument::Type(ty) => Some(ty.clone()),
+        _ => None,
+    }
+}
+
 pub fn extract_arg_info(sig: &Signature) -> (Option<ResourceFuncKind>, Vec<ArgInfo>) {
     let mut kind = None;
     let mut arg_infos = Vec::new();
@@ -115,7 +170,8 @@ impl<'a> VisitMut for FullTypePath<'a> {
     fn visit_type_path_mut(&mut self, ty: &mut syn::TypePath) {
         if ty.qself.is_none() && !ty.path.segments.is_empty() && ty.path.leading_colon.is_none() {
             let ident = &ty.path.segments[0].ident.to_string();
-            if BUILTIN_TYPES.contains(&ident.as_str()) {
+            // wit_bindgen paths are the stream and future handles
+            if BUILTIN_TYPES.contains(&ident.as_str()) || ident == "wit_bindgen" {
                 if ident == "_rt" {
                     assert!(ty.path.segments.len() == 2);
                     ty.path.segments = ty.path.segments.iter().skip(1).cloned().collect();
@@ -123,14 +179,17 @@ impl<'a> VisitMut for FullTypePath<'a> {
                 syn::visit_mut::visit_type_path_mut(self, ty);
                 return;
             }
-            let module_idents = self
-                .module_path
-                .iter()
-                .map(|s| syn::parse_str::<syn::Ident>(s).unwrap());
-            let original = &ty.path;
-            *ty = parse_quote! {
-                #(#module_idents)::*::#original
-            };
+            // world-level types are already in scope
+            if !self.module_path.is_empty() {
+                let module_idents = self
+                    .module_path
+                    .iter()
+                    .map(|s| syn::parse_str::<syn::Ident>(s).unwrap());
+                let original = &ty.path;
+                *ty = parse_quote! {
+                    #(#module_idents)::*::#original
+                };
+            }
         }
         syn::visit_mut::visit_type_path_mut(self, ty);
     }
@@ -188,7 +247,8 @@ impl codegen::State {
                 // import top-level functions
                 Item::Fn(func_item)
                     if matches!(func_item.vis, Visibility::Public(_))
-                        && current_path.len() >= 3 =>
+                        && (current_path.len() >= 3
+                            || current_path.is_empty() && !has_doc_hidden(&func_item.attrs)) =>
                 {
                     self.funcs
                         .entry(current_path.clone())
@@ -235,6 +295,33 @@ impl codegen::State {
                         .or_default()
                         .push(TypeInfo::Enum(enum_item));
                 }
+                // type aliases
+                Item::Type(type_item) if matches!(type_item.vis, Visibility::Public(_)) => {
+                    let mut ty = (*type_item.ty).clone();
+                    match &mut ty {
+                        // types used from other interfaces
+                        Type::Path(type_path) if type_path.path.segments[0].ident == "super" => {
+                            let depth = type_path
+                                .path
+                                .segments
+                                .iter()
+                                .take_while(|s| s.ident == "super")
+                                .count();
+                            let module_idents = current_path[..current_path.len() - depth]
+                                .iter()
+                                .map(|s| syn::parse_str::<syn::Ident>(s).unwrap());
+                            let rest = type_path.path.segments.iter().skip(depth);
+                            type_path.path = parse_quote! { #(#module_idents::)* #(#rest)::* };
+                        }
+                        _ => FullTypePath {
+                            module_path: &current_path,
+                        }
+                        .visit_type_mut(&mut ty),
+                    }
+                    let path = make_path(&current_path, &type_item.ident.to_string());
+                    self.type_aliases
+                        .insert(path.to_token_stream().to_string(), ty);
+                }
                 // flags
                 Item::Macro(macro_item) => {
                     if let Some(enum_item) = extract_bitflag(macro_item) {
@@ -252,6 +339,15 @@ impl codegen::State {
                         if current_path.is_empty() && mod_name == "_rt" {
                             continue;
                         }
+                        // payload traits for stream<T> and future<T>, see traits::stream
+                        if current_path.is_empty() && mod_name == "wit_stream" {
+                            self.has_stream = true;
+                            continue;
+                        }
+                        if current_path.is_empty() && mod_name == "wit_future" {
+                            self.has_future = true;
+                            continue;
+                        }
                         new_path.push(mod_name);
                         self.module_paths.insert(new_path.clone());
                         self.find_all_items(mod_items, new_path);
@@ -271,6 +367,100 @@ impl codegen::State {
         let funcs = module.get(resource)?;
         funcs.iter().find(|sig| sig.ident == *func)
     }
+    /// Resources of the exported interfaces are not in the conversion interface, see `ast::Opt::generate_wrapped_wits`.
+    pub fn has_mock_conversion(&self, module_path: &[String], resource: &str) -> bool {
+        let call =
+            format!("get_mock_{}_magic42_{}", module_path.join("_"), resource).to_snake_case();
+        let conversion_path = ["proxy", "conversion", "conversion"].map(String::from);
+        self.find_function(&conversion_path, &None, &syn::parse_str(&call).unwrap())
+            .is_some()
+    }
+    /// In replay mode, the live imports are wrapped, see `ast::Opt::generate_main_wit`.
+    pub fn has_wrapped_imports(&self) -> bool {
+        self.module_paths
+            .iter()
+            .any(|path| codegen::is_wrapped(path))
+            || self.funcs.get(&Vec::new()).is_some_and(|funcs| {
+                funcs
+                    .values()
+                    .flatten()
+                    .any(|sig| codegen::is_wrapped_func(&sig.ident))
+            })
+    }
+    pub fn is_resource_type(&self, ty: &Type) -> bool {
+        let Type::Path(type_path) = ty else {
+            return false;
+        };
+        let path = type_path.path.to_token_stream().to_string();
+        self.types.iter().any(|(module_path, types)| {
+            types.iter().any(|type_info| match type_info {
+                TypeInfo::Resource(item) => {
+                    make_path(module_path, &item.ident.to_string())
+                        .to_token_stream()
+                        .to_string()
+                        == path
+                }
+                _ => false,
+            })
+        })
+    }
+    /// Whether a value of `ty` may contain a resource, stream or future handle. `ty` is a full type path.
+    /// Unknown types are assumed to have handles.
+    pub fn may_have_handle(&self, ty: &Type) -> bool {
+        match ty {
+            Type::Reference(ty_ref) => self.may_have_handle(&ty_ref.elem),
+            Type::Slice(slice) => self.may_have_handle(&slice.elem),
+            Type::Tuple(tuple) => tuple.elems.iter().any(|ty| self.may_have_handle(ty)),
+            Type::Path(type_path) => {
+                let first = type_path.path.segments[0].ident.to_string();
+                if first == "Self" || first == "wit_bindgen" {
+                    return true;
+                }
+                let last = type_path.path.segments.last().unwrap();
+                if let syn::PathArguments::AngleBracketed(args) = &last.arguments
+                    && args.args.iter().any(|arg| {
+                        matches!(arg, syn::GenericArgument::Type(ty) if self.may_have_handle(ty))
+                    })
+                {
+                    return true;
+                }
+                if BUILTIN_TYPES.contains(&first.as_str()) {
+                    return false;
+                }
+                let path = type_path.path.to_token_stream().to_string();
+                let type_info = self.types.iter().find_map(|(module_path, types)| {
+                    types.iter().find(|type_info| {
+                        let name = match type_info {
+                            TypeInfo::Resource(item) | TypeInfo::Struct(item) => &item.ident,
+                            TypeInfo::Enum(item) => &item.ident,
+                            TypeInfo::Flag(item) => &item.name,
+                        };
+                        make_path(module_path, &name.to_string())
+                            .to_token_stream()
+                            .to_string()
+                            == path
+                    })
+                });
+                match type_info {
+                    Some(TypeInfo::Struct(item)) => {
+                        item.fields.iter().any(|f| self.may_have_handle(&f.ty))
+                    }
+                    Some(TypeInfo::Enum(item)) => item
+                        .variants
+                        .iter()
+                        .flat_map(|v| v.fields.iter())
+                        .any(|f| self.may_have_handle(&f.ty)),
+                    Some(TypeInfo::Flag(_)) => false,
+                    Some(TypeInfo::Resource(_)) => true,
+                    None => self
+                        .type_aliases
+                        .get(&path)
+                        .is_none_or(|ty| self.may_have_handle(ty)),
+                }
+            }
+            _ => true,
+        }
+    }
     pub fn has_type_def(&self, module_path: &[String], name: &str) -> bool {
         let types = match self.types.get(module_path) {
             Some(types) => types,